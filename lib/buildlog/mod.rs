@@ -15,7 +15,7 @@ mod murmurhash;
 pub use self::murmurhash::murmur_hash_64a;
 
 /// The latest entries for all targets in the build log.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct BuildLog {
 	pub entries: HashMap<RawString, Entry>,
 }
@@ -107,12 +107,21 @@ impl BuildLog {
 	}
 
 	/// Read a build log.
+	///
+	/// Ninja appends to the log, so it can contain multiple entries for the
+	/// same target. The last one in the file is kept, regardless of its times:
+	/// those are relative to the start of the build they were part of, so a
+	/// newer entry can have a lower `end_time_ms`.
 	pub fn read_from(file: File) -> Result<BuildLog, Error> {
+		// Rough guess of the number of entries, assuming about 100 bytes per
+		// line. Old logs with many dead entries will make this an overestimate.
+		let size_hint = file.metadata().map_or(0, |m| m.len() as usize / 100);
+
 		let mut file = BufReader::new(file);
 
 		let mut line = RawString::new();
 
-		file.read_until(b'\n', &mut line.as_mut_bytes())?;
+		file.read_until(b'\n', line.as_mut_bytes())?;
 
 		if !line.starts_with("# ninja log v") {
 			return Err(Error::new(ErrorKind::InvalidData, "Not a ninja log file"));
@@ -139,11 +148,11 @@ impl BuildLog {
 		let not_an_integer = || Error::new(ErrorKind::InvalidData, "Field is not an integer");
 		let not_hex = || Error::new(ErrorKind::InvalidData, "Invalid command hash");
 
-		let mut entries = HashMap::new();
+		let mut entries = HashMap::with_capacity(size_hint);
 
 		loop {
 			line.clear();
-			if file.read_until(b'\n', &mut line.as_mut_bytes())? == 0 {
+			if file.read_until(b'\n', line.as_mut_bytes())? == 0 {
 				break;
 			}
 
@@ -166,7 +175,7 @@ impl BuildLog {
 					parse(&line[tab2 + 1..tab3]).ok_or_else(not_an_integer)?,
 				),
 				command_hash: if version < 5 {
-					murmur_hash_64a(&line[tab4 + 1..].as_bytes())
+					murmur_hash_64a(line[tab4 + 1..].as_bytes())
 				} else {
 					parse_hex(&line[tab4 + 1..]).ok_or_else(not_hex)?
 				},
@@ -179,6 +188,55 @@ impl BuildLog {
 	}
 }
 
+/// Compute a fingerprint of a build rule, usable as a cache key.
+///
+/// `inputs` gives the identity of each input of the rule, as a path together
+/// with a 64-bit value identifying its contents. That can be its modification
+/// time ([`Timestamp::to_nanos`]), a hash of its contents, or anything else
+/// that changes whenever the input does. Usually these are the
+/// [`inputs`][BuildRule::inputs] of the rule, followed by any extra
+/// dependencies from the dependency log.
+///
+/// Exactly the following goes into the hash, in this order:
+///
+///  1. The length of the command plus one, followed by the command itself.
+///     For phony rules, which have no command, this is just a zero length.
+///  2. The number of outputs, and for each output: the length of its path,
+///     followed by the path itself.
+///  3. For each input, in the given order: the length of its path, the path
+///     itself, and its identity.
+///
+/// Lengths, counts and identities are encoded as 64-bit little endian
+/// integers. The length prefixes make sure that different commands or lists
+/// of paths can't be confused with each other. The outputs are included such
+/// that build rules with the same command and inputs but different outputs
+/// (e.g. with a command that doesn't use `$out`) get different fingerprints.
+/// Order-only dependencies and other variables of the rule (e.g.
+/// `description`) are not included.
+///
+/// The result is the [`murmur_hash_64a`] of all of the above. Note that the
+/// order of the inputs matters.
+pub fn fingerprint<'a>(
+	rule: &BuildRule,
+	inputs: impl IntoIterator<Item = (&'a RawStr, u64)>,
+) -> u64 {
+	let mut data = Vec::new();
+	let command = rule.command.as_ref().map(|c| c.command.as_bytes());
+	data.extend_from_slice(&command.map_or(0, |c| c.len() as u64 + 1).to_le_bytes());
+	data.extend_from_slice(command.unwrap_or(b""));
+	data.extend_from_slice(&(rule.outputs.len() as u64).to_le_bytes());
+	for output in &rule.outputs {
+		data.extend_from_slice(&(output.len() as u64).to_le_bytes());
+		data.extend_from_slice(output.as_bytes());
+	}
+	for (path, identity) in inputs {
+		data.extend_from_slice(&(path.len() as u64).to_le_bytes());
+		data.extend_from_slice(path.as_bytes());
+		data.extend_from_slice(&identity.to_le_bytes());
+	}
+	murmur_hash_64a(&data)
+}
+
 fn parse<T: std::str::FromStr>(s: &RawStr) -> Option<T> {
 	s.to_str().ok().and_then(|s| s.parse().ok())
 }
@@ -192,3 +250,75 @@ fn parse_hex(s: &RawStr) -> Option<u64> {
 fn as_millis(d: Duration) -> u64 {
 	d.as_secs() * 1000 + u64::from(d.subsec_millis())
 }
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::test_dir::TestDir;
+	use std::fs::write;
+
+	#[test]
+	fn last_entry_wins() -> Result<(), Error> {
+		let dir = TestDir::new("buildlog-last-entry");
+		let file = dir.join(".ninja_log");
+		// Two builds appended to the same log. The second build was shorter,
+		// so its entry for `a` has lower times than the first one.
+		write(
+			&file,
+			"# ninja log v5\n\
+			 100\t900\t0\ta\t1\n\
+			 200\t300\t0\tb\t2\n\
+			 5\t10\t0\ta\t3\n",
+		)?;
+		let log = BuildLog::read(&file)?;
+		assert_eq!(log.entries.len(), 2);
+		let a = &log.entries[RawStr::from_str("a")];
+		assert_eq!((a.start_time_ms, a.end_time_ms, a.command_hash), (5, 10, 3));
+		let b = &log.entries[RawStr::from_str("b")];
+		assert_eq!(b.command_hash, 2);
+		Ok(())
+	}
+
+	#[test]
+	fn fingerprint() {
+		let spec = crate::spec::read_from(
+			Path::new("build.ninja"),
+			"rule cc\n  command = cc\n\
+			 rule ld\n  command = ld\n\
+			 rule empty\n  command =\n\
+			 build x: cc a b\n\
+			 build y: ld a b\n\
+			 build z: empty\n\
+			 build w: phony\n\
+			 build v: cc a b\n\
+			 build v u: cc a b\n"
+				.as_bytes(),
+		)
+		.unwrap();
+		let rule = |i: usize| &spec.build_rules[i];
+		let a = RawStr::from_str("a");
+		let b = RawStr::from_str("b");
+		let ab = RawStr::from_str("ab");
+		let empty = RawStr::from_str("");
+		let cc = rule(0);
+		let fp = super::fingerprint(cc, vec![(a, 1), (b, 2)]);
+		assert_eq!(fp, super::fingerprint(cc, vec![(a, 1), (b, 2)]));
+		assert_ne!(fp, super::fingerprint(cc, vec![(a, 1), (b, 3)]));
+		assert_ne!(fp, super::fingerprint(cc, vec![(b, 2), (a, 1)]));
+		assert_ne!(fp, super::fingerprint(rule(1), vec![(a, 1), (b, 2)]));
+		// Same command and inputs, but different outputs.
+		assert_ne!(fp, super::fingerprint(rule(4), vec![(a, 1), (b, 2)]));
+		assert_ne!(
+			super::fingerprint(rule(4), vec![(a, 1), (b, 2)]),
+			super::fingerprint(rule(5), vec![(a, 1), (b, 2)])
+		);
+		assert_ne!(
+			super::fingerprint(cc, vec![(ab, 1)]),
+			super::fingerprint(cc, vec![(a, 1), (empty, 1)])
+		);
+		assert_ne!(
+			super::fingerprint(rule(3), vec![]),
+			super::fingerprint(rule(2), vec![])
+		);
+	}
+}
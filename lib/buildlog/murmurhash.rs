@@ -10,7 +10,7 @@ const R: u32 = 47;
 pub fn murmur_hash_64a(key: &[u8]) -> u64 {
 	let mut h = SEED ^ M.wrapping_mul(key.len() as u64);
 	let mut iter = key.chunks_exact(8);
-	while let Some(part) = iter.next() {
+	for part in iter.by_ref() {
 		let k = M.wrapping_mul(LE::read_u64(part));
 		h = M.wrapping_mul(h ^ M.wrapping_mul(k ^ k >> R));
 	}
@@ -0,0 +1,295 @@
+//! A local cache of build outputs, keyed by fingerprint.
+//!
+//! See [`fingerprint`][crate::buildlog::fingerprint] for how to compute a key
+//! for a build rule, and [`cache_key`] for a key based on the contents of its
+//! inputs.
+
+use crate::buildlog::{fingerprint, murmur_hash_64a};
+use crate::deplog::DepLog;
+use crate::spec::BuildRule;
+use raw_string::unix::RawStrExt;
+use raw_string::{RawStr, RawString};
+use std::fs::{copy, create_dir_all, read, remove_dir_all, remove_file, rename};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A directory holding the outputs of previously run commands.
+///
+/// Every entry is a directory named after the fingerprint (in hexadecimal),
+/// containing the outputs in the order they were given to
+/// [`store`][Cache::store], named `0`, `1`, etc.
+///
+/// Outputs are copied in and out of the cache, never hardlinked, such that
+/// commands modifying their outputs in place can't corrupt the cache, and
+/// such that restored outputs get a fresh `mtime`.
+///
+/// Using a cache is only correct if:
+///
+///  - Commands are deterministic: Running the same command with the same
+///    inputs must give the same outputs. Commands that embed timestamps or
+///    absolute paths of the build directory will give outputs that differ
+///    from what a fresh run would produce.
+///  - The outputs are complete: Everything a command produces must be
+///    declared as an output, as nothing else is stored or restored.
+///  - The inputs are complete: Everything a command reads must be part of the
+///    fingerprint. Files the command reads but that are not declared as
+///    inputs (or discovered as dependencies) will not invalidate the cache.
+///
+/// Entries are never removed. Remove the directory (or parts of it) to clean
+/// up.
+#[derive(Clone, Debug)]
+pub struct Cache {
+	dir: PathBuf,
+}
+
+impl Cache {
+	/// Use the given directory as cache.
+	///
+	/// The directory is created when the first entry is stored.
+	pub fn new(dir: impl Into<PathBuf>) -> Self {
+		Cache { dir: dir.into() }
+	}
+
+	/// The directory of the cache.
+	pub fn dir(&self) -> &Path {
+		&self.dir
+	}
+
+	/// Copy the cached outputs for the given fingerprint into place.
+	///
+	/// Returns `false` if there is no entry for this fingerprint, in which
+	/// case nothing is changed.
+	pub fn fetch<'a>(
+		&self,
+		fingerprint: u64,
+		outputs: impl IntoIterator<Item = &'a Path>,
+	) -> Result<bool, Error> {
+		let entry = self.entry(fingerprint);
+		if !entry.is_dir() {
+			return Ok(false);
+		}
+		for (i, output) in outputs.into_iter().enumerate() {
+			// Remove the output first, in case it's a hardlink or is being
+			// executed.
+			match remove_file(output) {
+				Err(ref e) if e.kind() != ErrorKind::NotFound => {
+					return Err(Error::new(
+						e.kind(),
+						format!("Unable to remove {:?}: {}", output, e),
+					));
+				}
+				_ => {}
+			}
+			copy(entry.join(i.to_string()), output).map_err(|e| {
+				Error::new(
+					e.kind(),
+					format!("Unable to restore {:?} from cache: {}", output, e),
+				)
+			})?;
+		}
+		Ok(true)
+	}
+
+	/// Store copies of the outputs under the given fingerprint.
+	///
+	/// An existing entry for the same fingerprint is kept as is.
+	pub fn store<'a>(
+		&self,
+		fingerprint: u64,
+		outputs: impl IntoIterator<Item = &'a Path>,
+	) -> Result<(), Error> {
+		static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+		let entry = self.entry(fingerprint);
+		if entry.is_dir() {
+			return Ok(());
+		}
+
+		// Fill a temporary directory first, and rename it into place when
+		// it's complete, such that a partial entry is never used.
+		let tmp = self.dir.join(format!(
+			"{:016x}.tmp-{}-{}",
+			fingerprint,
+			std::process::id(),
+			COUNTER.fetch_add(1, Ordering::Relaxed)
+		));
+		create_dir_all(&tmp)?;
+		let result = outputs
+			.into_iter()
+			.enumerate()
+			.try_for_each(|(i, output)| copy(output, tmp.join(i.to_string())).map(drop))
+			.and_then(|()| rename(&tmp, &entry));
+		if result.is_err() {
+			remove_dir_all(&tmp).ok();
+			if entry.is_dir() {
+				// Someone else stored the same entry in the meantime.
+				return Ok(());
+			}
+		}
+		result
+	}
+
+	fn entry(&self, fingerprint: u64) -> PathBuf {
+		self.dir.join(format!("{:016x}", fingerprint))
+	}
+}
+
+/// Hash the contents of a file, as an input identity for
+/// [`fingerprint`][crate::buildlog::fingerprint].
+pub fn content_hash(path: &Path) -> Result<u64, Error> {
+	Ok(murmur_hash_64a(&read(path)?))
+}
+
+/// The inputs of a rule to include in its [`cache_key`]: its own inputs,
+/// followed by the dependencies discovered the last time it ran.
+///
+/// Returns `None` if the rule can't be cached: phony rules, generator rules,
+/// rules that are always rebuilt, and rules with a `depfile` whose
+/// dependencies are unknown (because the rule doesn't use `deps`, or because
+/// the dependency log has no entry for it).
+pub fn cacheable_inputs(rule: &BuildRule, dep_log: &DepLog) -> Option<Vec<RawString>> {
+	let command = rule.command.as_ref()?;
+	if rule.is_generator() || command.always {
+		return None;
+	}
+	let mut inputs = rule.inputs.clone();
+	if command.deps.is_some() {
+		let deps = rule.outputs.iter().find_map(|output| dep_log.get(output))?;
+		inputs.extend(deps.deps().map(RawStr::to_owned));
+	} else if !command.depfile.is_empty() {
+		return None;
+	}
+	Some(inputs)
+}
+
+/// The [`fingerprint`] of a rule, using the [`content_hash`] of the given
+/// inputs (see [`cacheable_inputs`]) as their identity.
+///
+/// Returns `None` if an input can't be read.
+pub fn cache_key(rule: &BuildRule, inputs: &[RawString]) -> Option<u64> {
+	let hashes = inputs
+		.iter()
+		.map(|input| content_hash(input.as_path()).ok())
+		.collect::<Option<Vec<u64>>>()?;
+	Some(fingerprint(
+		rule,
+		inputs.iter().map(|input| &input[..]).zip(hashes),
+	))
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::test_dir::TestDir;
+	use std::fs::write;
+	use std::os::unix::ffi::OsStringExt;
+
+	#[test]
+	fn store_and_fetch() -> Result<(), Error> {
+		let dir = TestDir::new("cache");
+		let cache = Cache::new(dir.join("cache"));
+		let a = dir.join("a");
+		let b = dir.join("b");
+		write(&a, "first")?;
+		write(&b, "second")?;
+
+		assert!(!cache.fetch(1, [a.as_path(), b.as_path()])?);
+		cache.store(1, [a.as_path(), b.as_path()])?;
+		// Storing again keeps the existing entry.
+		write(&a, "changed")?;
+		cache.store(1, [a.as_path(), b.as_path()])?;
+
+		remove_file(&a)?;
+		write(&b, "changed")?;
+		assert!(cache.fetch(1, [a.as_path(), b.as_path()])?);
+		assert_eq!(read(&a)?, b"first");
+		assert_eq!(read(&b)?, b"second");
+		assert!(!cache.fetch(2, [a.as_path(), b.as_path()])?);
+
+		// Storing fails if an output doesn't exist, without leaving anything
+		// behind.
+		assert!(cache.store(3, [dir.join("c").as_path()]).is_err());
+		assert_eq!(std::fs::read_dir(cache.dir())?.count(), 1);
+
+		assert_eq!(content_hash(&a)?, murmur_hash_64a(b"first"));
+
+		Ok(())
+	}
+
+	#[test]
+	fn keys() -> Result<(), Error> {
+		let dir = TestDir::new("cache-keys");
+		let path = |name: &str| RawString::from(dir.join(name).into_os_string().into_vec());
+		let spec = crate::spec::read_from(
+			Path::new("build.ninja"),
+			format!(
+				"rule cc\n  command = cc\n\
+				 rule dep\n  command = dep\n  deps = gcc\n  depfile = $out.d\n\
+				 rule gen\n  command = gen\n  generator = 1\n\
+				 build {a}.o: cc {a}\n\
+				 build {b}.o: cc {a}\n\
+				 build {a}.d: dep {a}\n\
+				 build {b}.d: dep {a}\n\
+				 build {b}.g: gen {a}\n\
+				 build {b}.p: phony {a}\n",
+				a = path("a"),
+				b = path("b"),
+			)
+			.as_bytes(),
+		)
+		.unwrap();
+		let rule = |i: usize| &spec.build_rules[i];
+		let a = dir.join("a");
+		let h = dir.join("h");
+		write(&a, "first")?;
+		write(&h, "header")?;
+
+		let mut dep_log = crate::deplog::DepLogMut::open(dir.join("deps"))?;
+		dep_log.insert_deps(path("a.d"), None, vec![path("h")])?;
+
+		let key = |i: usize| {
+			cacheable_inputs(rule(i), &dep_log).and_then(|inputs| cache_key(rule(i), &inputs))
+		};
+
+		// Same command and inputs, but different outputs.
+		let cc = key(0).unwrap();
+		assert_ne!(Some(cc), key(1));
+
+		// A deps rule includes the dependencies from the log, and can't be
+		// cached without an entry in the log.
+		assert_eq!(
+			cacheable_inputs(rule(2), &dep_log),
+			Some(vec![path("a"), path("h")])
+		);
+		let dep = key(2).unwrap();
+		assert_eq!(key(3), None);
+
+		// Generator and phony rules aren't cached.
+		assert_eq!(key(4), None);
+		assert_eq!(key(5), None);
+
+		// Hit.
+		let cache = Cache::new(dir.join("cache"));
+		let output = dir.join("a.o");
+		write(&output, "output")?;
+		cache.store(cc, [output.as_path()])?;
+		remove_file(&output)?;
+		assert!(cache.fetch(key(0).unwrap(), [output.as_path()])?);
+		assert_eq!(read(&output)?, b"output");
+
+		// Miss, after changing an input or a discovered dependency.
+		write(&a, "changed")?;
+		assert!(!cache.fetch(key(0).unwrap(), [output.as_path()])?);
+		write(&a, "first")?;
+		assert_eq!(key(2), Some(dep));
+		write(&h, "changed")?;
+		assert_ne!(key(2), Some(dep));
+
+		// Missing inputs can't be hashed.
+		remove_file(&a)?;
+		assert_eq!(key(0), None);
+
+		Ok(())
+	}
+}
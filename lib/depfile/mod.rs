@@ -3,7 +3,7 @@
 use raw_string::{RawStr, RawString};
 use std::fs::File;
 use std::io::{BufRead, BufReader, Error, ErrorKind, Read};
-use std::mem::replace;
+use std::mem::{replace, take};
 use std::path::Path;
 
 /// Read a Makfile-style dependency file.
@@ -23,6 +23,9 @@ pub fn read_deps_file(
 ///
 /// `f` is called for every target. The first argument is the target, the
 /// second is the list of dependencies.
+///
+/// Errors about invalid syntax include the (1-based) line number at which
+/// they occurred.
 pub fn read_deps_file_from(
 	file: impl Read,
 	mut f: impl FnMut(RawString, Vec<RawString>) -> Result<(), Error>,
@@ -35,10 +38,12 @@ pub fn read_deps_file_from(
 
 	loop {
 		line.clear();
-		if file.read_until(b'\n', &mut line.as_mut_bytes())? == 0 {
+		if file.read_until(b'\n', line.as_mut_bytes())? == 0 {
 			break;
 		}
 
+		state.line_num += 1;
+
 		if line.last() == Some(b'\n') {
 			line.pop();
 		}
@@ -94,7 +99,7 @@ pub fn read_deps_file_from(
 	if state.target.is_none() {
 		Ok(())
 	} else {
-		Err(Error::new(ErrorKind::InvalidData, "Unexpected end of file"))
+		Err(state.error("Unexpected end of file"))
 	}
 }
 
@@ -106,9 +111,17 @@ struct State {
 	target: Option<RawString>,
 	/// The rest of the paths we've finished reading.
 	deps: Vec<RawString>,
+	/// The number of the line we're reading, starting at 1.
+	line_num: usize,
 }
 
 impl State {
+	fn error(&self, message: &str) -> Error {
+		Error::new(
+			ErrorKind::InvalidData,
+			format!("Line {}: {}", self.line_num, message),
+		)
+	}
 	fn add_part(&mut self, s: &RawStr) {
 		self.path.push_str(s);
 	}
@@ -119,10 +132,7 @@ impl State {
 				path.pop();
 				self.target = Some(path);
 			} else if self.target.is_none() {
-				return Err(Error::new(
-					ErrorKind::InvalidData,
-					"Rule in dependency file has multiple outputs",
-				));
+				return Err(self.error("Rule in dependency file has multiple outputs"));
 			} else {
 				self.deps.push(path);
 			}
@@ -135,7 +145,7 @@ impl State {
 	) -> Result<(), Error> {
 		self.finish_path()?;
 		if let Some(target) = self.target.take() {
-			f(target, replace(&mut self.deps, Vec::new()))?;
+			f(target, take(&mut self.deps))?;
 		}
 		Ok(())
 	}
@@ -219,24 +229,33 @@ test2:"#,
 		);
 	}
 
+	fn check_error(input: &str, expected: &str) {
+		let file = Cursor::new(input);
+		let error = read_deps_file_from(file, |_, _| Ok(())).unwrap_err();
+		assert_eq!(error.kind(), ErrorKind::InvalidData);
+		assert_eq!(error.to_string(), expected);
+	}
+
 	#[test]
 	fn truncated() {
-		let file = Cursor::new(
+		check_error(
 			r#"
 output: input input2 input3 \
  input4 input5 \"#,
+			"Line 3: Unexpected end of file",
 		);
-		assert!(read_deps_file_from(file, |_, _| Ok(())).is_err());
 	}
 
 	#[test]
 	fn multiple_outputs() {
-		let file = Cursor::new(
+		check_error(
 			r#"
+a: b
+
 output output2: input input2 input3 \
  input4 input5 \"#,
+			"Line 4: Rule in dependency file has multiple outputs",
 		);
-		assert!(read_deps_file_from(file, |_, _| Ok(())).is_err());
 	}
 
 	#[test]
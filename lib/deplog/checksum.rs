@@ -0,0 +1,140 @@
+//! CRC-32 checksums of everything read from or written to a log.
+
+use std::cmp::min;
+use std::io::{BufRead, Read, Result, Write};
+
+const TABLE: [u32; 256] = make_table();
+
+const fn make_table() -> [u32; 256] {
+	let mut table = [0u32; 256];
+	let mut i = 0;
+	while i < 256 {
+		let mut crc = i as u32;
+		let mut bit = 0;
+		while bit < 8 {
+			crc = if crc & 1 != 0 {
+				0xEDB8_8320 ^ (crc >> 1)
+			} else {
+				crc >> 1
+			};
+			bit += 1;
+		}
+		table[i] = crc;
+		i += 1;
+	}
+	table
+}
+
+/// A running CRC-32 (the same one as used by zlib and gzip), and the number
+/// of bytes it covers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Checksum {
+	crc: u32,
+	n_bytes: u64,
+}
+
+impl Checksum {
+	pub fn update(&mut self, data: &[u8]) {
+		let mut crc = !self.crc;
+		for &b in data {
+			crc = TABLE[((crc ^ u32::from(b)) & 0xFF) as usize] ^ (crc >> 8);
+		}
+		self.crc = !crc;
+		self.n_bytes += data.len() as u64;
+	}
+
+	pub fn crc(&self) -> u32 {
+		self.crc
+	}
+
+	pub fn n_bytes(&self) -> u64 {
+		self.n_bytes
+	}
+}
+
+/// Keeps a [`Checksum`] of all bytes read through it.
+pub struct ChecksumReader<R> {
+	inner: R,
+	checksum: Checksum,
+}
+
+impl<R: BufRead> ChecksumReader<R> {
+	pub fn new(inner: R) -> Self {
+		ChecksumReader {
+			inner,
+			checksum: Checksum::default(),
+		}
+	}
+
+	/// The checksum of everything read so far.
+	pub fn checksum(&self) -> Checksum {
+		self.checksum
+	}
+
+	/// Check if there's nothing left to read.
+	pub fn at_end(&mut self) -> Result<bool> {
+		Ok(self.inner.fill_buf()?.is_empty())
+	}
+}
+
+impl<R: BufRead> Read for ChecksumReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+		let data = self.inner.fill_buf()?;
+		let n = min(data.len(), buf.len());
+		buf[..n].copy_from_slice(&data[..n]);
+		self.checksum.update(&data[..n]);
+		self.inner.consume(n);
+		Ok(n)
+	}
+}
+
+/// Keeps a [`Checksum`] of all bytes written through it.
+#[derive(Debug)]
+pub struct ChecksumWriter<W> {
+	inner: W,
+	checksum: Checksum,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+	/// Wrap a writer, starting with the checksum of what was already written.
+	pub fn new(inner: W, checksum: Checksum) -> Self {
+		ChecksumWriter { inner, checksum }
+	}
+
+	/// The checksum of everything written so far.
+	pub fn checksum(&self) -> Checksum {
+		self.checksum
+	}
+
+	/// Get the underlying writer, to write something that should not be part
+	/// of the checksum.
+	pub fn get_mut(&mut self) -> &mut W {
+		&mut self.inner
+	}
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> Result<usize> {
+		let n = self.inner.write(buf)?;
+		self.checksum.update(&buf[..n]);
+		Ok(n)
+	}
+
+	fn flush(&mut self) -> Result<()> {
+		self.inner.flush()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn crc32() {
+		let mut checksum = Checksum::default();
+		checksum.update(b"123");
+		checksum.update(b"456789");
+		assert_eq!(checksum.crc(), 0xCBF4_3926);
+		assert_eq!(checksum.n_bytes(), 9);
+	}
+}
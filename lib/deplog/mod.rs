@@ -1,28 +1,126 @@
 //! Reading and writing dependency logs (i.e. `.ninja_deps` files).
 
+mod checksum;
+
+use self::checksum::{Checksum, ChecksumReader, ChecksumWriter};
 use crate::mtime::Timestamp;
-use byteorder::{ReadBytesExt, WriteBytesExt, LE};
-use indexmap::map::Entry as IndexMapEntry;
+use byteorder::{ByteOrder, ReadBytesExt, WriteBytesExt, LE};
 use indexmap::map::IndexMap;
+use log::{debug, log_enabled, trace, warn, Level};
 use raw_string::{RawStr, RawString};
+use std::cmp::{max, min};
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
-use std::mem::replace;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::mem::take;
 use std::path::Path;
+use std::sync::{Mutex, PoisonError};
 
 /// Represents the contents of a `.ninja_deps` file.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct DepLog {
 	records: IndexMap<RawString, Option<Record>>,
 }
 
 /// Represents a `.ninja_deps` file, and allows making additions to it.
-#[derive(Debug)]
 pub struct DepLogMut {
 	deps: DepLog,
-	file: BufWriter<File>,
+	/// Behind its own `Mutex`, such that [`flush`][Self::flush] only needs a
+	/// shared reference.
+	file: Mutex<LogFile>,
+	version: u32,
+	/// Write a footer with the checksum of the log when closed. Set by
+	/// [`open_checked`][Self::open_checked].
+	checked: bool,
+	/// Whether [`close`][Self::close] needs to write a new footer.
+	needs_footer: bool,
+	ignore_order: bool,
+	/// Only ever used through `&mut self`, so the `Mutex` is never locked.
+	/// It only makes `DepLogMut` `Sync` without requiring the callback to be.
+	on_write: Option<Mutex<Box<WriteCallback>>>,
+}
+
+/// The file of a [`DepLogMut`], and the records that still need to be
+/// appended to it.
+#[derive(Debug)]
+struct LogFile {
+	file: ChecksumWriter<File>,
+	pending: Vec<u8>,
+	/// Where to truncate the file and start appending before the next write,
+	/// to remove the footer (and anything corrupt).
+	append_at: Option<u64>,
+}
+
+impl LogFile {
+	fn new(file: ChecksumWriter<File>, append_at: Option<u64>) -> LogFile {
+		LogFile {
+			file,
+			pending: Vec::new(),
+			append_at,
+		}
+	}
+
+	/// Truncate the file where the next record should go, if that didn't
+	/// happen yet.
+	fn truncate(&mut self) -> Result<(), Error> {
+		if let Some(len) = self.append_at {
+			let file = self.file.get_mut();
+			file.set_len(len)?;
+			file.seek(SeekFrom::Start(len))?;
+			self.append_at = None;
+		}
+		Ok(())
+	}
+
+	fn write_pending(&mut self) -> Result<(), Error> {
+		if !self.pending.is_empty() {
+			self.truncate()?;
+			self.file.write_all(&self.pending)?;
+			self.pending.clear();
+		}
+		Ok(())
+	}
+}
+
+impl Drop for LogFile {
+	fn drop(&mut self) {
+		let _ = self.write_pending();
+	}
 }
 
+/// The start of the path in the footer that [`DepLogMut::open_checked`]
+/// writes.
+///
+/// The footer is a regular path record, for a path that can't exist because
+/// it starts with a zero byte: This prefix, followed by the CRC-32 of
+/// everything before the footer and the number of bytes before the footer,
+/// in hexadecimal (see [`footer_path`]). Ninja reads it like any other path
+/// without dependencies, and leaves it alone. When the footer is not at the
+/// end of the file (because ninja appended records after it), it is just a
+/// path.
+const FOOTER_PREFIX: &[u8] = b"\0ninj-checksum:";
+
+fn footer_path(checksum: Checksum) -> Vec<u8> {
+	let mut path = FOOTER_PREFIX.to_vec();
+	path.extend_from_slice(
+		format!("{:08x}:{:016x}", checksum.crc(), checksum.n_bytes()).as_bytes(),
+	);
+	path
+}
+
+/// What [`DepLog::read_versioned`] found at the end of a log.
+struct LogEnd {
+	/// The checksum of everything before the footer, or before the first
+	/// corrupt record, or of the whole file.
+	data: Checksum,
+	/// Whether the checksum in the footer matched, if there is a footer.
+	footer: Option<bool>,
+	/// The problem with the first corrupt record, if reading stopped there.
+	corrupt: Option<Error>,
+}
+
+/// A callback given to [`DepLogMut::set_on_write`].
+pub type WriteCallback = dyn FnMut(&RawStr, TargetInfo<'_>) + Send;
+
 /// The information you get out of a `DepLog` for a specific target.
 #[derive(Clone, Copy, Debug)]
 pub struct TargetInfo<'a> {
@@ -49,7 +147,7 @@ impl DepLog {
 	}
 
 	/// Look up a target in the log.
-	pub fn get(&self, path: &RawStr) -> Option<TargetInfo> {
+	pub fn get(&self, path: &RawStr) -> Option<TargetInfo<'_>> {
 		self.records.get(path).and_then(|v| {
 			v.as_ref().map(|r| TargetInfo {
 				record: r,
@@ -59,7 +157,7 @@ impl DepLog {
 	}
 
 	/// Iterate over all targets in the log.
-	pub fn iter(&self) -> impl Iterator<Item = (&RawStr, TargetInfo)> {
+	pub fn iter(&self) -> impl Iterator<Item = (&RawStr, TargetInfo<'_>)> {
 		let log = self;
 		self.records.iter().flat_map(move |(k, v)| {
 			v.as_ref()
@@ -79,8 +177,19 @@ impl DepLog {
 	}
 
 	/// Read a log.
+	///
+	/// A checksum footer written by [`DepLogMut::open_checked`] is ignored.
 	pub fn read_from(file: &mut dyn Read) -> Result<DepLog, Error> {
-		let mut file = BufReader::new(file);
+		DepLog::read_versioned(file, false).map(|(log, _, _)| log)
+	}
+
+	/// Read a log, and also return the version of the file format and what
+	/// was found at the end.
+	///
+	/// If `lenient` is set, reading stops at the first corrupt record instead
+	/// of returning an error, keeping all records before it.
+	fn read_versioned(file: &mut dyn Read, lenient: bool) -> Result<(DepLog, u32, LogEnd), Error> {
+		let mut file = ChecksumReader::new(BufReader::new(file));
 
 		{
 			let mut header = [0u8; 12];
@@ -103,118 +212,212 @@ impl DepLog {
 
 		let mut records = IndexMap::<RawString, Option<Record>>::new();
 
-		while let Some(record_head) = match file.read_u32::<LE>() {
-			Ok(data) => Some(data),
-			Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => None,
-			Err(e) => return Err(e),
-		} {
-			if record_head & 0x8000_0000 == 0 {
-				// Path record
-				let size = record_head;
-
-				if size % 4 != 0 || size < 4 {
-					return Err(Error::new(
-						ErrorKind::InvalidData,
-						format!("Invalid path record size: 0x{:x}", size),
-					));
-				}
+		let mut end = LogEnd {
+			data: Checksum::default(),
+			footer: None,
+			corrupt: None,
+		};
 
-				let id = records.len() as u32;
+		let mut buffer = Vec::new();
 
-				let mut name = vec![0u8; size as usize - 4];
-				file.read_exact(&mut name)?;
-				while name.last() == Some(&0u8) {
-					// Remove padding
-					name.pop();
+		loop {
+			end.data = file.checksum();
+			match read_record(&mut file, version, &mut records, &mut buffer) {
+				Ok(RecordKind::Record) => {}
+				Ok(RecordKind::End) => break,
+				Ok(RecordKind::Footer(path)) => {
+					end.footer = Some(path == footer_path(end.data));
+					break;
 				}
-
-				let checksum = file.read_u32::<LE>()?;
-				if checksum != !id {
-					return Err(Error::new(
-						ErrorKind::InvalidData,
-						format!(
-							"Invalid checksum in file: 0x{:08x} for ID 0x{:08x}",
-							checksum, id
-						),
-					));
+				Err(e)
+					if lenient
+						&& matches!(
+							e.kind(),
+							ErrorKind::InvalidData | ErrorKind::UnexpectedEof
+						) =>
+				{
+					end.corrupt = Some(e);
+					break;
 				}
+				Err(e) => return Err(e),
+			}
+		}
 
-				if records.insert(RawString::from_bytes(name), None).is_some() {
-					return Err(Error::new(
-						ErrorKind::InvalidData,
-						format!(
-							"Duplicate path in file: {:?}",
-							records.get_index(id as usize).unwrap().0
-						),
-					));
-				}
-			} else {
-				// Deps record
-				let size = record_head & 0x7FFF_FFFF;
-
-				if size % 4 != 0 || size < if version < 4 { 8 } else { 12 } {
-					return Err(Error::new(
-						ErrorKind::InvalidData,
-						format!("Invalid dependencies record size: 0x{:x}", size),
-					));
-				}
+		if log_enabled!(Level::Debug) {
+			debug!(
+				"Read version {} log with {} paths, of which {} have dependencies.",
+				version,
+				records.len(),
+				records.values().filter(|r| r.is_some()).count()
+			);
+		}
 
-				let len = (size / 4 - if version < 4 { 2 } else { 3 }) as usize;
-
-				let id = file.read_u32::<LE>()? as usize;
-
-				let mtime = if version < 4 {
-					u64::from(file.read_u32::<LE>()?) * 1_000_000_000 + 999_999_999
-				} else {
-					file.read_u64::<LE>()?
-				};
-
-				let n_records = records.len();
-
-				let record = match records.get_index_mut(id) {
-					Some((_, r)) => r,
-					None => {
-						return Err(Error::new(
-							ErrorKind::InvalidData,
-							format!("Dependencies record for undefined path ID: 0x{:x}", id),
-						));
-					}
-				};
-
-				let mut record_deps = match record {
-					Some(r) => {
-						// Re-use the old deps vector.
-						let mut d = replace(&mut r.deps, Vec::new());
-						d.clear();
-						d
-					}
-					None => Vec::new(),
-				};
-
-				record_deps.reserve_exact(len);
-
-				for _ in 0..len {
-					let dep = file.read_u32::<LE>()?;
-					if dep as usize >= n_records {
-						return Err(Error::new(
-							ErrorKind::InvalidData,
-							format!("Undefined path ID in dependency: 0x{:x}", dep),
-						));
-					}
-					record_deps.push(dep);
-				}
+		Ok((DepLog { records }, version, end))
+	}
+}
+
+/// What [`read_record`] read.
+enum RecordKind {
+	/// A path or dependencies record.
+	Record,
+	/// The end of the file.
+	End,
+	/// The path of a checksum footer at the end of the file.
+	Footer(Vec<u8>),
+}
+
+/// Read a single record into `records`.
+///
+/// If the record is invalid, an error is returned and `records` is left
+/// unchanged.
+fn read_record(
+	file: &mut ChecksumReader<impl BufRead>,
+	version: u32,
+	records: &mut IndexMap<RawString, Option<Record>>,
+	buffer: &mut Vec<u8>,
+) -> Result<RecordKind, Error> {
+	let record_head = match file.read_u32::<LE>() {
+		Ok(data) => data,
+		Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(RecordKind::End),
+		Err(e) => return Err(e),
+	};
+	if record_head & 0x8000_0000 == 0 {
+		// Path record
+		let size = record_head;
+
+		if size % 4 != 0 || size < 4 {
+			return Err(Error::new(
+				ErrorKind::InvalidData,
+				format!("Invalid path record size: 0x{:x}", size),
+			));
+		}
+
+		let id = records.len() as u32;
+
+		let mut name = vec![0u8; size as usize - 4];
+		file.read_exact(&mut name)?;
+
+		while name.last() == Some(&0u8) {
+			// Remove padding
+			name.pop();
+		}
+
+		let checksum = file.read_u32::<LE>()?;
+		if checksum != !id {
+			return Err(Error::new(
+				ErrorKind::InvalidData,
+				format!(
+					"Invalid checksum in file: 0x{:08x} for ID 0x{:08x}",
+					checksum, id
+				),
+			));
+		}
+
+		if name.starts_with(FOOTER_PREFIX) && file.at_end()? {
+			return Ok(RecordKind::Footer(name));
+		}
+
+		let name = RawString::from_bytes(name);
+
+		if records.contains_key(&name) {
+			return Err(Error::new(
+				ErrorKind::InvalidData,
+				format!("Duplicate path in file: {:?}", name),
+			));
+		}
+
+		records.insert(name, None);
+	} else {
+		// Deps record
+		let size = record_head & 0x7FFF_FFFF;
+
+		if size % 4 != 0 || size < if version < 4 { 8 } else { 12 } {
+			return Err(Error::new(
+				ErrorKind::InvalidData,
+				format!("Invalid dependencies record size: 0x{:x}", size),
+			));
+		}
+
+		let len = (size / 4 - if version < 4 { 2 } else { 3 }) as usize;
+
+		let id = file.read_u32::<LE>()? as usize;
+
+		let mtime = if version < 4 {
+			from_v3_mtime(file.read_u32::<LE>()?)
+		} else {
+			file.read_u64::<LE>()?
+		};
+
+		// Read all dependencies before changing anything, to leave the
+		// records unchanged if they are invalid.
+		buffer.resize(len * 4, 0);
+		file.read_exact(buffer)?;
+
+		let n_records = records.len();
 
-				*record = Some(Record {
-					deps: record_deps,
-					mtime: Timestamp::from_nanos(mtime),
-				});
+		let record = match records.get_index_mut(id) {
+			Some((_, r)) => r,
+			None => {
+				return Err(Error::new(
+					ErrorKind::InvalidData,
+					format!("Dependencies record for undefined path ID: 0x{:x}", id),
+				));
 			}
+		};
+
+		if let Some(dep) = buffer
+			.chunks_exact(4)
+			.map(LE::read_u32)
+			.find(|&dep| dep as usize >= n_records)
+		{
+			return Err(Error::new(
+				ErrorKind::InvalidData,
+				format!("Undefined path ID in dependency: 0x{:x}", dep),
+			));
 		}
 
-		Ok(DepLog { records })
+		let mut record_deps = match record {
+			Some(r) => {
+				// Re-use the old deps vector.
+				let mut d = take(&mut r.deps);
+				d.clear();
+				d
+			}
+			None => Vec::new(),
+		};
+
+		record_deps.reserve_exact(len);
+		record_deps.extend(buffer.chunks_exact(4).map(LE::read_u32));
+
+		*record = Some(Record {
+			deps: record_deps,
+			mtime: Timestamp::from_nanos(mtime),
+		});
+	}
+	Ok(RecordKind::Record)
+}
+
+/// Convert a version 3 `mtime` in seconds to nanoseconds.
+///
+/// Rounds up to the last nanosecond of the second, such that the result is
+/// never older than the original timestamp.
+fn from_v3_mtime(secs: u32) -> u64 {
+	match secs {
+		0 => 0,
+		s => u64::from(s) * 1_000_000_000 + 999_999_999,
 	}
 }
 
+/// Convert a `mtime` to a version 3 `mtime` in seconds.
+///
+/// Rounds down to whole seconds, but not to zero, as that means 'no `mtime`'.
+/// Saturates at 2^32-1 seconds after the epoch (in the year 2106).
+fn to_v3_mtime(mtime: Option<Timestamp>) -> u32 {
+	mtime.map_or(0, |t| {
+		max(1, min(t.to_nanos() / 1_000_000_000, u64::from(u32::MAX))) as u32
+	})
+}
+
 impl<'a> TargetInfo<'a> {
 	/// Get the `mtime` that was recorded in the log.
 	pub fn mtime(&self) -> Option<Timestamp> {
@@ -222,7 +425,7 @@ impl<'a> TargetInfo<'a> {
 	}
 
 	/// Get an iterator over the dependencies.
-	pub fn deps(&self) -> impl Iterator<Item = &'a RawStr> + ExactSizeIterator {
+	pub fn deps(&self) -> impl ExactSizeIterator<Item = &'a RawStr> {
 		let log = self.log;
 		self.record
 			.deps
@@ -233,52 +436,228 @@ impl<'a> TargetInfo<'a> {
 
 impl DepLogMut {
 	/// Open and read a dependency log, or start a new one.
-	pub fn open(file: impl AsRef<Path>) -> Result<DepLogMut, Error> {
+	///
+	/// New logs are written in version 4 of the file format. Existing logs
+	/// are appended to in the version they already have.
+	pub fn open(path: impl AsRef<Path>) -> Result<DepLogMut, Error> {
+		DepLogMut::open_impl(path.as_ref(), None, false)
+	}
+
+	/// Like [`open`][Self::open], but also verify the checksum of the whole
+	/// log, and write a new checksum when the log is [closed][Self::close]
+	/// after changing it.
+	///
+	/// If the log is corrupt, a warning is logged, and the log is recovered
+	/// like ninja does: All records before the first invalid one are kept, and
+	/// the rest of the file is dropped. If all records are valid but the
+	/// checksum doesn't match, all records are kept.
+	///
+	/// Logs without a checksum, such as those written by ninja or by
+	/// [`open`][Self::open], or those that were not properly closed, can't be
+	/// verified, and are used as is.
+	///
+	/// The checksum is written as a path record at the end of the file (see
+	/// [`FOOTER_PREFIX`]), which ninja reads without complaint.
+	pub fn open_checked(path: impl AsRef<Path>) -> Result<DepLogMut, Error> {
+		DepLogMut::open_impl(path.as_ref(), None, true)
+	}
+
+	/// Open and read a dependency log in a specific version of the file format
+	/// (3 or 4), or start a new one in that version.
+	///
+	/// Returns an error if the existing log has a different version.
+	///
+	/// Version 3 is understood by older versions of ninja, but only stores
+	/// `mtime`s with a resolution of one second: The recorded `mtime`s are
+	/// rounded down to whole seconds when writing, and read back as the last
+	/// nanosecond of that second. `mtime`s beyond the year 2106 can not be
+	/// represented, and are stored as the maximum value.
+	pub fn open_with_version(path: impl AsRef<Path>, version: u32) -> Result<DepLogMut, Error> {
+		if version != 3 && version != 4 {
+			return Err(Error::new(
+				ErrorKind::InvalidInput,
+				format!(
+					"Only version 3 and 4 are supported, but version {} was requested",
+					version
+				),
+			));
+		}
+		DepLogMut::open_impl(path.as_ref(), Some(version), false)
+	}
+
+	fn open_impl(path: &Path, version: Option<u32>, checked: bool) -> Result<DepLogMut, Error> {
 		let mut file = std::fs::OpenOptions::new()
 			.read(true)
 			.write(true)
 			.create(true)
-			.open(file)?;
+			.truncate(false)
+			.open(path)?;
 		if file.metadata()?.len() == 0 {
-			file.write_all(b"# ninjadeps\n\x04\0\0\0")?;
+			let version = version.unwrap_or(4);
+			debug!("Starting new version {} log at {:?}.", version, path);
+			let mut file = ChecksumWriter::new(file, Checksum::default());
+			file.write_all(b"# ninjadeps\n")?;
+			file.write_u32::<LE>(version)?;
 			Ok(DepLogMut {
 				deps: DepLog::new(),
-				file: BufWriter::new(file),
+				file: Mutex::new(LogFile::new(file, None)),
+				version,
+				checked,
+				needs_footer: checked,
+				ignore_order: false,
+				on_write: None,
 			})
 		} else {
+			let (deps, file_version, end) = DepLog::read_versioned(&mut file, checked)?;
+			if version.is_some_and(|v| v != file_version) {
+				return Err(Error::new(
+					ErrorKind::InvalidData,
+					format!(
+						"Existing log {:?} has version {}, but version {} was requested",
+						path,
+						file_version,
+						version.unwrap()
+					),
+				));
+			}
+			let recovered = if let Some(e) = &end.corrupt {
+				warn!(
+					"Dependency log {:?} is corrupt ({}). Keeping the records before the corrupt one.",
+					path, e
+				);
+				true
+			} else if end.footer == Some(false) {
+				warn!(
+					"Checksum of {:?} does not match. Keeping the records that are still valid.",
+					path
+				);
+				true
+			} else {
+				false
+			};
+			// The file is only truncated (to remove the footer, or what's
+			// corrupt) once something is written, to leave it untouched if
+			// nothing changes.
 			Ok(DepLogMut {
-				deps: DepLog::read_from(&mut file)?,
-				file: BufWriter::new(file),
+				deps,
+				file: Mutex::new(LogFile::new(
+					ChecksumWriter::new(file, end.data),
+					Some(end.data.n_bytes()),
+				)),
+				version: file_version,
+				checked,
+				needs_footer: recovered,
+				ignore_order: false,
+				on_write: None,
 			})
 		}
 	}
 
+	/// Write the records added by [`insert_deps`][Self::insert_deps] to the
+	/// file.
+	///
+	/// This only needs a shared reference, such that a `DepLogMut` behind a
+	/// `RwLock` can be written to the file while others keep reading it.
+	pub fn flush(&self) -> Result<(), Error> {
+		self.file
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+			.write_pending()
+	}
+
+	/// Flush everything to the file, and write the checksum footer if the log
+	/// was opened with [`open_checked`][Self::open_checked].
+	///
+	/// If nothing was written, the file is left untouched, unless a corrupt
+	/// log needs to be truncated.
+	///
+	/// Dropping a `DepLogMut` without closing it also flushes it, but ignores
+	/// errors and leaves a checked log without a checksum.
+	pub fn close(mut self) -> Result<(), Error> {
+		let id = self.deps.records.len() as u32;
+		let file = self.file.get_mut().unwrap_or_else(PoisonError::into_inner);
+		if self.needs_footer {
+			file.truncate()?;
+		}
+		file.write_pending()?;
+		if self.needs_footer {
+			let path = footer_path(file.file.checksum());
+			// The footer itself is not part of the checksum.
+			let file = file.file.get_mut();
+			file.write_u32::<LE>(path.len() as u32 + 4)?;
+			file.write_all(&path)?;
+			file.write_u32::<LE>(!id)?;
+		}
+		Ok(())
+	}
+
+	/// The buffer to add new records to.
+	fn start_writing(&mut self) -> &mut Vec<u8> {
+		self.needs_footer = self.checked;
+		&mut self
+			.file
+			.get_mut()
+			.unwrap_or_else(PoisonError::into_inner)
+			.pending
+	}
+
+	/// Set whether the order of dependencies is significant.
+	///
+	/// By default, [`insert_deps`][Self::insert_deps] writes a new record if
+	/// the dependencies are not exactly the same as before. When the order is
+	/// ignored, no new record is written if only the order changed, and the
+	/// dependencies keep the order they were recorded in before. A changed
+	/// `mtime` still results in a new record.
+	pub fn set_ignore_order(&mut self, ignore_order: bool) {
+		self.ignore_order = ignore_order;
+	}
+
+	/// Set a function to call whenever [`insert_deps`][Self::insert_deps]
+	/// writes a new record, with the target and its new information.
+	///
+	/// It is not called for dependencies that didn't change and were
+	/// therefore not written.
+	pub fn set_on_write(&mut self, callback: impl FnMut(&RawStr, TargetInfo<'_>) + Send + 'static) {
+		self.on_write = Some(Mutex::new(Box::new(callback)));
+	}
+
 	/// Writes a path to the file, if it wasn't already in there.
 	///
 	/// In both cases, it returns the ID of the path.
 	fn insert_path(&mut self, path: RawString) -> Result<u32, Error> {
-		let entry = self.deps.records.entry(path);
-		let id = entry.index() as u32;
-		if let IndexMapEntry::Vacant(entry) = entry {
-			let padding = (4 - entry.key().len() % 4) % 4;
-			let size = entry.key().len() as u32 + padding as u32 + 4;
-			self.file.write_u32::<LE>(size)?;
-			self.file.write_all(entry.key().as_bytes())?;
-			self.file.write_all(&b"\0\0\0"[..padding])?;
-			self.file.write_u32::<LE>(!id)?;
-			entry.insert(None);
+		if let Some((id, _, _)) = self.deps.records.get_full(&path) {
+			return Ok(id as u32);
 		}
+		let id = self.deps.records.len() as u32;
+		let padding = (4 - path.len() % 4) % 4;
+		let size = path.len() as u32 + padding as u32 + 4;
+		let out = self.start_writing();
+		out.write_u32::<LE>(size)?;
+		out.write_all(path.as_bytes())?;
+		out.write_all(&b"\0\0\0"[..padding])?;
+		out.write_u32::<LE>(!id)?;
+		self.deps.records.insert(path, None);
 		Ok(id)
 	}
 
-	/// Write a list of dependencies to the file, if it is different than
-	/// what's already in the file.
+	/// Record a list of dependencies, if it is different than what's already
+	/// in the log.
+	///
+	/// The new records are only kept in memory, and are written to the file
+	/// by [`flush`][Self::flush] or [`close`][Self::close].
 	pub fn insert_deps(
 		&mut self,
 		target: RawString,
 		mtime: Option<Timestamp>,
 		deps: Vec<RawString>,
 	) -> Result<(), Error> {
+		let mtime = if self.version < 4 {
+			// Keep the in-memory log the same as what will be read back.
+			Timestamp::from_nanos(from_v3_mtime(to_v3_mtime(mtime)))
+		} else {
+			mtime
+		};
+
 		let target = self.insert_path(target)?;
 		let record = self.deps.records.get_index_mut(target as usize).unwrap().1;
 
@@ -288,12 +667,18 @@ impl DepLogMut {
 			if record.mtime != mtime {
 				need_write = true;
 			}
-			replace(&mut record.deps, Vec::new())
+			take(&mut record.deps)
 		} else {
 			need_write = true;
 			Vec::new()
 		};
 
+		let old_ids = if self.ignore_order && !need_write {
+			Some(dep_ids.clone())
+		} else {
+			None
+		};
+
 		if deps.len() != dep_ids.len() {
 			need_write = true;
 			dep_ids.resize(deps.len(), !0);
@@ -307,14 +692,40 @@ impl DepLogMut {
 			}
 		}
 
+		if let (true, Some(old_ids)) = (need_write, old_ids) {
+			if same_set(&old_ids, &dep_ids) {
+				// Only the order changed. Keep the dependencies in the order
+				// they are in the file.
+				need_write = false;
+				dep_ids = old_ids;
+			}
+		}
+
+		trace!(
+			"{} {} dependencies for {:?}.",
+			if need_write {
+				"Recording"
+			} else {
+				"Unchanged:"
+			},
+			dep_ids.len(),
+			self.deps.records.get_index(target as usize).unwrap().0
+		);
+
 		if need_write {
-			let size = dep_ids.len() as u32 * 4 + 12;
-			let mtime = mtime.map_or(0, Timestamp::to_nanos);
-			self.file.write_u32::<LE>(0x8000_0000 | size)?;
-			self.file.write_u32::<LE>(target)?;
-			self.file.write_u64::<LE>(mtime)?;
+			let version = self.version;
+			let mtime_size = if version < 4 { 4 } else { 8 };
+			let size = dep_ids.len() as u32 * 4 + 4 + mtime_size;
+			let out = self.start_writing();
+			out.write_u32::<LE>(0x8000_0000 | size)?;
+			out.write_u32::<LE>(target)?;
+			if version < 4 {
+				out.write_u32::<LE>(to_v3_mtime(mtime))?;
+			} else {
+				out.write_u64::<LE>(mtime.map_or(0, Timestamp::to_nanos))?;
+			}
 			for &dep in &dep_ids {
-				self.file.write_u32::<LE>(dep)?;
+				out.write_u32::<LE>(dep)?;
 			}
 		}
 
@@ -323,10 +734,50 @@ impl DepLogMut {
 			mtime,
 		});
 
+		if need_write {
+			if let Some(on_write) = &mut self.on_write {
+				let on_write = on_write.get_mut().unwrap_or_else(PoisonError::into_inner);
+				let (path, record) = self.deps.records.get_index(target as usize).unwrap();
+				on_write(
+					path,
+					TargetInfo {
+						record: record.as_ref().unwrap(),
+						log: &self.deps,
+					},
+				);
+			}
+		}
+
 		Ok(())
 	}
 }
 
+impl std::fmt::Debug for DepLogMut {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.debug_struct("DepLogMut")
+			.field("deps", &self.deps)
+			.field("file", &self.file)
+			.field("version", &self.version)
+			.field("checked", &self.checked)
+			.field("needs_footer", &self.needs_footer)
+			.field("ignore_order", &self.ignore_order)
+			.field("on_write", &self.on_write.is_some())
+			.finish()
+	}
+}
+
+/// Check if two lists of IDs contain the same elements, ignoring order and
+/// duplicates.
+fn same_set(a: &[u32], b: &[u32]) -> bool {
+	let mut a = a.to_vec();
+	let mut b = b.to_vec();
+	a.sort_unstable();
+	a.dedup();
+	b.sort_unstable();
+	b.dedup();
+	a == b
+}
+
 impl std::ops::Deref for DepLogMut {
 	type Target = DepLog;
 	fn deref(&self) -> &Self::Target {
@@ -375,4 +826,258 @@ mod test {
 		std::fs::remove_file(file_name)?;
 		Ok(())
 	}
+
+	#[test]
+	#[rustfmt::skip]
+	fn version_3() -> Result<(), Error> {
+		let file_name = "ninj-test-deps-file-v3";
+		std::fs::remove_file(file_name).ok();
+		{
+			let mut dep_log = DepLogMut::open_with_version(file_name, 3)?;
+			dep_log.insert_deps("output1".into(), Timestamp::from_nanos(1_500_000_000), vec!["input1".into()])?;
+			dep_log.insert_deps("output2".into(), None, vec!["input1".into(), "input2".into()])?;
+			assert_eq!(dep_log.get(RawStr::from_str("output1")).unwrap().mtime(), Timestamp::from_nanos(1_999_999_999));
+		}
+		assert_eq!(&std::fs::read(file_name)?[12..16], b"\x03\0\0\0");
+		assert!(DepLogMut::open_with_version(file_name, 4).is_err());
+		{
+			// Appending keeps using version 3.
+			let mut dep_log = DepLogMut::open(file_name)?;
+			dep_log.insert_deps("output3".into(), Timestamp::from_nanos(1), vec![])?;
+		}
+		{
+			let dep_log = DepLog::read(file_name)?;
+			assert_eq!(dep_log.get(RawStr::from_str("output1")).unwrap().mtime(), Timestamp::from_nanos(1_999_999_999));
+			assert_eq!(dep_log.get(RawStr::from_str("output2")).unwrap().mtime(), None);
+			assert_eq!(dep_log.get(RawStr::from_str("output3")).unwrap().mtime(), Timestamp::from_nanos(1_999_999_999));
+			assert!(dep_log.get(RawStr::from_str("output2")).unwrap().deps().eq(&["input1", "input2"]));
+		}
+		std::fs::remove_file(file_name)?;
+		Ok(())
+	}
+
+	#[test]
+	#[rustfmt::skip]
+	fn ignore_order() -> Result<(), Error> {
+		let file_name = "ninj-test-deps-file-order";
+		std::fs::remove_file(file_name).ok();
+		let size = || std::fs::metadata(file_name).map(|m| m.len());
+		{
+			let mut dep_log = DepLogMut::open(file_name)?;
+			dep_log.insert_deps("output".into(), Timestamp::from_nanos(100), vec!["a".into(), "b".into(), "c".into()])?;
+		}
+		let initial_size = size()?;
+		{
+			let mut dep_log = DepLogMut::open(file_name)?;
+			dep_log.set_ignore_order(true);
+			dep_log.insert_deps("output".into(), Timestamp::from_nanos(100), vec!["c".into(), "a".into(), "b".into()])?;
+			assert!(dep_log.get(RawStr::from_str("output")).unwrap().deps().eq(&["a", "b", "c"]));
+		}
+		assert_eq!(size()?, initial_size);
+		{
+			// A different mtime is still recorded.
+			let mut dep_log = DepLogMut::open(file_name)?;
+			dep_log.set_ignore_order(true);
+			dep_log.insert_deps("output".into(), Timestamp::from_nanos(200), vec!["b".into(), "c".into(), "a".into()])?;
+		}
+		let size_after_mtime = size()?;
+		assert!(size_after_mtime > initial_size);
+		{
+			// A different set is still recorded.
+			let mut dep_log = DepLogMut::open(file_name)?;
+			dep_log.set_ignore_order(true);
+			dep_log.insert_deps("output".into(), Timestamp::from_nanos(200), vec!["b".into(), "a".into()])?;
+		}
+		assert!(size()? > size_after_mtime);
+		{
+			let dep_log = DepLog::read(file_name)?;
+			assert_eq!(dep_log.get(RawStr::from_str("output")).unwrap().mtime(), Timestamp::from_nanos(200));
+			assert!(dep_log.get(RawStr::from_str("output")).unwrap().deps().eq(&["b", "a"]));
+		}
+		std::fs::remove_file(file_name)?;
+		Ok(())
+	}
+
+	#[test]
+	fn on_write() -> Result<(), Error> {
+		use std::sync::{Arc, Mutex};
+		let file_name = "ninj-test-deps-file-on-write";
+		std::fs::remove_file(file_name).ok();
+		let written = Arc::new(Mutex::new(Vec::new()));
+		{
+			let mut dep_log = DepLogMut::open(file_name)?;
+			let w = written.clone();
+			dep_log.set_on_write(move |target, info| {
+				let deps = info.deps().map(|d| d.to_string()).collect::<Vec<_>>();
+				w.lock()
+					.unwrap()
+					.push((target.to_string(), info.mtime(), deps));
+			});
+			let ts = Timestamp::from_nanos;
+			dep_log.insert_deps("a".into(), ts(1), vec!["x".into(), "y".into()])?;
+			dep_log.insert_deps("a".into(), ts(1), vec!["x".into(), "y".into()])?;
+			dep_log.insert_deps("b".into(), ts(2), vec![])?;
+			dep_log.insert_deps("a".into(), ts(3), vec!["z".into()])?;
+		}
+		let written = written.lock().unwrap();
+		assert_eq!(
+			*written,
+			[
+				(
+					"a".to_string(),
+					Timestamp::from_nanos(1),
+					vec!["x".to_string(), "y".to_string()]
+				),
+				("b".to_string(), Timestamp::from_nanos(2), vec![]),
+				(
+					"a".to_string(),
+					Timestamp::from_nanos(3),
+					vec!["z".to_string()]
+				),
+			]
+		);
+		std::fs::remove_file(file_name)?;
+		Ok(())
+	}
+
+	#[test]
+	fn flush() -> Result<(), Error> {
+		let file_name = "ninj-test-deps-file-flush";
+		std::fs::remove_file(file_name).ok();
+		let dep_log = std::sync::RwLock::new(DepLogMut::open(file_name)?);
+		let header_len = std::fs::metadata(file_name)?.len();
+		dep_log.write().unwrap().insert_deps(
+			"output".into(),
+			Timestamp::from_nanos(1),
+			vec!["input".into()],
+		)?;
+		// Only recorded in memory until flushed.
+		assert_eq!(std::fs::metadata(file_name)?.len(), header_len);
+		assert!(dep_log
+			.read()
+			.unwrap()
+			.get(RawStr::from_str("output"))
+			.is_some());
+		{
+			// Flushing works while others are reading.
+			let _reader = dep_log.read().unwrap();
+			dep_log.read().unwrap().flush()?;
+		}
+		let deps = DepLog::read(file_name)?;
+		assert!(deps
+			.get(RawStr::from_str("output"))
+			.unwrap()
+			.deps()
+			.eq(&["input"]));
+		dep_log.into_inner().unwrap().close()?;
+		std::fs::remove_file(file_name)?;
+		Ok(())
+	}
+
+	#[test]
+	fn checksum() -> Result<(), Error> {
+		let file_name = "ninj-test-deps-file-checksum";
+		std::fs::remove_file(file_name).ok();
+		let ts = Timestamp::from_nanos;
+		let read = || std::fs::read(file_name);
+		let ends_with_footer = || -> Result<bool, Error> {
+			let data = read()?;
+			Ok(data.len() >= 48 && data[data.len() - 44..].starts_with(FOOTER_PREFIX))
+		};
+		{
+			let mut dep_log = DepLogMut::open_checked(file_name)?;
+			dep_log.insert_deps("output1".into(), ts(1), vec!["input1".into()])?;
+			dep_log.close()?;
+		}
+		assert!(ends_with_footer()?);
+		// Readers that don't check the checksum ignore the footer.
+		assert_eq!(DepLog::read(file_name)?.records.len(), 2);
+		{
+			// Nothing is written if nothing changed.
+			let data = read()?;
+			let mut dep_log = DepLogMut::open_checked(file_name)?;
+			dep_log.insert_deps("output1".into(), ts(1), vec!["input1".into()])?;
+			dep_log.close()?;
+			drop(DepLogMut::open_checked(file_name)?);
+			assert_eq!(read()?, data);
+		}
+		{
+			// Appending without checking removes the footer.
+			let mut dep_log = DepLogMut::open(file_name)?;
+			dep_log.insert_deps("output2".into(), ts(2), vec!["input2".into()])?;
+			dep_log.close()?;
+		}
+		assert!(!ends_with_footer()?);
+		{
+			// A log without a checksum can't be verified, but is used as is.
+			let dep_log = DepLogMut::open_checked(file_name)?;
+			assert_eq!(dep_log.iter().count(), 2);
+			dep_log.close()?;
+		}
+		assert!(!ends_with_footer()?);
+		{
+			let mut dep_log = DepLogMut::open_checked(file_name)?;
+			dep_log.insert_deps("output3".into(), ts(3), vec![])?;
+			dep_log.close()?;
+		}
+		assert!(ends_with_footer()?);
+
+		// Ninja reads the footer as a path, and might append after it. The
+		// footer is then just a path.
+		{
+			let mut data = read()?;
+			data.extend_from_slice(&8u32.to_le_bytes());
+			data.extend_from_slice(b"x\0\0\0");
+			data.extend_from_slice(&(!6u32).to_le_bytes());
+			std::fs::write(file_name, &data)?;
+			let dep_log = DepLogMut::open_checked(file_name)?;
+			assert_eq!(dep_log.records.len(), 7);
+			assert_eq!(dep_log.iter().count(), 3);
+		}
+
+		{
+			let mut dep_log = DepLogMut::open_checked(file_name)?;
+			dep_log.insert_deps("output4".into(), ts(4), vec![])?;
+			dep_log.close()?;
+		}
+		assert!(ends_with_footer()?);
+
+		// Corrupt the name of the first path, which otherwise goes unnoticed.
+		let mut data = read()?;
+		assert_eq!(&data[20..27], b"output1");
+		data[26] = b'5';
+		std::fs::write(file_name, &data)?;
+		assert!(DepLog::read(file_name)?
+			.get(RawStr::from_str("output5"))
+			.is_some());
+		{
+			// All records are still valid, so they are kept.
+			let dep_log = DepLogMut::open_checked(file_name)?;
+			assert_eq!(dep_log.iter().count(), 4);
+			dep_log.close()?;
+		}
+		assert!(ends_with_footer()?);
+		assert_eq!(read()?.len(), data.len());
+
+		// A corrupt record is dropped, together with everything after it.
+		let mut data = read()?;
+		let valid_len = data.len() - 48;
+		data.truncate(valid_len);
+		data.extend_from_slice(&8u32.to_le_bytes());
+		data.extend_from_slice(b"y\0\0\0");
+		data.extend_from_slice(&0u32.to_le_bytes());
+		std::fs::write(file_name, &data)?;
+		assert!(DepLogMut::open(file_name).is_err());
+		{
+			let mut dep_log = DepLogMut::open_checked(file_name)?;
+			assert_eq!(dep_log.iter().count(), 4);
+			dep_log.insert_deps("output6".into(), ts(6), vec![])?;
+			dep_log.close()?;
+		}
+		assert!(ends_with_footer()?);
+		assert_eq!(DepLog::read(file_name)?.iter().count(), 5);
+
+		std::fs::remove_file(file_name)?;
+		Ok(())
+	}
 }
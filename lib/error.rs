@@ -31,6 +31,8 @@ pub struct ErrorWithLocation<T> {
 	pub file: Option<PathBuf>,
 	pub line: Option<NonZeroU32>,
 	pub error: T,
+	/// See [`include_chain`][Self::include_chain].
+	pub(crate) included_from: Box<[(PathBuf, Option<NonZeroU32>)]>,
 }
 
 impl<'a> Location<'a> {
@@ -40,6 +42,7 @@ impl<'a> Location<'a> {
 			file: self.file.map(|p| p.to_path_buf()),
 			line: self.line,
 			error,
+			included_from: Box::default(),
 		}
 	}
 }
@@ -52,16 +55,28 @@ impl<A> ErrorWithLocation<A> {
 			file: self.file,
 			line: self.line,
 			error: From::from(self.error),
+			included_from: self.included_from,
 		}
 	}
 
 	/// Get the location at which the error occured.
-	pub fn location(&self) -> Location {
+	pub fn location(&self) -> Location<'_> {
 		Location {
-			file: self.file.as_ref().map(PathBuf::as_path),
+			file: self.file.as_deref(),
 			line: self.line,
 		}
 	}
+
+	/// The locations of the statements (e.g. `include`) through which the
+	/// file of the error was reached, innermost first.
+	///
+	/// Empty if unknown or not applicable.
+	pub fn include_chain(&self) -> impl Iterator<Item = Location<'_>> {
+		self.included_from.iter().map(|(file, line)| Location {
+			file: Some(file),
+			line: *line,
+		})
+	}
 }
 
 /// Extension trait: Adds [`err_at()`][Self::err_at] to [`Result`].
@@ -109,7 +124,15 @@ impl<T: fmt::Display> fmt::Display for ErrorWithLocation<T> {
 		if self.location() != Location::UNKNOWN {
 			write!(f, "{}: ", self.location())?;
 		}
-		write!(f, "{}", self.error)
+		write!(f, "{}", self.error)?;
+		for (i, location) in self.include_chain().enumerate() {
+			let prefix = if i == 0 { " (included from" } else { ", from" };
+			write!(f, "{} {}", prefix, location)?;
+		}
+		if !self.included_from.is_empty() {
+			write!(f, ")")?;
+		}
+		Ok(())
 	}
 }
 
@@ -117,6 +140,6 @@ impl<T: Error> Error for ErrorWithLocation<T> {}
 
 impl<T: Error + Send + Sync + 'static> From<ErrorWithLocation<T>> for std::io::Error {
 	fn from(src: ErrorWithLocation<T>) -> std::io::Error {
-		std::io::Error::new(std::io::ErrorKind::Other, src)
+		std::io::Error::other(src)
 	}
 }
@@ -52,6 +52,27 @@
 //!   The [`outdated`] module contains an [`is_outdated`][outdated::is_outdated]
 //!   function, which checks the `mtime`'s of targets and their dependencies
 //!   to check if it should be rebuilt.
+//!
+//! - **Caching outputs**
+//!
+//!   [`Cache`][cache::Cache] stores the outputs of commands under a
+//!   [`fingerprint`][buildlog::fingerprint] of the command and its inputs, so
+//!   they can be restored instead of running the command again.
+//!
+//! - **Running commands**
+//!
+//!   The [`process`] module contains
+//!   [`listen_to_child`][process::listen_to_child], which waits for a child
+//!   process while capturing its output live.
+//!
+//! # Logging
+//!
+//! Some modules emit [`log`] records, mostly at the `debug` and `trace`
+//! levels, to make clear what they're doing and why. They use these targets:
+//!
+//! - `ninj::spec`: Which files are read, and what was found in them.
+//! - `ninj::outdated`: Why a target is considered outdated.
+//! - `ninj::deplog`: Reading and updating `.ninja_deps` files.
 
 #![warn(bare_trait_objects)]
 #![warn(trivial_casts)]
@@ -59,10 +80,15 @@
 #![warn(unused_qualifications)]
 
 pub mod buildlog;
+pub mod cache;
 pub mod depfile;
 pub mod deplog;
 pub mod error;
 pub mod mtime;
 pub mod outdated;
+pub mod process;
 pub mod queue;
 pub mod spec;
+
+#[cfg(test)]
+mod test_dir;
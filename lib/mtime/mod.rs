@@ -1,11 +1,14 @@
 //! Getting the `mtime` of files to check if they're outdated.
 
+use chrono::TimeZone;
 use std::cmp::max;
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
 use std::io::Error;
 use std::num::NonZeroU64;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 /// A timestamp of a file.
@@ -57,6 +60,30 @@ impl Timestamp {
 	pub fn to_system_time(self) -> SystemTime {
 		UNIX_EPOCH + Duration::from_nanos(self.to_nanos())
 	}
+
+	/// Parse an RFC 3339 timestamp, such as `2019-01-02T03:04:05.123Z`.
+	///
+	/// Returns [`None`] if the string is not a valid timestamp, or if it does
+	/// not lie after the epoch and before the maximum representable timestamp.
+	pub fn from_rfc3339(s: &str) -> Option<Self> {
+		let time = chrono::DateTime::parse_from_rfc3339(s).ok()?;
+		let secs = u64::try_from(time.timestamp()).ok()?;
+		let ns = secs
+			.checked_mul(1_000_000_000)?
+			.checked_add(time.timestamp_subsec_nanos().into())?;
+		Timestamp::from_nanos(ns)
+	}
+}
+
+/// Displays the timestamp in ISO 8601 format, in UTC, with nanosecond
+/// precision. For example: `2019-01-02T03:04:05.123000000Z`.
+impl fmt::Display for Timestamp {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let ns = self.to_nanos();
+		let time = chrono::Utc.timestamp((ns / 1_000_000_000) as i64, (ns % 1_000_000_000) as u32);
+		let s = time.to_rfc3339_opts(chrono::SecondsFormat::Nanos, true);
+		f.pad(&s)
+	}
 }
 
 /// Looks up the `mtime` of a file. Returns `None` if the file does not exist.
@@ -72,24 +99,64 @@ pub fn mtime(file: &Path) -> Result<Option<Timestamp>, Error> {
 	}
 }
 
+/// A way to look up the `mtime`s of files.
+///
+/// [`RealFs`] uses the actual file system. For testing, a
+/// `HashMap<PathBuf, Timestamp>` can be used as a fake file system, in which
+/// files that are not in the map do not exist.
+pub trait FileStat {
+	/// Looks up the `mtime` of a file. Returns `None` if the file does not
+	/// exist.
+	fn mtime(&self, file: &Path) -> Result<Option<Timestamp>, Error>;
+}
+
+/// The actual file system, using [`mtime()`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RealFs;
+
+impl FileStat for RealFs {
+	fn mtime(&self, file: &Path) -> Result<Option<Timestamp>, Error> {
+		mtime(file)
+	}
+}
+
+impl FileStat for HashMap<PathBuf, Timestamp> {
+	fn mtime(&self, file: &Path) -> Result<Option<Timestamp>, Error> {
+		Ok(self.get(file).cloned())
+	}
+}
+
 /// A cache that remembers the `mtime`s of files.
-pub struct StatCache<'a> {
+///
+/// Looks up the `mtime`s through a [`FileStat`], which is the actual file
+/// system ([`RealFs`]) by default.
+#[derive(Default)]
+pub struct StatCache<'a, S = RealFs> {
 	// `None` means the file does not exist.
 	cache: HashMap<&'a Path, Option<Timestamp>>,
+	fs: S,
 }
 
 impl<'a> StatCache<'a> {
-	/// Create an empty StatCache.
+	/// Create an empty StatCache for the actual file system.
 	pub fn new() -> Self {
+		StatCache::with_fs(RealFs)
+	}
+}
+
+impl<'a, S: FileStat> StatCache<'a, S> {
+	/// Create an empty StatCache which looks up `mtime`s through `fs`.
+	pub fn with_fs(fs: S) -> Self {
 		StatCache {
 			cache: HashMap::new(),
+			fs,
 		}
 	}
 
 	/// Looks up the `mtime` of a file, returns the cached value if it exists.
 	pub fn mtime(&mut self, file: &'a Path) -> Result<Option<Timestamp>, Error> {
 		match self.cache.entry(file) {
-			Entry::Vacant(v) => Ok(*v.insert(mtime(file)?)),
+			Entry::Vacant(v) => Ok(*v.insert(self.fs.mtime(file)?)),
 			Entry::Occupied(v) => Ok(*v.get()),
 		}
 	}
@@ -98,7 +165,7 @@ impl<'a> StatCache<'a> {
 	///
 	/// It does, however, store the result in the cache.
 	pub fn fresh_mtime(&mut self, file: &'a Path) -> Result<Option<Timestamp>, Error> {
-		let mtime = mtime(file)?;
+		let mtime = self.fs.mtime(file)?;
 		self.cache.insert(file, mtime);
 		Ok(mtime)
 	}
@@ -114,3 +181,29 @@ impl<'a> StatCache<'a> {
 		self.cache.get(file).cloned()
 	}
 }
+
+#[cfg(test)]
+mod test {
+	use super::Timestamp;
+
+	#[test]
+	fn display() {
+		let t = Timestamp::from_nanos(1_546_398_245_123_000_000).unwrap();
+		assert_eq!(t.to_string(), "2019-01-02T03:04:05.123000000Z");
+		let t = Timestamp::from_nanos(u64::MAX).unwrap();
+		assert_eq!(t.to_string(), "2554-07-21T23:34:33.709551615Z");
+	}
+
+	#[test]
+	fn rfc3339() {
+		let t = Timestamp::from_nanos(1_546_398_245_123_000_000);
+		assert_eq!(Timestamp::from_rfc3339("2019-01-02T03:04:05.123Z"), t);
+		assert_eq!(Timestamp::from_rfc3339("2019-01-02T04:04:05.123+01:00"), t);
+		let t = Timestamp::from_nanos(u64::MAX).unwrap();
+		assert_eq!(Timestamp::from_rfc3339(&t.to_string()), Some(t));
+		assert_eq!(Timestamp::from_rfc3339("1970-01-01T00:00:00Z"), None);
+		assert_eq!(Timestamp::from_rfc3339("1969-12-31T23:59:59Z"), None);
+		assert_eq!(Timestamp::from_rfc3339("2600-01-01T00:00:00Z"), None);
+		assert_eq!(Timestamp::from_rfc3339("2019-01-02"), None);
+	}
+}
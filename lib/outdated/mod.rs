@@ -8,9 +8,13 @@
 //!     Checking the inputs.
 //!
 //! [`is_outdated`][outdated::is_outdated] performs both.
+//!
+//! After a rule is run, [`restat_mtime`][outdated::restat_mtime] gives the
+//! `mtime` to store in the build log.
 
+use crate::buildlog::BuildLog;
 use crate::deplog::DepLog;
-use crate::mtime::{StatCache, Timestamp};
+use crate::mtime::{mtime, FileStat, StatCache, Timestamp};
 use crate::spec::BuildRule;
 use log::debug;
 use raw_string::unix::RawStrExt;
@@ -31,14 +35,15 @@ use std::io::{Error, ErrorKind};
 /// stored in it. If it was not in that cache, it will be cached in
 /// `dep_stat_cache` instead. (So you can modify the `dep_log` afterwards
 /// by throwing out `dep_stat_cache`, but keeping `stat_cache`.)
-pub fn is_outdated<'a, 'b>(
+pub fn is_outdated<'a, 'b, S: FileStat>(
 	rule: &'a BuildRule,
 	dep_log: &'b DepLog,
-	stat_cache: &mut StatCache<'a>,
-	dep_stat_cache: &mut StatCache<'b>,
+	build_log: &BuildLog,
+	stat_cache: &mut StatCache<'a, S>,
+	dep_stat_cache: &mut StatCache<'b, S>,
 	check_dep: impl FnMut(&RawStr) -> bool,
 ) -> Result<bool, Error> {
-	let oldest_output = check_outputs(rule, dep_log, stat_cache, dep_stat_cache)?;
+	let oldest_output = check_outputs(rule, dep_log, build_log, stat_cache, dep_stat_cache)?;
 	check_inputs(rule, stat_cache, oldest_output, check_dep)
 }
 
@@ -47,6 +52,7 @@ pub fn is_outdated<'a, 'b>(
 /// Returns [`None`] if the target is definitely out of date.
 /// That happens in these cases:
 ///
+/// - If the rule sets [`always`][crate::spec::BuildCommand::always].
 /// - If output does not exist.
 ///
 /// And in case the rule uses [`deps`][crate::spec::BuildCommand::deps]:
@@ -57,29 +63,57 @@ pub fn is_outdated<'a, 'b>(
 /// Otherwise, it returns the [`Timestamp`] of the oldest output, for
 /// comparison with the rule's [`inputs`][BuildRule::inputs].
 ///
+/// For rules that use [`restat`][crate::spec::BuildCommand::restat], the
+/// `mtime` recorded in the `build_log` is used instead of the `mtime` of the
+/// output itself, if there is one, also when comparing against the logged
+/// dependencies. (See [`restat_mtime`].)
+///
 /// Paths from the `dep_log` are looked up first in `stat_cache`, but never
 /// stored in it. If it was not in that cache, it will be cached in
 /// `dep_stat_cache` instead. (So you can modify the `dep_log` afterwards
 /// by throwing out `dep_stat_cache`, but keeping `stat_cache`.)
-pub fn check_outputs<'a, 'b>(
+pub fn check_outputs<'a, 'b, S: FileStat>(
 	rule: &'a BuildRule,
 	dep_log: &'b DepLog,
-	stat_cache: &mut StatCache<'a>,
-	dep_stat_cache: &mut StatCache<'b>,
+	build_log: &BuildLog,
+	stat_cache: &mut StatCache<'a, S>,
+	dep_stat_cache: &mut StatCache<'b, S>,
 ) -> Result<Option<Timestamp>, Error> {
+	if rule.command.as_ref().is_some_and(|c| c.always) {
+		debug!(
+			"{:?} is outdated because it is always rebuilt.",
+			rule.outputs
+		);
+		return Ok(None);
+	}
+
 	let mut oldest = None;
 
+	let restat = rule.command.as_ref().is_some_and(|c| c.restat);
+
 	for output in &rule.outputs {
 		if let Some(mtime) = stat_cache.mtime(output.as_path())? {
-			if oldest.map_or(true, |oldest| mtime < oldest) {
-				oldest = Some(mtime);
+			// A restat rule might not have touched its output the last time it
+			// ran, in which case the log tells us which inputs it was up to date
+			// with.
+			let logged_mtime = if restat {
+				build_log
+					.entries
+					.get(&output[..])
+					.and_then(|entry| entry.restat_mtime)
+			} else {
+				None
+			};
+			let output_mtime = logged_mtime.unwrap_or(mtime);
+			if oldest.is_none_or(|oldest| output_mtime < oldest) {
+				oldest = Some(output_mtime);
 			}
-			if rule.command.as_ref().map_or(true, |c| c.deps.is_none()) {
+			if rule.command.as_ref().is_none_or(|c| c.deps.is_none()) {
 				// Don't even look up dependencies in de dependency log for
 				// targets that don't use extra dependencies anyway.
 				continue;
 			}
-			if let Some(deps) = dep_log.get(&output) {
+			if let Some(deps) = dep_log.get(output) {
 				if deps.mtime() < Some(mtime) {
 					debug!(
 						"{:?} is outdated because our dependency log is stale.",
@@ -93,7 +127,7 @@ pub fn check_outputs<'a, 'b>(
 						None => dep_stat_cache.mtime(dep.as_path())?,
 					};
 					if let Some(dep_mtime) = dep_mtime {
-						if mtime < dep_mtime {
+						if output_mtime < dep_mtime {
 							debug!("{:?} is outdated because {:?} is newer.", output, dep);
 							return Ok(None);
 						}
@@ -135,17 +169,17 @@ pub fn check_outputs<'a, 'b>(
 /// Calls `check_dep(path)` for every input. This function should return true
 /// iff there's a build rule to make the input. If there is not, and the file
 /// does not exist, an error is returned.
-pub fn check_inputs<'a>(
+pub fn check_inputs<'a, S: FileStat>(
 	rule: &'a BuildRule,
-	stat_cache: &mut StatCache<'a>,
+	stat_cache: &mut StatCache<'a, S>,
 	oldest_output: Option<Timestamp>,
 	mut check_dep: impl FnMut(&RawStr) -> bool,
 ) -> Result<bool, Error> {
 	let mut outdated = oldest_output.is_none();
 
-	for path in &rule.inputs {
+	for (path, input) in rule.inputs.iter().zip(rule.input_paths()) {
 		let has_rule = check_dep(path);
-		let mtime = stat_cache.mtime(path.as_path())?;
+		let mtime = stat_cache.mtime(input)?;
 		if mtime.is_none() {
 			outdated = true;
 			debug!(
@@ -172,3 +206,342 @@ pub fn check_inputs<'a>(
 
 	Ok(outdated)
 }
+
+/// Get the `mtime` to record in the build log for a rule that just finished
+/// running.
+///
+/// Normally, this is the `mtime` of the newest output.
+///
+/// However, if the rule uses [`restat`][crate::spec::BuildCommand::restat]
+/// and the command left any of its outputs untouched, this is the `mtime` of
+/// the newest input instead. That way, [`check_outputs`] can tell the next
+/// time that the outputs are up to date with the inputs, even though the
+/// outputs themselves are older. This also holds for the very first time the
+/// rule is built. For rules that use [`deps`][crate::spec::BuildCommand::deps],
+/// the dependencies of the outputs in the `dep_log` count as inputs too.
+///
+/// `old_mtimes` are the `mtime`s of the outputs (in the same order) from
+/// right before the command was run. They are only used for `restat` rules.
+pub fn restat_mtime(
+	rule: &BuildRule,
+	dep_log: &DepLog,
+	old_mtimes: &[Option<Timestamp>],
+) -> Result<Option<Timestamp>, Error> {
+	let restat = rule.command.as_ref().is_some_and(|c| c.restat);
+
+	let mut newest_output = None;
+	let mut unchanged = false;
+
+	for (i, output) in rule.output_paths().enumerate() {
+		let new_mtime = mtime(output)?;
+		if restat && new_mtime.is_some() && old_mtimes.get(i) == Some(&new_mtime) {
+			debug!("{:?} was not changed by its restat rule.", output);
+			unchanged = true;
+		}
+		newest_output = newest_output.max(new_mtime);
+	}
+
+	if !unchanged {
+		return Ok(newest_output);
+	}
+
+	let mut newest_input = None;
+	for input in rule.input_paths() {
+		newest_input = newest_input.max(mtime(input)?);
+	}
+
+	if rule.command.as_ref().is_some_and(|c| c.deps.is_some()) {
+		for output in &rule.outputs {
+			if let Some(deps) = dep_log.get(output) {
+				for dep in deps.deps() {
+					newest_input = newest_input.max(mtime(dep.as_path())?);
+				}
+			}
+		}
+	}
+
+	Ok(newest_input)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::deplog::DepLogMut;
+	use crate::queue::{BuildQueue, DepInfo, TaskInfo, TaskStatus};
+	use crate::spec::{read_from, Spec};
+	use crate::test_dir::TestDir;
+	use std::collections::HashMap;
+	use std::fs::{write, File};
+	use std::path::{Path, PathBuf};
+	use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+	fn set_mtime(path: &Path, secs: u64) -> Result<(), Error> {
+		File::options()
+			.write(true)
+			.open(path)?
+			.set_modified(UNIX_EPOCH + Duration::from_secs(secs))
+	}
+
+	fn secs(secs: u64) -> Option<Timestamp> {
+		Some(Timestamp::from_system_time(
+			UNIX_EPOCH + Duration::from_secs(secs),
+		))
+	}
+
+	/// Make a queue to build the `target` task like `ninj` does, using
+	/// [`is_outdated`] to decide what needs to run.
+	fn make_queue(
+		spec: &Spec,
+		target: usize,
+		dep_log: &DepLog,
+		build_log: &BuildLog,
+	) -> BuildQueue {
+		let index = spec.make_index();
+		let mut stat_cache = StatCache::new();
+		let mut dep_stat_cache = StatCache::new();
+		BuildQueue::new(spec.build_rules.len(), Some(target), |task| {
+			let mut dependencies = Vec::new();
+			let outdated = is_outdated(
+				&spec.build_rules[task],
+				dep_log,
+				build_log,
+				&mut stat_cache,
+				&mut dep_stat_cache,
+				|input| {
+					let dep = index.get(input);
+					dependencies.extend(dep.map(|&task| DepInfo {
+						task,
+						order_only: false,
+					}));
+					dep.is_some()
+				},
+			)
+			.unwrap();
+			TaskInfo {
+				phony: false,
+				dependencies,
+				validations: None,
+				outdated,
+				pool: None,
+			}
+		})
+	}
+
+	#[test]
+	fn restat_first_build() -> Result<(), Error> {
+		let dir = TestDir::new("restat-first-build");
+		let input = dir.join("in.txt");
+		let output = dir.join("out.h");
+		let dependent = dir.join("final");
+		let log_file = dir.join(".ninja_log");
+
+		// The output is older than the input, but the (restat) command will
+		// not touch it, as it would generate the exact same contents.
+		write(&input, "input")?;
+		write(&output, "output")?;
+		write(&dependent, "final")?;
+		set_mtime(&input, 2000)?;
+		set_mtime(&output, 1000)?;
+		set_mtime(&dependent, 3000)?;
+
+		let source = format!(
+			"rule gen\n  command = true\n  restat = 1\n\
+			 rule cat\n  command = cat $in > $out\n\
+			 rule gendeps\n  command = true\n  restat = 1\n  deps = gcc\n  depfile = $out.d\n\
+			 build {0}/out.h: gen {0}/in.txt\n\
+			 build {0}/final: cat {0}/out.h\n\
+			 build {0}/deps.h: gendeps {0}/in.txt\n",
+			dir.display()
+		);
+		let spec = read_from(Path::new("build.ninja"), source.as_bytes()).unwrap();
+		let gen = &spec.build_rules[0];
+		let dep_log = DepLog::new();
+
+		// First build: No build log yet, so only the restat rule runs.
+		let mut build_log = BuildLog::new();
+		let mut queue = make_queue(&spec, 1, &dep_log, &build_log);
+		assert_eq!(queue.next(), Some(0));
+		assert_eq!(queue.next(), None);
+
+		// Run the restat rule, which leaves its output untouched.
+		let old_mtimes = [mtime(&output)?];
+		let logged = restat_mtime(gen, &dep_log, &old_mtimes)?;
+		assert_eq!(logged, secs(2000));
+		let now = Instant::now();
+		build_log.add_entry(gen, now, now, now, logged);
+		build_log.write(&log_file)?;
+
+		// The dependent is re-checked, and doesn't need to run.
+		let mut recheck = |task: usize| {
+			is_outdated(
+				&spec.build_rules[task],
+				&dep_log,
+				&build_log,
+				&mut StatCache::new(),
+				&mut StatCache::new(),
+				|_| true,
+			)
+			.unwrap()
+		};
+		queue.complete_task(0, Some(&mut recheck));
+		assert_eq!(queue.next(), None);
+		assert_eq!(queue.get_task_status(1), TaskStatus::NotRun);
+		assert_eq!(queue.n_left(), 0);
+
+		// Second build: Nothing changed, so nothing is scheduled.
+		let mut build_log = BuildLog::read(&log_file)?;
+		let mut queue = make_queue(&spec, 1, &dep_log, &build_log);
+		assert_eq!(queue.n_left(), 0);
+		assert_eq!(queue.next(), None);
+
+		// When the command does write the output, its own mtime is logged.
+		File::options()
+			.write(true)
+			.open(&output)?
+			.set_modified(SystemTime::now())?;
+		assert_eq!(restat_mtime(gen, &dep_log, &old_mtimes)?, mtime(&output)?);
+
+		// For a restat rule with `deps`, the logged dependencies count as
+		// inputs as well.
+		let gendeps = &spec.build_rules[2];
+		let deps_output = dir.join("deps.h");
+		let header = dir.join("header.h");
+		write(&deps_output, "output")?;
+		write(&header, "header")?;
+		set_mtime(&deps_output, 1000)?;
+		set_mtime(&header, 2500)?;
+		let mut dep_log = DepLogMut::open(dir.join(".ninja_deps"))?;
+		dep_log.insert_deps(
+			format!("{}", deps_output.display()).as_str().into(),
+			mtime(&deps_output)?,
+			vec![format!("{}", header.display()).as_str().into()],
+		)?;
+		let run_gendeps = |build_log: &mut BuildLog| -> Result<Option<Timestamp>, Error> {
+			let mut queue = make_queue(&spec, 2, &dep_log, build_log);
+			assert_eq!(queue.next(), Some(2));
+			assert_eq!(queue.next(), None);
+			// The command leaves its output untouched.
+			let logged = restat_mtime(gendeps, &dep_log, &[mtime(&deps_output)?])?;
+			build_log.add_entry(gendeps, now, now, now, logged);
+			Ok(logged)
+		};
+
+		// The header is newer than the input, so its mtime is logged.
+		assert_eq!(run_gendeps(&mut build_log)?, secs(2500));
+		assert_eq!(make_queue(&spec, 2, &dep_log, &build_log).n_left(), 0);
+
+		// Touching the header runs the rule again, but only once.
+		set_mtime(&header, 3500)?;
+		assert_eq!(run_gendeps(&mut build_log)?, secs(3500));
+		assert_eq!(make_queue(&spec, 2, &dep_log, &build_log).n_left(), 0);
+
+		Ok(())
+	}
+
+	/// Check if the first build rule is outdated, with the given files (and
+	/// their `mtime`s in seconds) in a fake file system.
+	fn outdated_with(dep_log: &DepLog, files: &[(&str, u64)]) -> Result<bool, Error> {
+		let spec = read_from(
+			Path::new("build.ninja"),
+			&b"rule cc\n  command = cc $in -o $out\n  depfile = $out.d\n  deps = gcc\n\
+			   build out.o: cc in.c\n"[..],
+		)
+		.unwrap();
+		let fs: HashMap<PathBuf, Timestamp> = files
+			.iter()
+			.map(|&(path, t)| (PathBuf::from(path), secs(t).unwrap()))
+			.collect();
+		is_outdated(
+			&spec.build_rules[0],
+			dep_log,
+			&BuildLog::new(),
+			&mut StatCache::with_fs(fs.clone()),
+			&mut StatCache::with_fs(fs),
+			|_| false,
+		)
+	}
+
+	#[test]
+	fn always() -> Result<(), Error> {
+		let spec = read_from(
+			Path::new("build.ninja"),
+			&b"rule stamp\n  command = stamp $out\n  always = true\n\
+			   rule cp\n  command = cp $in $out\n\
+			   build stamp.h: stamp in.txt\n\
+			   build out.txt: cp in.txt\n\
+			   build out2.txt: cp in.txt\n  always = 1\n"[..],
+		)
+		.unwrap();
+		let fs: HashMap<PathBuf, Timestamp> = [
+			("in.txt", 10),
+			("stamp.h", 20),
+			("out.txt", 20),
+			("out2.txt", 20),
+		]
+		.iter()
+		.map(|&(path, t)| (PathBuf::from(path), secs(t).unwrap()))
+		.collect();
+		let outdated = |rule: &BuildRule| {
+			is_outdated(
+				rule,
+				&DepLog::new(),
+				&BuildLog::new(),
+				&mut StatCache::with_fs(fs.clone()),
+				&mut StatCache::with_fs(fs.clone()),
+				|_| false,
+			)
+		};
+		// All outputs are newer than the input, but `always` is set.
+		assert!(outdated(&spec.build_rules[0])?);
+		assert!(!outdated(&spec.build_rules[1])?);
+		assert!(outdated(&spec.build_rules[2])?);
+		Ok(())
+	}
+
+	#[test]
+	fn fake_fs() -> Result<(), Error> {
+		let dir = TestDir::new("outdated-deps");
+		let mut dep_log = DepLogMut::open(dir.join(".ninja_deps"))?;
+		dep_log.insert_deps("out.o".into(), secs(20), vec!["in.h".into()])?;
+		let dep_log = &*dep_log;
+
+		// Up to date.
+		assert!(!outdated_with(
+			dep_log,
+			&[("in.c", 10), ("in.h", 10), ("out.o", 20)]
+		)?);
+		// Newer input.
+		assert!(outdated_with(
+			dep_log,
+			&[("in.c", 30), ("in.h", 10), ("out.o", 20)]
+		)?);
+		// Missing output.
+		assert!(outdated_with(dep_log, &[("in.c", 10), ("in.h", 10)])?);
+		// Newer logged dependency.
+		assert!(outdated_with(
+			dep_log,
+			&[("in.c", 10), ("in.h", 30), ("out.o", 20)]
+		)?);
+		// Missing logged dependency.
+		assert!(outdated_with(dep_log, &[("in.c", 10), ("out.o", 20)])?);
+		// Logged dependencies are older than the output.
+		assert!(outdated_with(
+			dep_log,
+			&[("in.c", 10), ("in.h", 10), ("out.o", 25)]
+		)?);
+		// No logged dependencies at all.
+		assert!(outdated_with(
+			&DepLog::new(),
+			&[("in.c", 10), ("in.h", 10), ("out.o", 20)]
+		)?);
+		// Missing input, without a rule to make it.
+		assert_eq!(
+			outdated_with(dep_log, &[("in.h", 10), ("out.o", 20)])
+				.unwrap_err()
+				.kind(),
+			ErrorKind::NotFound
+		);
+
+		Ok(())
+	}
+}
@@ -0,0 +1,311 @@
+//! Running subprocesses while capturing their output live.
+
+use log::warn;
+use nix::poll::{poll, EventFlags, PollFd};
+use std::fs::File;
+use std::io::{pipe, Error, ErrorKind, Read};
+use std::os::fd::OwnedFd;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+use std::process::{Child, ChildStdout, Command, ExitStatus};
+use std::slice::from_raw_parts_mut;
+
+/// The stream on which a child process produced output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Source {
+	/// The standard output stream.
+	Stdout,
+	/// The standard error stream.
+	Stderr,
+}
+
+/// Spawns a command with both its stdout and stderr connected to a single
+/// pipe, like `2>&1` in a shell.
+///
+/// The [`stdout`][Child::stdout] of the returned [`Child`] gives the merged
+/// output, in the order in which the command wrote it. Its
+/// [`stderr`][Child::stderr] is `None`. So, when given to
+/// [`listen_to_child`], all output is reported as [`Source::Stdout`].
+///
+/// Any stdout or stderr configuration on `command` is overridden.
+pub fn spawn_merged(mut command: Command) -> Result<Child, Error> {
+	let (reader, writer) = pipe()?;
+	let mut child = command.stdout(writer.try_clone()?).stderr(writer).spawn()?;
+	// Make sure our copies of the writing end are closed, so we'll see the end
+	// of the stream once the child closes its copies.
+	drop(command);
+	child.stdout = Some(ChildStdout::from(OwnedFd::from(reader)));
+	Ok(child)
+}
+
+/// Waits for a [`Child`] to finish, while reading its output live as it runs.
+///
+/// Captures stdout and/or stderr, if they are set to
+/// [`piped`][std::process::Stdio::piped]. Streams that are not piped are
+/// ignored.
+///
+/// The captured output is buffered, and `output_callback` is called with the
+/// [`Source`] of the output and the buffered bytes:
+///
+///  - after `timeout_ms` milliseconds of silence,
+///  - when the output switches between stdout and stderr (or back), and
+///  - once more at the end, if there's anything left in the buffer.
+///
+/// The callback is never called with an empty slice. The bytes are passed on
+/// as they were read, so a single call might end halfway through a line (or
+/// even halfway through a UTF-8 sequence), if the child paused there for
+/// longer than `timeout_ms`.
+///
+/// Waits for the child to exit, and returns its [`ExitStatus`].
+///
+/// # Errors
+///
+/// If polling or reading any of the pipes fails, the child is killed and
+/// waited for (so no zombie process is left behind), and the error is
+/// returned. Any output that was already read is still given to
+/// `output_callback` first.
+///
+/// # Example
+///
+/// ```no_run
+/// use ninj::process::listen_to_child;
+/// use raw_string::RawStr;
+/// use std::process::{Command, Stdio};
+///
+/// let child = Command::new("cargo")
+///     .stdin(Stdio::null())
+///     .stdout(Stdio::piped())
+///     .stderr(Stdio::piped())
+///     .arg("build")
+///     .spawn()?;
+///
+/// let result = listen_to_child(child, 100, |source, buffer| {
+///     println!("{:?}: {}", source, RawStr::from_bytes(buffer));
+/// })?;
+///
+/// println!("Subprocess exited: {}", result);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn listen_to_child(
+	mut child: Child,
+	timeout_ms: i32,
+	mut output_callback: impl FnMut(Source, &[u8]),
+) -> Result<ExitStatus, Error> {
+	// The file descriptors we'll be reading from.
+	let mut fds = [
+		child.stdout.take().map(|f| unsafe { into_file(f) }),
+		child.stderr.take().map(|f| unsafe { into_file(f) }),
+	];
+
+	// Data that has been read from one of the pipes, and where it came from.
+	let mut buffer = Vec::<u8>::with_capacity(16 * 1024);
+	let mut buffer_source = Source::Stdout;
+
+	let result = read_pipes(
+		&mut fds,
+		timeout_ms,
+		&mut buffer,
+		&mut buffer_source,
+		&mut output_callback,
+	);
+
+	// Flush the buffer, if there's anything in there.
+	if !buffer.is_empty() {
+		output_callback(buffer_source, &buffer);
+	}
+
+	if let Err(e) = result {
+		// Don't leave the child running (or as a zombie) if we can no longer
+		// listen to it.
+		drop(fds);
+		if let Err(e) = child.kill() {
+			warn!("Unable to kill subprocess: {}", e);
+		}
+		child.wait().ok();
+		return Err(e);
+	}
+
+	// Both stderr and stdout have been closed. Now we just wait for the process to
+	// exit.
+	child.wait()
+}
+
+/// Reads from the pipes until they are all closed.
+fn read_pipes(
+	fds: &mut [Option<File>; 2],
+	timeout_ms: i32,
+	buffer: &mut Vec<u8>,
+	buffer_source: &mut Source,
+	output_callback: &mut impl FnMut(Source, &[u8]),
+) -> Result<(), Error> {
+	const SOURCES: [Source; 2] = [Source::Stdout, Source::Stderr];
+
+	loop {
+		// The list of file descriptors `poll` will need to check, for the
+		// streams that are still open. (Together with the index into `fds`.)
+		let mut indexes = Vec::with_capacity(2);
+		let mut poll_fds = Vec::with_capacity(2);
+		for (i, fd) in fds.iter().enumerate() {
+			if let Some(fd) = fd {
+				indexes.push(i);
+				poll_fds.push(PollFd::new(fd.as_raw_fd(), EventFlags::POLLIN));
+			}
+		}
+
+		// If all are closed, we stop reading them.
+		if poll_fds.is_empty() {
+			return Ok(());
+		}
+
+		let timeout_ms = if buffer.is_empty() {
+			-1
+		} else {
+			// If there's data in the buffer, we should output it after
+			// `timeout_ms` milliseconds of silence.
+			timeout_ms
+		};
+
+		// Wait until there's data to read, or the timeout occurs.
+		let n_ready = match poll(&mut poll_fds, timeout_ms) {
+			Ok(n) => n,
+			Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+			Err(nix::Error::Sys(errno)) => return Err(errno.into()),
+			Err(e) => return Err(Error::other(e)),
+		};
+
+		if n_ready == 0 {
+			// Timeout.
+			// Flush the buffer.
+			output_callback(*buffer_source, buffer);
+			buffer.clear();
+			continue;
+		}
+
+		// New data (or errors) available.
+		for (poll_fd, &i) in poll_fds.iter().zip(&indexes) {
+			let revents = poll_fd.revents().unwrap_or_else(EventFlags::empty);
+
+			if revents.contains(EventFlags::POLLNVAL) {
+				return Err(Error::new(
+					ErrorKind::InvalidInput,
+					format!("Invalid file descriptor for {:?}", SOURCES[i]),
+				));
+			}
+
+			let mut closed = false;
+
+			if revents.contains(EventFlags::POLLIN) {
+				// Output switched between stdout and stderr. Flush first.
+				if SOURCES[i] != *buffer_source && !buffer.is_empty() {
+					output_callback(*buffer_source, buffer);
+					buffer.clear();
+				}
+				*buffer_source = SOURCES[i];
+
+				// Reserve 4 KiB of space in the buffer.
+				buffer.reserve(4 * 1024);
+
+				// The unused (free) part of the buffer.
+				let buffer_free_space = unsafe {
+					from_raw_parts_mut(
+						buffer.as_mut_ptr().add(buffer.len()),
+						buffer.capacity() - buffer.len(),
+					)
+				};
+
+				// Read the bytes from the pipe.
+				// This is guaranteed to not block, because `poll` said so.
+				let n_read = match fds[i].as_mut().map(|f| f.read(buffer_free_space)) {
+					Some(Ok(n)) => n,
+					Some(Err(ref e)) if e.kind() == ErrorKind::Interrupted => continue,
+					Some(Err(e)) => return Err(e),
+					None => 0,
+				};
+
+				// Make the read bytes part of the buffer.
+				let new_len = buffer.len() + n_read;
+				unsafe { buffer.set_len(new_len) };
+
+				// End of file.
+				closed = n_read == 0;
+			} else if revents.contains(EventFlags::POLLERR) {
+				warn!("Error condition on {:?} of subprocess", SOURCES[i]);
+				closed = true;
+			}
+
+			if closed || revents == EventFlags::POLLHUP {
+				// Close our side of the pipe which was closed by the client.
+				fds[i].take();
+			}
+		}
+	}
+}
+
+unsafe fn into_file(stream: impl IntoRawFd) -> File {
+	File::from_raw_fd(stream.into_raw_fd())
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::process::Stdio;
+
+	#[test]
+	fn listen() -> Result<(), Error> {
+		let child = Command::new("sh")
+			.arg("-c")
+			.arg("echo out; sleep 0.1; echo err >&2; sleep 0.1; echo out2; exit 3")
+			.stdin(Stdio::null())
+			.stdout(Stdio::piped())
+			.stderr(Stdio::piped())
+			.spawn()?;
+		let mut output = Vec::new();
+		let status = listen_to_child(child, 10, |source, data| {
+			assert!(!data.is_empty());
+			output.push((source, data.to_vec()));
+		})?;
+		assert_eq!(status.code(), Some(3));
+		assert_eq!(
+			output,
+			[
+				(Source::Stdout, b"out\n".to_vec()),
+				(Source::Stderr, b"err\n".to_vec()),
+				(Source::Stdout, b"out2\n".to_vec()),
+			]
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn merged() -> Result<(), Error> {
+		let mut command = Command::new("sh");
+		command
+			.arg("-c")
+			.arg("echo out; echo err >&2; echo out2; echo err2 >&2")
+			.stdin(Stdio::null());
+		let child = spawn_merged(command)?;
+		let mut output = Vec::new();
+		let status = listen_to_child(child, 100, |source, data| {
+			assert_eq!(source, Source::Stdout);
+			output.extend_from_slice(data);
+		})?;
+		assert!(status.success());
+		assert_eq!(output, b"out\nerr\nout2\nerr2\n");
+		Ok(())
+	}
+
+	#[test]
+	fn not_piped() -> Result<(), Error> {
+		let child = Command::new("sh")
+			.arg("-c")
+			.arg("echo hello")
+			.stdin(Stdio::null())
+			.stdout(Stdio::piped())
+			.stderr(Stdio::null())
+			.spawn()?;
+		let mut output = Vec::new();
+		let status = listen_to_child(child, 100, |_, data| output.extend_from_slice(data))?;
+		assert!(status.success());
+		assert_eq!(output, b"hello\n");
+		Ok(())
+	}
+}
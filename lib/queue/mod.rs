@@ -5,7 +5,8 @@
 //! anything about the tasks, and only refers to them by 'task number', which is
 //! simply an index into a vector.
 
-use std::mem::replace;
+use std::collections::VecDeque;
+use std::mem::take;
 use std::sync::{Condvar, Mutex, MutexGuard};
 use std::time::{Duration, Instant};
 
@@ -17,12 +18,13 @@ use std::time::{Duration, Instant};
 ///
 ///  - The state (waiting, running, finished, etc.),
 ///  - whether it is a 'phony' task,
-///  - whether it was marked as outdated, and
-///  - the task numbers of the tasks it depends on.
+///  - whether it was marked as outdated,
+///  - the task numbers of the tasks it depends on, and
+///  - the pool it runs in, if any.
 ///
 /// The [`next`][Self::next] method gives the next task to be run. After the
 /// task is done, [`complete_task`][Self::complete_task] should be called to
-/// update the queue.
+/// update the queue, or [`fail_task`][Self::fail_task] if the task failed.
 ///
 /// [`make_async`][Self::make_async] turns this into a concurrent
 /// data-structure on which threads can [wait][LockedAsyncBuildQueue::wait].
@@ -33,12 +35,31 @@ pub struct BuildQueue {
 	/// The index in this vector is their ID.
 	tasks: Vec<Task>,
 	/// The tasks which are ready to run, will never contain phony tasks.
+	///
+	/// Tasks in here might still have to wait for their pool, in which case
+	/// they are moved to [`Pool::delayed`] by [`next_at`][Self::next_at].
 	ready: Vec<usize>,
+	/// The pools, indexed by [`PoolInfo::id`].
+	pools: Vec<Pool>,
 	/// Number of non-phony tasks which still need to be started.
 	///
 	/// Includes tasks which are not oudated, but might turn out to be outdated
 	/// later.
 	n_left: usize,
+	/// Set by [`stop`][Self::stop]. No new tasks will be started.
+	stopped: bool,
+	/// Set by [`set_deadline`][Self::set_deadline]. No new tasks will be
+	/// started after this time.
+	deadline: Option<Instant>,
+	/// Set when the queue stopped because the deadline passed.
+	deadline_reached: bool,
+	/// Number of non-phony tasks which ran successfully.
+	n_finished: usize,
+	/// Number of tasks which failed.
+	n_failed: usize,
+	/// Number of non-phony tasks which were skipped because a dependency
+	/// failed.
+	n_skipped: usize,
 }
 
 /// A task tracked by a [`BuildQueue`].
@@ -51,6 +72,19 @@ pub struct Task {
 	/// Number of unfinished build rules which have this rule in their `next`
 	/// list.
 	n_deps_left: usize,
+	/// The pool this task runs in, if any.
+	pool: Option<usize>,
+}
+
+/// The state of a pool of a [`BuildQueue`].
+#[derive(Clone, Debug, Default)]
+struct Pool {
+	/// The maximum number of tasks of this pool that can run at the same time.
+	depth: usize,
+	/// The number of tasks of this pool that are running.
+	n_running: usize,
+	/// Tasks that are ready to run, but had to wait because the pool was full.
+	delayed: VecDeque<usize>,
 }
 
 /// The status of a [`Task`] inside a [`BuildQueue`].
@@ -86,6 +120,14 @@ pub enum TaskStatus {
 	/// The task is phony and was outdated, and all dependencies have been
 	/// finished.
 	PhonyFinished,
+	/// The task ran, but failed.
+	Failed {
+		/// The time it took to run this task.
+		running_time: Duration,
+	},
+	/// The task will not run, because one of its (indirect) dependencies
+	/// failed.
+	Skipped,
 }
 
 /// Wraps a [`BuildQueue`] to allow multiple threads to use it and wait for it.
@@ -105,12 +147,29 @@ pub struct LockedAsyncBuildQueue<'a> {
 ///
 /// [`BuildQueue::new`] requires [`dependencies`][Self::dependencies] to be
 /// <code>IntoIter&lt;Item =
-/// <a href="struct.DepInfo.html">DepInfo</a>&gt;</code>.
+/// <a href="struct.DepInfo.html">DepInfo</a>&gt;</code>, and
+/// [`validations`][Self::validations] to be
+/// <code>IntoIter&lt;Item = usize&gt;</code>.
 #[derive(Debug, Clone, Copy)]
-pub struct TaskInfo<T> {
+pub struct TaskInfo<T, V> {
 	pub phony: bool,
 	pub dependencies: T,
+	/// Tasks which need to run whenever this task is needed, but which this
+	/// task does not depend on.
+	pub validations: V,
 	pub outdated: bool,
+	/// The pool this task runs in, or `None` if its concurrency isn't limited.
+	pub pool: Option<PoolInfo>,
+}
+
+/// The information the [`BuildQueue`] needs for the pool of a task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolInfo {
+	/// Identifies the pool. Should be small, as it's used as an index.
+	pub id: usize,
+	/// The maximum number of tasks in this pool that can run at the same time.
+	/// Should be at least 1.
+	pub depth: usize,
 }
 
 /// The information the [`BuildQueue`] needs for each task dependency.
@@ -130,19 +189,53 @@ impl BuildQueue {
 	///
 	/// - `get_task` is used to get the information the queue needs of each
 	///   (relevant) task: Whether it is phony, on which tasks it depends (and
-	///   how), and if the target is outdated. It is called exactly once for
-	///   every task in the dependency tree of the targets.
-	pub fn new<T, F, D>(max_task_num: usize, targets: T, mut get_task: F) -> BuildQueue
+	///   how), which tasks validate it, and if the target is outdated. It is
+	///   called exactly once for every task in the dependency tree of the
+	///   targets.
+	///
+	/// Validation tasks are added to the queue as if they were extra targets.
+	/// The task they validate does not wait for them.
+	pub fn new<T, F, D, V>(max_task_num: usize, targets: T, get_task: F) -> BuildQueue
 	where
 		T: IntoIterator<Item = usize>,
-		F: FnMut(usize) -> TaskInfo<D>,
+		F: FnMut(usize) -> TaskInfo<D, V>,
 		D: IntoIterator<Item = DepInfo>,
+		V: IntoIterator<Item = usize>,
+	{
+		Self::new_filtered(max_task_num, targets, |_| true, get_task)
+	}
+
+	/// Construct a new build dependency graph, leaving out some of the tasks.
+	///
+	/// Like [`new`][Self::new], but `include` is first called for every task
+	/// in the dependency tree. Tasks for which it returns `false` are treated
+	/// as provided externally: They are never run and their dependencies are
+	/// not looked at, so tasks only needed by excluded tasks are left out as
+	/// well. `get_task` is not called for excluded tasks.
+	///
+	/// Excluded tasks are assumed to be present and up to date. They never
+	/// make the tasks depending on them outdated. (Although `get_task` might
+	/// still say those are outdated, e.g. because the excluded task's output
+	/// is newer.)
+	pub fn new_filtered<T, P, F, D, V>(
+		max_task_num: usize,
+		targets: T,
+		mut include: P,
+		mut get_task: F,
+	) -> BuildQueue
+	where
+		T: IntoIterator<Item = usize>,
+		P: FnMut(usize) -> bool,
+		F: FnMut(usize) -> TaskInfo<D, V>,
+		D: IntoIterator<Item = DepInfo>,
+		V: IntoIterator<Item = usize>,
 	{
 		let mut tasks = vec![
 			Task {
 				status: TaskStatus::NotNeeded,
 				next: vec![],
 				n_deps_left: 0,
+				pool: None,
 			};
 			max_task_num
 		];
@@ -159,11 +252,24 @@ impl BuildQueue {
 		let mut n_tasks = 0;
 		let mut finished = Vec::new();
 		let mut ready = Vec::new();
+		let mut pools = Vec::<Pool>::new();
 
 		// Build dependency graph
 		while let Some(task) = to_visit.pop() {
 			assert_eq!(tasks[task].status, TaskStatus::WillBeNeeded);
+			if !include(task) {
+				tasks[task].status = TaskStatus::NotRun;
+				finished.push(task);
+				continue;
+			}
 			let mut info = get_task(task);
+			if let Some(pool) = info.pool {
+				if pools.len() <= pool.id {
+					pools.resize_with(pool.id + 1, Pool::default);
+				}
+				pools[pool.id].depth = pool.depth;
+				tasks[task].pool = Some(pool.id);
+			}
 			let mut n_deps = 0;
 			let mut n_order_deps = 0;
 			for dep in info.dependencies {
@@ -180,6 +286,12 @@ impl BuildQueue {
 					order_only: dep.order_only,
 				});
 			}
+			for validation in info.validations {
+				if tasks[validation].status == TaskStatus::NotNeeded {
+					to_visit.push(validation);
+					tasks[validation].status = TaskStatus::WillBeNeeded;
+				}
+			}
 			if !info.outdated && n_deps > 0 && n_deps == n_order_deps {
 				// This task is not outdated, and have no dependencies that can
 				// make it outdated. Therefore, it does not need to run.
@@ -218,7 +330,14 @@ impl BuildQueue {
 		let mut queue = BuildQueue {
 			tasks,
 			ready,
+			pools,
 			n_left: n_tasks,
+			stopped: false,
+			deadline: None,
+			deadline_reached: false,
+			n_finished: 0,
+			n_failed: 0,
+			n_skipped: 0,
 		};
 
 		// Mark any ready phony tasks as finished, and update the tasks
@@ -245,15 +364,26 @@ impl BuildQueue {
 	///
 	/// Returns the index of the task. Will never return a phony tasks, as
 	/// those don't have any work to do.
+	#[allow(clippy::should_implement_trait)]
 	pub fn next(&mut self) -> Option<usize> {
 		self.next_at(Instant::now())
 	}
 
 	/// Like next(), returns the next thing to do, but notes it as having
 	/// started at the given time instead of now.
+	///
+	/// Tasks in a pool that is full are not returned, but wait until another
+	/// task of that pool is finished.
 	pub fn next_at(&mut self, start_time: Instant) -> Option<usize> {
-		let next = self.ready.pop();
-		if let Some(next) = next {
+		if self.stopped {
+			return None;
+		}
+		if self.deadline.is_some_and(|deadline| start_time >= deadline) {
+			self.stopped = true;
+			self.deadline_reached = true;
+			return None;
+		}
+		while let Some(next) = self.ready.pop() {
 			assert_eq!(self.tasks[next].n_deps_left, 0);
 			assert_eq!(
 				self.tasks[next].status,
@@ -262,10 +392,35 @@ impl BuildQueue {
 					outdated: true
 				}
 			);
+			if let Some(pool) = self.tasks[next].pool {
+				let pool = &mut self.pools[pool];
+				if pool.n_running >= pool.depth {
+					pool.delayed.push_back(next);
+					continue;
+				}
+				pool.n_running += 1;
+			}
 			self.tasks[next].status = TaskStatus::Running { start_time };
 			self.n_left -= 1;
+			return Some(next);
 		}
-		next
+		None
+	}
+
+	/// Make room in the pool of a task that is no longer running, making the
+	/// next delayed task of that pool ready again.
+	///
+	/// Returns the number of newly ready tasks: zero or one.
+	fn leave_pool(&mut self, task: usize) -> usize {
+		if let Some(pool) = self.tasks[task].pool {
+			let pool = &mut self.pools[pool];
+			pool.n_running -= 1;
+			if let Some(delayed) = pool.delayed.pop_front() {
+				self.ready.push(delayed);
+				return 1;
+			}
+		}
+		0
 	}
 
 	/// Mark the task as ready, possibly queueing dependent tasks.
@@ -313,7 +468,8 @@ impl BuildQueue {
 				task, self.tasks[task]
 			),
 		};
-		let mut newly_ready = 0;
+		self.n_finished += 1;
+		let mut newly_ready = self.leave_pool(task);
 		let mut newly_finished = Vec::new();
 		newly_ready += self.update_finished_task(task, &mut newly_finished, restat);
 		while let Some(task) = newly_finished.pop() {
@@ -322,6 +478,81 @@ impl BuildQueue {
 		newly_ready
 	}
 
+	/// Mark the task as failed, and mark all tasks depending on it (directly
+	/// or indirectly) as [`Skipped`][TaskStatus::Skipped].
+	///
+	/// Skipped tasks will never become ready, but no longer count towards
+	/// [`n_left`][Self::n_left].
+	///
+	/// # Panics
+	/// Panics when the task wasn't running.
+	pub fn fail_task(&mut self, task: usize) {
+		self.fail_task_at(task, Instant::now())
+	}
+
+	/// Like `fail_task`, marks a task as failed, but notes it as having
+	/// finished at the given time instead of now.
+	///
+	/// # Panics
+	/// Panics when
+	///  - `finish_time` is before the
+	///    [`start_time`][TaskStatus::Running::start_time] of the task; or
+	///  - the task wasn't running.
+	pub fn fail_task_at(&mut self, task: usize, finish_time: Instant) {
+		self.tasks[task].status = match &self.tasks[task].status {
+			TaskStatus::Running { start_time } => TaskStatus::Failed {
+				running_time: finish_time - *start_time,
+			},
+			_ => panic!(
+				"fail_task({}) on task that isn't Running: {:?}",
+				task, self.tasks[task]
+			),
+		};
+		self.n_failed += 1;
+		self.leave_pool(task);
+		let mut to_skip = take(&mut self.tasks[task].next);
+		while let Some(DepInfo { task: next, .. }) = to_skip.pop() {
+			match self.tasks[next].status {
+				TaskStatus::Needed { phony, .. } => {
+					if !phony {
+						self.n_left -= 1;
+						self.n_skipped += 1;
+					}
+				}
+				TaskStatus::Skipped => continue,
+				_ => unreachable!(
+					"Task {} was not `Needed', but depends on failed task {}: {:?}",
+					next, task, self.tasks[next]
+				),
+			}
+			self.tasks[next].status = TaskStatus::Skipped;
+			to_skip.append(&mut self.tasks[next].next);
+		}
+	}
+
+	/// Stop starting new tasks.
+	///
+	/// After this, [`next`][Self::next] will always return `None`. Tasks that
+	/// are already running can still be completed.
+	pub fn stop(&mut self) {
+		self.stopped = true;
+	}
+
+	/// Stop starting new tasks once the given time has passed.
+	///
+	/// Like [`stop`][Self::stop], tasks that are already running can still be
+	/// completed. [`n_left`][Self::n_left] then gives the number of tasks
+	/// that were not started because of the deadline.
+	pub fn set_deadline(&mut self, deadline: Instant) {
+		self.deadline = Some(deadline);
+	}
+
+	/// Check if the queue stopped because the deadline set by
+	/// [`set_deadline`][Self::set_deadline] passed.
+	pub fn deadline_reached(&self) -> bool {
+		self.deadline_reached
+	}
+
 	/// Decrement the `n_deps_left` of all the tasks depending on this task,
 	/// and mark any newly ready tasks as ready.
 	///
@@ -344,11 +575,13 @@ impl BuildQueue {
 		for DepInfo {
 			task: next,
 			order_only,
-		} in replace(&mut self.tasks[task].next, Vec::new())
+		} in take(&mut self.tasks[task].next)
 		{
 			let next_phony;
 			let next_outdated;
 			match &mut self.tasks[next].status {
+				// Another dependency of this task failed.
+				TaskStatus::Skipped => continue,
 				TaskStatus::Needed { phony, outdated } => {
 					if did_run && !order_only && !*outdated {
 						*outdated = if let Some(restat) = restat.as_mut() {
@@ -399,11 +632,31 @@ impl BuildQueue {
 	pub fn n_left(&self) -> usize {
 		self.n_left
 	}
+
+	/// Number of tasks which ran successfully.
+	///
+	/// Does not include phony tasks, or tasks that did not need to run.
+	pub fn n_finished(&self) -> usize {
+		self.n_finished
+	}
+
+	/// Number of tasks which failed.
+	pub fn n_failed(&self) -> usize {
+		self.n_failed
+	}
+
+	/// Number of tasks which were skipped because one of their (indirect)
+	/// dependencies failed.
+	///
+	/// Does not include phony tasks.
+	pub fn n_skipped(&self) -> usize {
+		self.n_skipped
+	}
 }
 
 impl AsyncBuildQueue {
 	/// Get exclusive access to the build queue.
-	pub fn lock(&self) -> LockedAsyncBuildQueue {
+	pub fn lock(&self) -> LockedAsyncBuildQueue<'_> {
 		LockedAsyncBuildQueue {
 			queue: self.queue.lock().unwrap(),
 			condvar: &self.condvar,
@@ -422,11 +675,17 @@ impl<'a> LockedAsyncBuildQueue<'a> {
 	/// those don't have any work to do.
 	///
 	/// Does not block.
+	#[allow(clippy::should_implement_trait)]
 	pub fn next(&mut self) -> Option<usize> {
+		let was_stopped = self.queue.stopped;
 		let next = self.queue.next();
 		if next.is_some() && self.queue.n_left == 0 {
 			self.condvar.notify_all();
 		}
+		if !was_stopped && self.queue.stopped {
+			// The deadline passed: Wake up all waiting threads so they stop.
+			self.condvar.notify_all();
+		}
 		next
 	}
 
@@ -434,10 +693,15 @@ impl<'a> LockedAsyncBuildQueue<'a> {
 	///
 	/// Returns `None` when all tasks are finished.
 	pub fn wait(mut self) -> Option<usize> {
-		while self.queue.ready.is_empty() && self.queue.n_left > 0 {
+		loop {
+			if let Some(next) = self.next() {
+				return Some(next);
+			}
+			if self.queue.stopped || self.queue.n_left == 0 {
+				return None;
+			}
 			self.queue = self.condvar.wait(self.queue).unwrap();
 		}
-		self.next()
 	}
 
 	/// Mark the task as ready, unblocking dependent tasks.
@@ -456,6 +720,33 @@ impl<'a> LockedAsyncBuildQueue<'a> {
 		}
 	}
 
+	/// Mark the task as failed, skipping all tasks that depend on it.
+	///
+	/// See [`BuildQueue::fail_task`].
+	pub fn fail_task(&mut self, task: usize) {
+		self.queue.fail_task(task);
+		if self.queue.n_left == 0 || !self.queue.ready.is_empty() {
+			// Either all threads can stop, or one might be waiting for a pool
+			// that now has room.
+			self.condvar.notify_all();
+		}
+	}
+
+	/// Number of tasks which failed.
+	///
+	/// See [`BuildQueue::n_failed`].
+	pub fn n_failed(&self) -> usize {
+		self.queue.n_failed()
+	}
+
+	/// Stop starting new tasks, and wake up all threads waiting for one.
+	///
+	/// See [`BuildQueue::stop`].
+	pub fn stop(&mut self) {
+		self.queue.stop();
+		self.condvar.notify_all();
+	}
+
 	/// Get a full copy of the internal state.
 	///
 	/// This is useful if you want to inspect the full state without blocking
@@ -464,3 +755,231 @@ impl<'a> LockedAsyncBuildQueue<'a> {
 		self.queue.clone()
 	}
 }
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	/// Make a queue of outdated non-phony tasks, with the given dependencies
+	/// and validations.
+	fn make_queue(targets: &[usize], tasks: &[(&[usize], &[usize])]) -> BuildQueue {
+		BuildQueue::new(tasks.len(), targets.iter().cloned(), |task| {
+			let (deps, validations) = tasks[task];
+			TaskInfo {
+				phony: false,
+				dependencies: deps.iter().map(|&task| DepInfo {
+					task,
+					order_only: false,
+				}),
+				validations: validations.iter().cloned(),
+				outdated: true,
+				pool: None,
+			}
+		})
+	}
+
+	#[test]
+	fn validations() {
+		// Task 0 depends on task 1, and is validated by task 2, which itself
+		// depends on task 0.
+		let mut queue = make_queue(&[0], &[(&[1], &[2]), (&[], &[]), (&[0], &[])]);
+		assert_eq!(queue.n_left(), 3);
+		assert_eq!(queue.next(), Some(1));
+		assert_eq!(queue.next(), None);
+		queue.complete_task(1, None);
+		// Task 0 does not wait for its validation.
+		assert_eq!(queue.next(), Some(0));
+		assert_eq!(queue.next(), None);
+		queue.complete_task(0, None);
+		assert_eq!(queue.next(), Some(2));
+		queue.complete_task(2, None);
+		assert_eq!(queue.n_left(), 0);
+	}
+
+	#[test]
+	fn validations_not_needed() {
+		// Task 1 is only needed as validation of task 0, which is not needed.
+		let mut queue = make_queue(&[2], &[(&[], &[1]), (&[], &[]), (&[], &[])]);
+		assert_eq!(queue.n_left(), 1);
+		assert_eq!(queue.get_task_status(1), TaskStatus::NotNeeded);
+		assert_eq!(queue.next(), Some(2));
+	}
+
+	#[test]
+	fn filtered() {
+		// Task 0 depends on tasks 1 and 2. Task 2 depends on task 3.
+		let tasks: &[&[usize]] = &[&[1, 2], &[], &[3], &[]];
+		let mut queue = BuildQueue::new_filtered(
+			tasks.len(),
+			vec![0],
+			|task| task != 2,
+			|task| {
+				assert_ne!(task, 2);
+				TaskInfo {
+					phony: false,
+					dependencies: tasks[task].iter().map(|&task| DepInfo {
+						task,
+						order_only: false,
+					}),
+					validations: None,
+					outdated: true,
+					pool: None,
+				}
+			},
+		);
+		assert_eq!(queue.get_task_status(2), TaskStatus::NotRun);
+		assert_eq!(queue.get_task_status(3), TaskStatus::NotNeeded);
+		assert_eq!(queue.n_left(), 2);
+		assert_eq!(queue.next(), Some(1));
+		assert_eq!(queue.next(), None);
+		queue.complete_task(1, None);
+		assert_eq!(queue.next(), Some(0));
+		queue.complete_task(0, None);
+		assert_eq!(queue.n_left(), 0);
+	}
+
+	#[test]
+	fn diamond_failure() {
+		// Task 0 depends on tasks 1 and 2, which both depend on task 3.
+		// Task 4 only depends on task 2.
+		let mut queue = make_queue(
+			&[0, 4],
+			&[
+				(&[1, 2], &[]),
+				(&[3], &[]),
+				(&[3], &[]),
+				(&[], &[]),
+				(&[2], &[]),
+			],
+		);
+		assert_eq!(queue.n_left(), 5);
+		assert_eq!(queue.next(), Some(3));
+		queue.complete_task(3, None);
+		let mut started = vec![queue.next().unwrap(), queue.next().unwrap()];
+		started.sort();
+		assert_eq!(started, [1, 2]);
+		assert_eq!(queue.next(), None);
+		queue.fail_task(2);
+		assert!(matches!(
+			queue.get_task_status(2),
+			TaskStatus::Failed { .. }
+		));
+		assert_eq!(queue.get_task_status(0), TaskStatus::Skipped);
+		assert_eq!(queue.get_task_status(4), TaskStatus::Skipped);
+		// Task 1 is still running, and can still finish.
+		assert_eq!(queue.n_left(), 0);
+		queue.complete_task(1, None);
+		assert!(matches!(
+			queue.get_task_status(1),
+			TaskStatus::Finished { .. }
+		));
+		assert_eq!(queue.get_task_status(0), TaskStatus::Skipped);
+		assert_eq!(queue.next(), None);
+		assert_eq!(queue.n_finished(), 2);
+		assert_eq!(queue.n_failed(), 1);
+		assert_eq!(queue.n_skipped(), 2);
+	}
+
+	#[test]
+	fn failure_wakes_waiters() {
+		// Task 0 depends on task 1. Once task 1 fails, there's nothing left to
+		// do, so a thread waiting for a task should return.
+		let queue = make_queue(&[0], &[(&[1], &[]), (&[], &[])]).make_async();
+		assert_eq!(queue.lock().next(), Some(1));
+		crossbeam::thread::scope(|scope| {
+			let waiter = scope.spawn(|_| queue.lock().wait());
+			std::thread::sleep(Duration::from_millis(10));
+			queue.lock().fail_task(1);
+			assert_eq!(waiter.join().unwrap(), None);
+		})
+		.unwrap();
+		let queue = queue.into_inner();
+		assert_eq!(queue.n_left(), 0);
+		assert_eq!(queue.n_skipped(), 1);
+	}
+
+	#[test]
+	fn stop() {
+		let mut queue = make_queue(&[0, 1], &[(&[], &[]), (&[], &[])]);
+		assert!(queue.next().is_some());
+		queue.stop();
+		assert_eq!(queue.next(), None);
+		let queue = queue.make_async();
+		assert_eq!(queue.lock().wait(), None);
+	}
+
+	#[test]
+	fn pools() {
+		// Tasks 0, 1 and 2 are in a pool of depth 2. Task 3 is not in a pool.
+		let mut queue = BuildQueue::new(4, 0..4, |task| TaskInfo {
+			phony: false,
+			dependencies: None,
+			validations: None,
+			outdated: true,
+			pool: if task < 3 {
+				Some(PoolInfo { id: 0, depth: 2 })
+			} else {
+				None
+			},
+		});
+		let mut started = [
+			queue.next().unwrap(),
+			queue.next().unwrap(),
+			queue.next().unwrap(),
+		];
+		assert_eq!(queue.next(), None);
+		started.sort();
+		assert_eq!(started[2], 3);
+		assert_eq!(queue.n_left(), 1);
+		// Finishing a task of the pool makes room for the last one.
+		assert_eq!(queue.complete_task(3, None), 0);
+		assert_eq!(queue.next(), None);
+		assert_eq!(queue.complete_task(started[0], None), 1);
+		let last = queue.next().unwrap();
+		assert!(last < 3 && !started.contains(&last));
+		// Failing a task also makes room.
+		queue.fail_task(started[1]);
+		assert_eq!(queue.pools[0].n_running, 1);
+		queue.complete_task(last, None);
+		assert_eq!(queue.n_left(), 0);
+	}
+
+	#[test]
+	fn pool_wakes_waiters() {
+		// Tasks 0 and 1 are in a pool of depth 1.
+		let queue = BuildQueue::new(2, 0..2, |_| TaskInfo {
+			phony: false,
+			dependencies: None,
+			validations: None,
+			outdated: true,
+			pool: Some(PoolInfo { id: 0, depth: 1 }),
+		})
+		.make_async();
+		let first = queue.lock().next().unwrap();
+		crossbeam::thread::scope(|scope| {
+			let waiter = scope.spawn(|_| queue.lock().wait());
+			std::thread::sleep(Duration::from_millis(10));
+			queue.lock().complete_task(first, None);
+			assert_eq!(waiter.join().unwrap(), Some(1 - first));
+		})
+		.unwrap();
+	}
+
+	#[test]
+	fn deadline() {
+		let mut queue = make_queue(&[0], &[(&[1, 2], &[]), (&[], &[]), (&[], &[])]);
+		let start = Instant::now();
+		queue.set_deadline(start + Duration::from_secs(10));
+		let first = queue.next_at(start).unwrap();
+		assert!(first == 1 || first == 2);
+		assert!(!queue.deadline_reached());
+		// Tasks that are running can still complete after the deadline, but
+		// no new tasks are started.
+		let after = start + Duration::from_secs(11);
+		queue.complete_task_at(first, None, after);
+		assert_eq!(queue.next_at(after), None);
+		assert!(queue.deadline_reached());
+		assert_eq!(queue.n_finished(), 1);
+		assert_eq!(queue.n_left(), 2);
+	}
+}
@@ -1,7 +1,33 @@
 use raw_string::RawString;
 
+/// Quickly checks if a path is already canonical, such that
+/// [`canonicalize_path_in_place`] doesn't have to touch it.
+///
+/// This is conservative: It returns false for some canonical paths, like
+/// `../a` and `.`, which are then left to the full algorithm.
+fn is_canonical(path: &[u8]) -> bool {
+	let path = path.strip_prefix(b"/").unwrap_or(path);
+	if cfg!(windows) && memchr::memchr(b'\\', path).is_some() {
+		return false;
+	}
+	// Every component must be non-empty (so no '//' and no trailing '/'), and
+	// must not be '.' or '..'.
+	let mut rest = path;
+	loop {
+		let end = memchr::memchr(b'/', rest).unwrap_or(rest.len());
+		let component = &rest[..end];
+		if component.is_empty() || component == b"." || component == b".." {
+			return false;
+		}
+		if end == rest.len() {
+			return true;
+		}
+		rest = &rest[end + 1..];
+	}
+}
+
 pub fn canonicalize_path_in_place(path: &mut RawString) {
-	if path.is_empty() {
+	if path.is_empty() || is_canonical(path.as_bytes()) {
 		return;
 	}
 
@@ -143,4 +169,17 @@ mod test {
 		assert_eq!(canonicalize_path_str("../foo/../..".to_string()), "../..");
 		assert_eq!(canonicalize_path_str("../x/a/b/../c/../..".to_string()), "../x");
 	}
+
+	#[test]
+	fn test_is_canonical() {
+		for path in &["a", "/a", "a/b.c", "a/.b", ".a/b..", "a/...", "/a/b/c"] {
+			assert!(is_canonical(path.as_bytes()), "{}", path);
+			assert_eq!(&canonicalize_path_str(path.to_string()), path);
+		}
+		for path in &[
+			"/", ".", "..", "a/", "a//b", "./a", "a/./b", "a/..", "../a", "//a",
+		] {
+			assert!(!is_canonical(path.as_bytes()), "{}", path);
+		}
+	}
 }
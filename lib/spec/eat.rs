@@ -64,6 +64,19 @@ pub fn eat_path<'a>(src: &mut &'a RawStr) -> Result<&'a RawStr, ParseError> {
 	}
 }
 
+/// Check that a path contains no bytes that are dangerous in shell commands.
+///
+/// Rejects control characters (including newlines), and any of
+/// ``$ ` ; & | < > ( ) \ " ' * ?``.
+pub fn check_safe_path(path: &RawStr) -> Result<(), ParseError> {
+	let unsafe_byte = |c: u8| c.is_ascii_control() || b"$`;&|<>()\\\"'*?".contains(&c);
+	if path.bytes().any(unsafe_byte) {
+		Err(ParseError::UnsafePath)
+	} else {
+		Ok(())
+	}
+}
+
 pub fn eat_paths<'a>(
 	src: &mut &'a RawStr,
 	endings: &[u8],
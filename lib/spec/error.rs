@@ -29,6 +29,10 @@ pub enum ParseError {
 	ExpectedRuleName,
 	ExpectedEndOfLine,
 	InvalidEscape,
+	/// A path contains shell metacharacters or control characters, while
+	/// [`reject_unsafe_paths`][super::ReadOptions::reject_unsafe_paths] is
+	/// enabled.
+	UnsafePath,
 }
 
 impl fmt::Display for ParseError {
@@ -49,6 +53,7 @@ impl fmt::Display for ParseError {
 				ExpectedRuleName => "Missing rule name",
 				ExpectedEndOfLine => "Garbage at end of line",
 				InvalidEscape => "Invalid $-escape (literal `$' is written as `$$')",
+				UnsafePath => "Path contains shell metacharacters or control characters",
 			}
 		)
 	}
@@ -111,6 +116,22 @@ pub enum ReadError {
 		file_name: std::path::PathBuf,
 		error: std::io::Error,
 	},
+	/// The file sets `ninja_required_version` to a version newer than
+	/// [`SUPPORTED_NINJA_VERSION`][super::SUPPORTED_NINJA_VERSION].
+	VersionTooOld {
+		/// The version required by the file.
+		required: String,
+		/// The version supported by this implementation.
+		supported: &'static str,
+	},
+	/// Two of the files given to [`read_all`][super::read_all] have a build
+	/// rule with the same output.
+	ConflictingOutput {
+		/// The output.
+		path: RawString,
+		/// The two files that both build it.
+		files: (std::path::PathBuf, std::path::PathBuf),
+	},
 	/// Invalid UTF-8 encoding in path.
 	///
 	/// This error does not occor on Unix. On Unix, the raw bytes are used in
@@ -140,6 +161,19 @@ impl fmt::Display for ReadError {
 			ReadError::IoError { file_name, error } => {
 				write!(f, "Unable to read {:?}: {}", file_name, error)
 			}
+			ReadError::VersionTooOld {
+				required,
+				supported,
+			} => write!(
+				f,
+				"File requires ninja version {}, but only version {} is supported",
+				required, supported
+			),
+			ReadError::ConflictingOutput { path, files } => write!(
+				f,
+				"Output {:?} is built by both {:?} and {:?}",
+				path, files.0, files.1
+			),
 			ReadError::InvalidUtf8 { var } => {
 				write!(f, "Invalid UTF-8 encoding")?;
 				if let Some(var) = var {
@@ -160,6 +194,90 @@ impl Error for ReadError {
 	}
 }
 
+/// A problem in a [`Spec`][super::Spec] which does not prevent reading it,
+/// but is most likely a mistake.
+///
+/// See [`Spec::validate`][super::Spec::validate] and
+/// [`Spec::check_rules`][super::Spec::check_rules].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+	/// An output of a build rule is also an input (or order-only dependency)
+	/// of the same rule. This makes the rule always outdated.
+	OutputIsInput {
+		/// The index of the build rule.
+		rule: usize,
+		/// The output that is also an input.
+		path: RawString,
+	},
+	/// The command of a non-phony build rule is empty (or only whitespace),
+	/// for example because it refers to an undefined variable.
+	EmptyCommand {
+		/// The index of the build rule.
+		rule: usize,
+		/// The first output of the build rule.
+		output: RawString,
+	},
+	/// The `depfile` or `rspfile` of a build rule is not a valid file name.
+	InvalidPath {
+		/// The index of the build rule.
+		rule: usize,
+		/// The first output of the build rule.
+		output: RawString,
+		/// The name of the variable: `depfile` or `rspfile`.
+		var: &'static str,
+		/// The invalid path.
+		path: RawString,
+	},
+	/// Multiple build rules have the same output.
+	///
+	/// See [`Spec::make_index_with`][super::Spec::make_index_with].
+	DuplicateOutput {
+		/// The indexes of the two build rules.
+		rules: (usize, usize),
+		/// The output they have in common.
+		path: RawString,
+	},
+	/// An input of a build rule does not exist, and no rule produces it.
+	MissingInput {
+		/// The index of the build rule.
+		rule: usize,
+		/// The first output of the build rule.
+		output: RawString,
+		/// The missing input.
+		path: RawString,
+	},
+}
+
+impl fmt::Display for ValidationError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ValidationError::OutputIsInput { path, .. } => {
+				write!(f, "Build rule for {:?} depends on its own output", path)
+			}
+			ValidationError::EmptyCommand { output, .. } => {
+				write!(f, "Build rule for {:?} has an empty command", output)
+			}
+			ValidationError::InvalidPath {
+				output, var, path, ..
+			} => write!(
+				f,
+				"Build rule for {:?} has an invalid {}: {:?}",
+				output, var, path
+			),
+			ValidationError::DuplicateOutput { path, .. } => {
+				write!(f, "Multiple rules generating {:?}", path)
+			}
+			ValidationError::MissingInput { output, path, .. } => write!(
+				f,
+				"Build rule for {:?} needs {:?}, which is missing and not produced by any rule",
+				output, path
+			),
+		}
+	}
+}
+
+impl Error for ValidationError {}
+
 impl From<ParseError> for ReadError {
 	fn from(src: ParseError) -> ReadError {
 		ReadError::ParseError(src)
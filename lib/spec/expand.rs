@@ -79,11 +79,10 @@ pub fn expand_path<T: AsRef<RawStr>, S: VarScope>(
 }
 
 fn is_shell_safe(c: u8) -> bool {
-	match c {
-		b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => true,
-		b'_' | b'-' | b'+' | b'/' | b'.' => true,
-		_ => false,
-	}
+	matches!(
+		c,
+		b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_' | b'-' | b'+' | b'/' | b'.'
+	)
 }
 
 fn write_shell_escaped_to(source: &RawStr, output: &mut RawString) {
@@ -107,6 +106,24 @@ fn write_shell_escaped_to(source: &RawStr, output: &mut RawString) {
 	}
 }
 
+/// Write the paths like `$in`, `$out`, and `$in_newline` expand to:
+/// shell-escaped, and separated by spaces or terminated by newlines.
+pub(crate) fn write_shell_escaped_paths_to(
+	paths: &[RawString],
+	newlines: bool,
+	output: &mut RawString,
+) {
+	for (i, p) in paths.iter().enumerate() {
+		if !newlines && i > 0 {
+			output.push(b' ');
+		}
+		write_shell_escaped_to(p, output);
+		if newlines {
+			output.push(b'\n');
+		}
+	}
+}
+
 fn expand_var_to<S: VarScope>(
 	var_name: &str,
 	scope: &S,
@@ -116,15 +133,7 @@ fn expand_var_to<S: VarScope>(
 	match scope.lookup_var(var_name) {
 		Some(FoundVar::Expanded(e)) => result.push_str(e),
 		Some(FoundVar::Paths { paths, newlines }) => {
-			for (i, p) in paths.iter().enumerate() {
-				if !newlines && i > 0 {
-					result.push(b' ');
-				}
-				write_shell_escaped_to(p, result);
-				if newlines {
-					result.push(b'\n');
-				}
-			}
+			write_shell_escaped_paths_to(paths, newlines, result);
 		}
 		Some(FoundVar::Unexpanded(e)) => {
 			check_recursion(var_name, prot)?;
@@ -223,7 +232,7 @@ fn check_recursion(
 pub fn expand_str_test() {
 	struct Scope;
 	impl VarScope for Scope {
-		fn lookup_var(&self, var_name: &str) -> Option<FoundVar> {
+		fn lookup_var(&self, var_name: &str) -> Option<FoundVar<'_>> {
 			match var_name {
 				"world" => Some(FoundVar::Expanded("TEST".as_ref())),
 				"WORLD" => Some(FoundVar::Expanded("$TEST".as_ref())),
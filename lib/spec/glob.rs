@@ -0,0 +1,212 @@
+//! Glob patterns over declared outputs.
+//!
+//! Ninja doesn't support globs. This is an extension, to select targets like
+//! `**/*.test` from the outputs of the build rules of a [`Spec`]. The file
+//! system is never consulted: Only paths that are declared as outputs can
+//! match.
+//!
+//! Supported syntax:
+//!
+//!  - `*` matches any sequence of characters within a path component (not
+//!    including `/`).
+//!  - `**` matches any sequence of characters, including `/`. `**/` also
+//!    matches nothing, such that `**/*.o` also matches `a.o`.
+//!  - `?` matches a single character other than `/`.
+//!  - `[abc]`, `[a-z]` and `[!a-z]` match a single character in (or not in)
+//!    the set.
+//!
+//! [`Spec`]: super::Spec
+
+use raw_string::RawStr;
+use std::collections::HashMap;
+
+/// Check if the path contains any glob metacharacters.
+///
+/// Paths without them only match themselves.
+pub fn is_glob(pattern: &RawStr) -> bool {
+	pattern
+		.as_bytes()
+		.iter()
+		.any(|&b| b == b'*' || b == b'?' || b == b'[')
+}
+
+/// Check if the path matches the glob pattern.
+pub fn glob_match(pattern: &RawStr, path: &RawStr) -> bool {
+	matches(pattern.as_bytes(), path.as_bytes())
+}
+
+/// Find all outputs in the index (as made by
+/// [`make_index`][super::Spec::make_index]) that match the pattern, sorted
+/// by path.
+pub fn match_outputs<'a>(pattern: &RawStr, index: &HashMap<&'a RawStr, usize>) -> Vec<&'a RawStr> {
+	let mut outputs: Vec<&RawStr> = index
+		.keys()
+		.copied()
+		.filter(|output| glob_match(pattern, output))
+		.collect();
+	outputs.sort_unstable();
+	outputs
+}
+
+fn matches(pattern: &[u8], path: &[u8]) -> bool {
+	Matcher {
+		pattern,
+		path,
+		failed: vec![false; (pattern.len() + 1) * (path.len() + 1)],
+	}
+	.matches(0, 0)
+}
+
+/// Matches a pattern against a path, remembering which combinations of
+/// positions in both failed to match.
+///
+/// Without that, patterns with many stars (like `**a**a**a**b`) take
+/// exponential time.
+struct Matcher<'a> {
+	pattern: &'a [u8],
+	path: &'a [u8],
+	failed: Vec<bool>,
+}
+
+impl<'a> Matcher<'a> {
+	/// Check if `pattern[p..]` matches `path[i..]`.
+	fn matches(&mut self, p: usize, i: usize) -> bool {
+		let key = p * (self.path.len() + 1) + i;
+		if self.failed[key] {
+			return false;
+		}
+		let matched = self.match_uncached(p, i);
+		if !matched {
+			self.failed[key] = true;
+		}
+		matched
+	}
+
+	fn match_uncached(&mut self, p: usize, i: usize) -> bool {
+		let pattern = self.pattern;
+		let path = self.path;
+		let at = |rest: &[u8]| pattern.len() - rest.len();
+		match &pattern[p..] {
+			[] => i == path.len(),
+			[b'*', b'*', b'/', rest @ ..] => {
+				let rest = at(rest);
+				self.matches(rest, i)
+					|| (i..path.len()).any(|j| path[j] == b'/' && self.matches(rest, j + 1))
+			}
+			[b'*', b'*', rest @ ..] => {
+				let rest = at(rest);
+				(i..=path.len()).any(|j| self.matches(rest, j))
+			}
+			[b'*', rest @ ..] => {
+				let rest = at(rest);
+				for j in i..=path.len() {
+					if self.matches(rest, j) {
+						return true;
+					}
+					if path.get(j) == Some(&b'/') {
+						break;
+					}
+				}
+				false
+			}
+			[b'?', ..] => match path.get(i) {
+				Some(&c) if c != b'/' => self.matches(p + 1, i + 1),
+				_ => false,
+			},
+			[b'[', class @ ..] => match parse_class(class) {
+				Some((set, negated, rest)) => match path.get(i) {
+					Some(&c) if c != b'/' && in_class(set, c) != negated => {
+						self.matches(at(rest), i + 1)
+					}
+					_ => false,
+				},
+				// Unterminated class: Match the `[` literally.
+				None => path.get(i) == Some(&b'[') && self.matches(p + 1, i + 1),
+			},
+			[c, ..] => path.get(i) == Some(c) && self.matches(p + 1, i + 1),
+		}
+	}
+}
+
+/// Parse the part of a `[..]` class after the `[`, returning the set, whether
+/// it's negated, and the rest of the pattern after the `]`.
+fn parse_class(class: &[u8]) -> Option<(&[u8], bool, &[u8])> {
+	let (negated, class) = match class {
+		[b'!', class @ ..] | [b'^', class @ ..] => (true, class),
+		_ => (false, class),
+	};
+	// A `]` directly at the start is part of the set.
+	let end = 1 + memchr::memchr(b']', class.get(1..)?)?;
+	Some((&class[..end], negated, &class[end + 1..]))
+}
+
+fn in_class(mut set: &[u8], c: u8) -> bool {
+	while let [first, rest @ ..] = set {
+		match rest {
+			[b'-', last, rest @ ..] => {
+				if (*first..=*last).contains(&c) {
+					return true;
+				}
+				set = rest;
+			}
+			_ => {
+				if *first == c {
+					return true;
+				}
+				set = rest;
+			}
+		}
+	}
+	false
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn patterns() {
+		let m = |p: &str, s: &str| glob_match(RawStr::from_str(p), RawStr::from_str(s));
+		assert!(m("a.o", "a.o"));
+		assert!(!m("a.o", "b.o"));
+		assert!(m("*.o", "a.o"));
+		assert!(!m("*.o", "x/a.o"));
+		assert!(m("x/*.o", "x/a.o"));
+		assert!(m("**/*.test", "a.test"));
+		assert!(m("**/*.test", "x/y/a.test"));
+		assert!(!m("**/*.test", "x/y/a.test.o"));
+		assert!(m("x/**", "x/y/z"));
+		assert!(m("x**.o", "x/y.o"));
+		assert!(m("?.o", "a.o"));
+		assert!(!m("?.o", "ab.o"));
+		assert!(!m("a?b", "a/b"));
+		assert!(m("[ab].o", "b.o"));
+		assert!(!m("[ab].o", "c.o"));
+		assert!(m("[a-c].o", "c.o"));
+		assert!(m("[!a-c].o", "d.o"));
+		assert!(!m("[!a-c].o", "b.o"));
+		assert!(m("[]].o", "].o"));
+		assert!(m("[a", "[a"));
+		// Many stars don't take exponential time.
+		let path = format!("{}/{}", "a".repeat(100), "a".repeat(100));
+		assert!(!m("**a**a**a**a**a**a**a**a**b", &path));
+		assert!(!m("*a*a*a*a*a*a*a*a*/*a*a*a*a*a*a*a*a*b", &path));
+		assert!(m("**a**a**a**a**a**a**a**a**a", &path));
+		assert!(is_glob(RawStr::from_str("**/*.test")));
+		assert!(!is_glob(RawStr::from_str("a/b.o")));
+	}
+
+	#[test]
+	fn outputs() {
+		let spec = super::super::read_from(
+			std::path::Path::new("build.ninja"),
+			b"build a.test b.o: phony\nbuild x/c.test: phony\n",
+		)
+		.unwrap();
+		let index = spec.make_index();
+		let m = |p: &str| match_outputs(RawStr::from_str(p), &index);
+		assert_eq!(m("**/*.test"), ["a.test", "x/c.test"]);
+		assert_eq!(m("*.test"), ["a.test"]);
+		assert!(m("*.c").is_empty());
+	}
+}
@@ -8,20 +8,41 @@ mod read;
 
 pub mod error;
 pub mod expand;
+pub mod glob;
 pub mod parse;
 pub mod scope;
 
 pub use self::read::read;
+pub use self::read::read_all;
+pub use self::read::read_collecting_errors;
 pub use self::read::read_from;
+pub use self::read::read_from_with;
+pub use self::read::read_with;
+pub use self::read::ReadOptions;
 
+use self::error::ValidationError;
+use crate::error::Location;
 use raw_string::unix::RawStrExt;
 use raw_string::{RawStr, RawString};
-use std::collections::HashMap;
-use std::path::Path;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// The version of ninja whose `build.ninja` files this implementation
+/// supports.
+///
+/// Files with a higher `ninja_required_version` are rejected with
+/// [`ReadError::VersionTooOld`][error::ReadError::VersionTooOld].
+///
+/// (`dyndep`, from ninja 1.10, is not supported, but results in an error of
+/// its own.)
+pub const SUPPORTED_NINJA_VERSION: &str = "1.11";
 
 /// The result of reading a `build.ninja` file, the specification of how to
 /// build what.
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Spec {
 	/// All the build rules.
 	pub build_rules: Vec<BuildRule>,
@@ -29,6 +50,62 @@ pub struct Spec {
 	pub default_targets: Vec<RawString>,
 	/// The build dir specified by `builddir = ..`, if any.
 	pub build_dir: Option<RawString>,
+	/// All `rule` definitions, in order of appearance.
+	pub rules: Vec<Definition>,
+	/// All `pool` definitions, in order of appearance.
+	///
+	/// Does not include the built-in `console` pool.
+	pub pools: Vec<Pool>,
+}
+
+/// Where a `rule` or `pool` was defined, and whether it was used.
+#[derive(Debug, Clone)]
+pub struct Definition {
+	/// The name of the rule or pool.
+	pub name: String,
+	/// The file in which it was defined.
+	pub file: Option<PathBuf>,
+	/// The line at which it was defined.
+	pub line: Option<NonZeroU32>,
+	/// Whether any `build` definition used it.
+	pub used: bool,
+}
+
+impl Definition {
+	/// Get the location at which it was defined.
+	pub fn location(&self) -> Location<'_> {
+		Location {
+			file: self.file.as_deref(),
+			line: self.line,
+		}
+	}
+}
+
+/// A `pool` definition.
+#[derive(Debug, Clone)]
+pub struct Pool {
+	/// Where the pool was defined, and whether it was used.
+	pub definition: Definition,
+	/// The maximum number of concurrent jobs in the pool.
+	pub depth: u16,
+}
+
+impl Pool {
+	/// The built-in `console` pool, with a depth of 1.
+	///
+	/// It has no location, and is always marked as used.
+	pub fn console() -> &'static Pool {
+		static CONSOLE: OnceLock<Pool> = OnceLock::new();
+		CONSOLE.get_or_init(|| Pool {
+			definition: Definition {
+				name: "console".to_string(),
+				file: None,
+				line: None,
+				used: true,
+			},
+			depth: 1,
+		})
+	}
 }
 
 /// How to build a set of outputs from a set of inputs.
@@ -41,11 +118,28 @@ pub struct BuildRule {
 	/// Usually just one.
 	///
 	/// Never empty, if produced by [`read()`].
+	///
+	/// Contains the explicit outputs first, followed by the implicit outputs.
+	/// See [`explicit_outputs`][Self::explicit_outputs] and
+	/// [`implicit_outputs`][Self::implicit_outputs].
 	pub outputs: Vec<RawString>,
+	/// The number of explicit outputs at the start of `outputs`.
+	pub n_explicit_outputs: usize,
 	/// The list of inputs.
+	///
+	/// Contains the explicit inputs first, followed by the implicit inputs.
+	/// See [`explicit_inputs`][Self::explicit_inputs] and
+	/// [`implicit_inputs`][Self::implicit_inputs].
 	pub inputs: Vec<RawString>,
+	/// The number of explicit inputs at the start of `inputs`.
+	pub n_explicit_inputs: usize,
 	/// The list of order-only dependencies
 	pub order_deps: Vec<RawString>,
+	/// The list of validations.
+	///
+	/// These need to be built whenever this rule is built, but this rule does
+	/// not depend on them.
+	pub validations: Vec<RawString>,
 	/// The details of command to run, or `None` for phony rules.
 	pub command: Option<BuildCommand>,
 }
@@ -57,6 +151,82 @@ impl BuildRule {
 	pub fn is_phony(&self) -> bool {
 		self.command.is_none()
 	}
+
+	/// Check if the build rule re-generates the build specification itself.
+	///
+	/// Returns true iff the `generator` variable is set for this rule.
+	pub fn is_generator(&self) -> bool {
+		self.command.as_ref().is_some_and(|c| c.generator)
+	}
+
+	/// The explicit outputs, which are the ones that appear in `$out`.
+	pub fn explicit_outputs(&self) -> &[RawString] {
+		&self.outputs[..self.n_explicit_outputs]
+	}
+
+	/// The implicit outputs, which don't appear in `$out`, but are produced
+	/// nonetheless.
+	pub fn implicit_outputs(&self) -> &[RawString] {
+		&self.outputs[self.n_explicit_outputs..]
+	}
+
+	/// The explicit inputs, which are the ones that appear in `$in`.
+	pub fn explicit_inputs(&self) -> &[RawString] {
+		&self.inputs[..self.n_explicit_inputs]
+	}
+
+	/// The implicit inputs, which don't appear in `$in`, but can still make
+	/// the outputs outdated.
+	pub fn implicit_inputs(&self) -> &[RawString] {
+		&self.inputs[self.n_explicit_inputs..]
+	}
+
+	/// What `$in` expands to for this rule: The explicit inputs,
+	/// shell-escaped, separated by spaces.
+	///
+	/// Useful for showing the inputs the same way as the command sees them.
+	pub fn expand_in(&self) -> RawString {
+		let mut result = RawString::new();
+		expand::write_shell_escaped_paths_to(self.explicit_inputs(), false, &mut result);
+		result
+	}
+
+	/// What `$out` expands to for this rule: The explicit outputs,
+	/// shell-escaped, separated by spaces.
+	pub fn expand_out(&self) -> RawString {
+		let mut result = RawString::new();
+		expand::write_shell_escaped_paths_to(self.explicit_outputs(), false, &mut result);
+		result
+	}
+
+	/// The outputs, as [`Path`]s.
+	///
+	/// This does not allocate or copy anything: On Unix, paths are just bytes,
+	/// so the [`RawString`]s are used as [`Path`]s directly. There's no need
+	/// to cache the result.
+	pub fn output_paths(&self) -> impl ExactSizeIterator<Item = &Path> {
+		self.outputs.iter().map(|p| p.as_path())
+	}
+
+	/// The inputs, as [`Path`]s.
+	///
+	/// Like [`output_paths`][Self::output_paths], this does not allocate.
+	pub fn input_paths(&self) -> impl ExactSizeIterator<Item = &Path> {
+		self.inputs.iter().map(|p| p.as_path())
+	}
+}
+
+/// What to do when multiple build rules have the same output.
+///
+/// See [`Spec::make_index_with`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateOutputs {
+	/// Fail with [`ValidationError::DuplicateOutput`].
+	Error,
+	/// Use the first build rule for the output, and log a warning.
+	FirstWins,
+	/// Use the last build rule for the output, and log a warning.
+	LastWins,
 }
 
 /// The method of discovering extra dependencies.
@@ -87,6 +257,9 @@ pub struct BuildCommand {
 	pub generator: bool,
 	/// Re-stat the command output to check if they actually changed.
 	pub restat: bool,
+	/// Always consider the outputs outdated, regardless of `mtime`s. Set by
+	/// the `always` variable.
+	pub always: bool,
 	/// A file to write before executing the command.
 	pub rspfile: RawString,
 	/// The contents of the file to write before executing the command.
@@ -98,6 +271,18 @@ pub struct BuildCommand {
 	pub pool_depth: Option<u16>,
 }
 
+impl BuildCommand {
+	/// The files other than the outputs that running the command produces:
+	/// The `depfile` and `rspfile`, if set.
+	///
+	/// These are removed when cleaning, just like the outputs.
+	pub fn transient_files(&self) -> impl Iterator<Item = &RawStr> {
+		IntoIterator::into_iter([&self.depfile, &self.rspfile])
+			.filter(|file| !file.is_empty())
+			.map(|file| &file[..])
+	}
+}
+
 impl Spec {
 	/// Create an empty specification.
 	pub fn new() -> Self {
@@ -105,6 +290,8 @@ impl Spec {
 			build_rules: Vec::new(),
 			default_targets: Vec::new(),
 			build_dir: None,
+			rules: Vec::new(),
+			pools: Vec::new(),
 		}
 	}
 
@@ -115,20 +302,519 @@ impl Spec {
 			.map_or(Path::new(""), |p| p.as_path())
 	}
 
+	/// Iterate over all build rules, together with the pool they run in.
+	///
+	/// Phony rules and rules without a `pool` give `None`. Rules in the
+	/// built-in `console` pool give [`Pool::console()`].
+	pub fn rules_with_pools(&self) -> impl Iterator<Item = (&BuildRule, Option<&Pool>)> {
+		self.build_rules.iter().map(move |rule| {
+			let pool = rule
+				.command
+				.as_ref()
+				.filter(|command| !command.pool.is_empty())
+				.map(|command| {
+					self.pools
+						.iter()
+						.find(|pool| pool.definition.name == command.pool)
+						.unwrap_or_else(|| Pool::console())
+				});
+			(rule, pool)
+		})
+	}
+
 	/// Generate an index mapping output file names to build rule indexes.
+	///
+	/// If multiple build rules have the same output, the last one is used,
+	/// and a warning is logged. See [`make_index_with`][Self::make_index_with]
+	/// for other options.
 	pub fn make_index(&self) -> HashMap<&RawStr, usize> {
+		self.make_index_with(DuplicateOutputs::LastWins)
+			.expect("LastWins never fails")
+	}
+
+	/// Generate an index mapping output file names to build rule indexes,
+	/// using the given policy for outputs that are produced by multiple build
+	/// rules.
+	///
+	/// Only fails for [`DuplicateOutputs::Error`].
+	pub fn make_index_with(
+		&self,
+		duplicates: DuplicateOutputs,
+	) -> Result<HashMap<&RawStr, usize>, ValidationError> {
 		use log::warn;
 		let mut index = HashMap::<&RawStr, usize>::new();
 		for (rule_i, rule) in self.build_rules.iter().enumerate() {
 			for output in &rule.outputs {
-				if index.insert(&output, rule_i).is_some() {
-					warn!(
-						"Warning, multiple rules generating {:?}. Ignoring all but last one.",
-						output
-					);
+				match index.entry(output) {
+					Entry::Vacant(e) => {
+						e.insert(rule_i);
+					}
+					Entry::Occupied(mut e) => match duplicates {
+						DuplicateOutputs::Error => {
+							return Err(ValidationError::DuplicateOutput {
+								rules: (*e.get(), rule_i),
+								path: output.clone(),
+							});
+						}
+						DuplicateOutputs::FirstWins => {
+							warn!(
+								"Warning, multiple rules generating {:?}. Ignoring all but first one.",
+								output
+							);
+						}
+						DuplicateOutputs::LastWins => {
+							warn!(
+								"Warning, multiple rules generating {:?}. Ignoring all but last one.",
+								output
+							);
+							e.insert(rule_i);
+						}
+					},
 				}
 			}
 		}
-		index
+		Ok(index)
+	}
+
+	/// Get the root targets: The outputs that are not used as an input,
+	/// order-only dependency, or validation by any build rule.
+	///
+	/// These are the targets ninja builds when no targets are given and there
+	/// are no `default` statements.
+	pub fn root_targets(&self) -> Vec<&RawStr> {
+		let mut used = HashSet::<&RawStr>::new();
+		for rule in &self.build_rules {
+			let deps = rule.inputs.iter().chain(&rule.order_deps);
+			used.extend(deps.chain(&rule.validations).map(|p| &p[..]));
+		}
+		self.build_rules
+			.iter()
+			.flat_map(|rule| &rule.outputs)
+			.map(|o| &o[..])
+			.filter(|o| !used.contains(o))
+			.collect()
+	}
+
+	/// The inputs (including order-only dependencies) that no build rule
+	/// produces, sorted by path.
+	///
+	/// These are the source files, which must exist for the build to succeed.
+	pub fn source_inputs(&self) -> BTreeSet<&RawStr> {
+		let index = self.make_index();
+		self.build_rules
+			.iter()
+			.flat_map(|rule| rule.inputs.iter().chain(&rule.order_deps))
+			.map(|p| &p[..])
+			.filter(|p| !index.contains_key(p))
+			.collect()
+	}
+
+	/// Count the build rules, as `(real, phony)`.
+	pub fn count_rules(&self) -> (usize, usize) {
+		let phony = self.build_rules.iter().filter(|r| r.is_phony()).count();
+		(self.build_rules.len() - phony, phony)
+	}
+
+	/// Count the outputs of all build rules.
+	///
+	/// Outputs generated by multiple rules are counted multiple times.
+	pub fn count_outputs(&self) -> usize {
+		self.build_rules.iter().map(|r| r.outputs.len()).sum()
+	}
+
+	/// Count the unique inputs of all build rules.
+	///
+	/// Includes both explicit and implicit inputs, but not order-only
+	/// dependencies.
+	pub fn count_inputs(&self) -> usize {
+		let mut inputs = HashSet::<&RawStr>::new();
+		for rule in &self.build_rules {
+			inputs.extend(rule.inputs.iter().map(|i| &i[..]));
+		}
+		inputs.len()
+	}
+
+	/// Check for mistakes that don't prevent building, but will probably not
+	/// do what was intended.
+	///
+	/// Currently only finds build rules which have one of their own outputs as
+	/// an input or order-only dependency.
+	pub fn validate(&self) -> Vec<ValidationError> {
+		let mut errors = Vec::new();
+		for (rule_i, rule) in self.build_rules.iter().enumerate() {
+			for output in &rule.outputs {
+				if rule.inputs.contains(output) || rule.order_deps.contains(output) {
+					errors.push(ValidationError::OutputIsInput {
+						rule: rule_i,
+						path: output.clone(),
+					});
+				}
+			}
+		}
+		errors
+	}
+
+	/// Find the build rules affected by changes to the given files: The rules
+	/// that have any of them as an input, directly or indirectly.
+	///
+	/// `index` is the index made by [`make_index`][Self::make_index].
+	/// `extra_deps` are additional `(output, dependency)` pairs, such as the
+	/// dependencies recorded in a [dependency log][crate::deplog::DepLog].
+	///
+	/// Order-only dependencies are not followed, as they don't make outputs
+	/// outdated. Returns the indexes of the affected rules, sorted.
+	pub fn affected_rules<'a, 'c>(
+		&'a self,
+		index: &HashMap<&'a RawStr, usize>,
+		extra_deps: impl IntoIterator<Item = (&'a RawStr, &'a RawStr)>,
+		changed: impl IntoIterator<Item = &'c RawStr>,
+	) -> Vec<usize> {
+		let mut dependents = HashMap::<&RawStr, Vec<usize>>::new();
+		for (rule_i, rule) in self.build_rules.iter().enumerate() {
+			for input in &rule.inputs {
+				dependents.entry(input).or_default().push(rule_i);
+			}
+		}
+		for (output, dep) in extra_deps {
+			if let Some(&rule_i) = index.get(output) {
+				dependents.entry(dep).or_default().push(rule_i);
+			}
+		}
+		let mut affected = vec![false; self.build_rules.len()];
+		let mut todo: Vec<usize> = changed
+			.into_iter()
+			.filter_map(|path| dependents.get(path))
+			.flatten()
+			.cloned()
+			.collect();
+		while let Some(rule_i) = todo.pop() {
+			if std::mem::replace(&mut affected[rule_i], true) {
+				continue;
+			}
+			for output in &self.build_rules[rule_i].outputs {
+				todo.extend(dependents.get(&output[..]).into_iter().flatten());
+			}
+		}
+		(0..affected.len()).filter(|&i| affected[i]).collect()
+	}
+
+	/// Check the build rules needed for the given targets for problems that
+	/// would make building them fail, without running anything.
+	///
+	/// `index` is the index made by [`make_index`][Self::make_index], and
+	/// `targets` are the indexes of the build rules for the targets.
+	///
+	/// Checks that the commands are not empty, that the `depfile` and
+	/// `rspfile` paths are valid file names, and that all inputs either exist
+	/// or are produced by some build rule.
+	pub fn check_rules(
+		&self,
+		index: &HashMap<&RawStr, usize>,
+		targets: impl IntoIterator<Item = usize>,
+	) -> Vec<ValidationError> {
+		let mut errors = Vec::new();
+		let mut seen = vec![false; self.build_rules.len()];
+		let mut todo: Vec<usize> = targets.into_iter().collect();
+		while let Some(rule_i) = todo.pop() {
+			if std::mem::replace(&mut seen[rule_i], true) {
+				continue;
+			}
+			let rule = &self.build_rules[rule_i];
+			let output = &rule.outputs[0];
+			if let Some(command) = &rule.command {
+				if command.command.bytes().all(|b| b.is_ascii_whitespace()) {
+					errors.push(ValidationError::EmptyCommand {
+						rule: rule_i,
+						output: output.clone(),
+					});
+				}
+				for (var, path) in &[("depfile", &command.depfile), ("rspfile", &command.rspfile)] {
+					if !path.is_empty() && (path.ends_with("/") || path.contains_byte(0)) {
+						errors.push(ValidationError::InvalidPath {
+							rule: rule_i,
+							output: output.clone(),
+							var,
+							path: (*path).clone(),
+						});
+					}
+				}
+			}
+			for input in &rule.inputs {
+				match index.get(&input[..]) {
+					Some(&dep) => todo.push(dep),
+					None if input.as_path().exists() => {}
+					None => errors.push(ValidationError::MissingInput {
+						rule: rule_i,
+						output: output.clone(),
+						path: input.clone(),
+					}),
+				}
+			}
+			let deps = rule.order_deps.iter().chain(&rule.validations);
+			todo.extend(deps.filter_map(|dep| index.get(&dep[..]).cloned()));
+		}
+		errors
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn read_str(source: &str) -> Spec {
+		read_from(Path::new("build.ninja"), source.as_bytes()).unwrap()
+	}
+
+	#[test]
+	fn implicit_outputs() {
+		let spec = read_str(
+			"rule touch\n  command = touch $out\n\
+			 build a | b: touch\n\
+			 build c d: touch\n\
+			 build e | f g: phony\n",
+		);
+		let rules = &spec.build_rules;
+		assert_eq!(rules[0].explicit_outputs(), ["a"]);
+		assert_eq!(rules[0].implicit_outputs(), ["b"]);
+		assert_eq!(rules[0].command.as_ref().unwrap().command, "touch a");
+		assert_eq!(rules[1].explicit_outputs(), ["c", "d"]);
+		assert!(rules[1].implicit_outputs().is_empty());
+		assert_eq!(rules[2].explicit_outputs(), ["e"]);
+		assert_eq!(rules[2].implicit_outputs(), ["f", "g"]);
+	}
+
+	#[test]
+	fn implicit_inputs() {
+		let spec = read_str(
+			"rule cc\n  command = cc $in -o $out\n  description = $in_newline\n\
+			 build a: cc b c | d || e\n\
+			 build f: cc | g\n",
+		);
+		let rules = &spec.build_rules;
+		assert_eq!(rules[0].explicit_inputs(), ["b", "c"]);
+		assert_eq!(rules[0].implicit_inputs(), ["d"]);
+		assert_eq!(rules[0].order_deps, ["e"]);
+		let command = rules[0].command.as_ref().unwrap();
+		assert_eq!(command.command, "cc b c -o a");
+		assert_eq!(command.description, "b\nc\n");
+		assert!(rules[1].explicit_inputs().is_empty());
+		assert_eq!(rules[1].implicit_inputs(), ["g"]);
+		assert_eq!(rules[1].command.as_ref().unwrap().command, "cc  -o f");
+	}
+
+	fn used(defs: &[Definition]) -> Vec<(&str, u32, bool)> {
+		defs.iter()
+			.map(|d| (d.name.as_str(), d.line.unwrap().get(), d.used))
+			.collect()
+	}
+
+	#[test]
+	fn unused_definitions() {
+		let spec = read_str(
+			"rule a\n  command = a\n\
+			 rule b\n  command = b\n  pool = p\n\
+			 rule c\n  command = c\n\
+			 pool p\n  depth = 1\n\
+			 pool q\n  depth = 1\n\
+			 build x: b\n\
+			 build y: c\n  pool = console\n",
+		);
+
+		assert_eq!(
+			used(&spec.rules),
+			[("a", 1, false), ("b", 3, true), ("c", 6, true)]
+		);
+		let pools: Vec<_> = spec.pools.iter().map(|p| p.definition.clone()).collect();
+		assert_eq!(used(&pools), [("p", 8, true), ("q", 10, false)]);
+	}
+
+	#[test]
+	fn rules_with_pools() {
+		let spec = read_str(
+			"pool p\n  depth = 3\n\
+			 rule a\n  command = a\n  pool = p\n\
+			 rule b\n  command = b\n\
+			 build w: a\n\
+			 build x: b\n\
+			 build y: b\n  pool = console\n\
+			 build z: phony\n",
+		);
+		let pools: Vec<_> = spec
+			.rules_with_pools()
+			.map(|(rule, pool)| {
+				let pool = pool.map(|p| (p.definition.name.as_str(), p.depth));
+				(rule.outputs[0].to_str().unwrap(), pool)
+			})
+			.collect();
+		assert_eq!(
+			pools,
+			[
+				("w", Some(("p", 3))),
+				("x", None),
+				("y", Some(("console", 1))),
+				("z", None),
+			]
+		);
+	}
+
+	#[test]
+	fn root_targets() {
+		let spec = read_str(
+			"rule r\n  command = r\n\
+			 build a: r b || c\n\
+			 build b: r |@ v\n\
+			 build c d: r\n\
+			 build v: r\n\
+			 build e: phony a\n",
+		);
+		assert_eq!(spec.root_targets(), ["d", "e"]);
+	}
+
+	#[test]
+	fn source_inputs() {
+		let spec = read_str(
+			"rule cc\n  command = cc\n\
+			 rule ld\n  command = ld\n\
+			 build gen.h: cc gen.py\n\
+			 build a.o: cc a.c | a.h || gen.h\n\
+			 build b.o: cc b.c | a.h\n\
+			 build app: ld a.o b.o lib.a\n\
+			 build all: phony app\n",
+		);
+		assert!(spec
+			.source_inputs()
+			.into_iter()
+			.eq(["a.c", "a.h", "b.c", "gen.py", "lib.a"]));
+	}
+
+	#[test]
+	fn check_rules() {
+		let spec = read_str(
+			"rule r\n  command = $cmd\n  depfile = $dep\n\
+			 build a: r b c\n  cmd = r\n\
+			 build b: r\n  dep = dir/\n  cmd = r\n\
+			 build c: r ninj-test-missing-input\n\
+			 build d: r\n",
+		);
+		let index = spec.make_index();
+		assert_eq!(
+			spec.check_rules(&index, vec![0]),
+			[
+				ValidationError::EmptyCommand {
+					rule: 2,
+					output: "c".into(),
+				},
+				ValidationError::MissingInput {
+					rule: 2,
+					output: "c".into(),
+					path: "ninj-test-missing-input".into(),
+				},
+				ValidationError::InvalidPath {
+					rule: 1,
+					output: "b".into(),
+					var: "depfile",
+					path: "dir/".into(),
+				},
+			]
+		);
+	}
+
+	#[test]
+	fn duplicate_outputs() {
+		let spec = read_str(
+			"rule r\n  command = r\n\
+			 build a b: r\n\
+			 build c: r\n\
+			 build b: phony c\n",
+		);
+		let index = spec.make_index_with(DuplicateOutputs::FirstWins).unwrap();
+		assert_eq!(index[RawStr::from_str("b")], 0);
+		let index = spec.make_index_with(DuplicateOutputs::LastWins).unwrap();
+		assert_eq!(index[RawStr::from_str("b")], 2);
+		assert_eq!(index, spec.make_index());
+		assert_eq!(
+			spec.make_index_with(DuplicateOutputs::Error),
+			Err(ValidationError::DuplicateOutput {
+				rules: (0, 2),
+				path: "b".into(),
+			})
+		);
+	}
+
+	#[test]
+	fn expand_in_out() {
+		let spec = read_str(
+			"rule cc\n  command = cc $in -o $out\n\
+			 build a$ b.o | a.d: cc a$ b.c it's.c | a.h\n",
+		);
+		let rule = &spec.build_rules[0];
+		assert_eq!(rule.expand_in(), "'a b.c' it\\'s.c");
+		assert_eq!(rule.expand_out(), "'a b.o'");
+		let command = &rule.command.as_ref().unwrap().command;
+		let expected = format!("cc {} -o {}", rule.expand_in(), rule.expand_out());
+		assert_eq!(command, expected.as_str());
+	}
+
+	#[test]
+	fn affected_rules() {
+		let spec = read_str(
+			"rule r\n  command = r\n\
+			 build a.o: r a.c\n\
+			 build b.o: r b.c || gen.h\n\
+			 build gen.h: r gen.in\n\
+			 build bin: r a.o b.o\n\
+			 build all: phony bin\n",
+		);
+		let index = spec.make_index();
+		let affected = |changed: &[&str], extra: &[(&'static str, &'static str)]| {
+			spec.affected_rules(
+				&index,
+				extra
+					.iter()
+					.map(|&(o, d)| (RawStr::from_str(o), RawStr::from_str(d))),
+				changed.iter().map(|&p| RawStr::from_str(p)),
+			)
+		};
+		assert_eq!(affected(&["a.c"], &[]), [0, 3, 4]);
+		assert_eq!(affected(&["gen.in"], &[]), [2]);
+		assert_eq!(affected(&["gen.in"], &[("b.o", "gen.h")]), [1, 2, 3, 4]);
+		assert_eq!(affected(&["b.h"], &[("b.o", "b.h")]), [1, 3, 4]);
+		assert!(affected(&["unknown.c"], &[]).is_empty());
+	}
+
+	#[test]
+	fn counts() {
+		let spec = read_str(
+			"rule r\n  command = r\n\
+			 build a b: r x y | z\n\
+			 build c: r x\n\
+			 build all: phony a c\n",
+		);
+		assert_eq!(spec.count_rules(), (2, 1));
+		assert_eq!(spec.count_outputs(), 4);
+		assert_eq!(spec.count_inputs(), 5);
+	}
+
+	#[test]
+	fn output_is_input() {
+		let spec = read_str(
+			"rule r\n  command = r\n\
+			 build a: r a b\n\
+			 build c: r d || c\n\
+			 build e: r f\n",
+		);
+		assert_eq!(
+			spec.validate(),
+			[
+				ValidationError::OutputIsInput {
+					rule: 0,
+					path: "a".into(),
+				},
+				ValidationError::OutputIsInput {
+					rule: 1,
+					path: "c".into(),
+				},
+			]
+		);
 	}
 }
@@ -40,6 +40,7 @@ pub enum Statement<'a> {
 		explicit_deps: Vec<&'a RawStr>,
 		implicit_deps: Vec<&'a RawStr>,
 		order_deps: Vec<&'a RawStr>,
+		validations: Vec<&'a RawStr>,
 	},
 
 	/// A pool definition.
@@ -68,6 +69,11 @@ impl<'a, 'b> Parser<'a, 'b> {
 		}
 	}
 
+	/// The name of the file being parsed.
+	pub fn file_name(&self) -> &'b Path {
+		self.file_name
+	}
+
 	/// The location of the last read line, statement, or variable.
 	///
 	/// Used for error reporting.
@@ -155,6 +161,15 @@ impl<'a, 'b> Parser<'a, 'b> {
 		Ok(None)
 	}
 
+	/// Skip the indented lines (if any) following a statement.
+	///
+	/// Used to continue with the next statement after an error.
+	pub fn skip_statement(&mut self) {
+		while self.next_indent() > 0 {
+			self.next_line();
+		}
+	}
+
 	/// Read the next statement in the file.
 	///
 	/// Does *not* read the variables underneath a `build` or `rule` statement.
@@ -165,7 +180,11 @@ impl<'a, 'b> Parser<'a, 'b> {
 	) -> Result<Option<Statement<'a>>, ErrorWithLocation<ParseError>> {
 		let mut line = loop {
 			if self.next_indent() != 0 {
-				return Err(ParseError::UnexpectedIndent.at(self.location()));
+				let location = self.location();
+				// Skip the indented line, to be able to continue after the
+				// error.
+				self.next_line();
+				return Err(ParseError::UnexpectedIndent.at(location));
 			}
 
 			let line = match self.next_line() {
@@ -205,13 +224,21 @@ impl<'a, 'b> Parser<'a, 'b> {
 
 				eat_whitespace(&mut line);
 				let (explicit_deps, x) = eat_paths(&mut line, b"|").err_at(loc)?;
-				let (implicit_deps, x) = if x == Some(b'|') && !line.starts_with("|") {
+				let (implicit_deps, x) =
+					if x == Some(b'|') && !line.starts_with("|") && !line.starts_with("@") {
+						eat_whitespace(&mut line);
+						eat_paths(&mut line, b"|").err_at(loc)?
+					} else {
+						(Vec::new(), x)
+					};
+				let (order_deps, x) = if x == Some(b'|') && line.starts_with("|") {
+					line = &line[1..];
 					eat_whitespace(&mut line);
 					eat_paths(&mut line, b"|").err_at(loc)?
 				} else {
 					(Vec::new(), x)
 				};
-				let order_deps = if x == Some(b'|') && line.starts_with("|") {
+				let validations = if x == Some(b'|') && line.starts_with("@") {
 					line = &line[1..];
 					eat_whitespace(&mut line);
 					eat_paths(&mut line, b"").err_at(loc)?.0
@@ -230,6 +257,7 @@ impl<'a, 'b> Parser<'a, 'b> {
 					explicit_deps,
 					implicit_deps,
 					order_deps,
+					validations,
 				}
 			}
 			"rule" => {
@@ -280,3 +308,53 @@ impl<'a, 'b> Parser<'a, 'b> {
 		}))
 	}
 }
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn parse_build(source: &str) -> Result<Statement<'_>, ErrorWithLocation<ParseError>> {
+		let mut parser = Parser::new(Path::new("build.ninja"), RawStr::from_str(source));
+		parser.next_statement().map(Option::unwrap)
+	}
+
+	#[test]
+	fn validations() {
+		match parse_build("build out: cc in | implicit || order |@ check1 check2").unwrap() {
+			Statement::Build {
+				explicit_deps,
+				implicit_deps,
+				order_deps,
+				validations,
+				..
+			} => {
+				assert_eq!(explicit_deps, ["in"]);
+				assert_eq!(implicit_deps, ["implicit"]);
+				assert_eq!(order_deps, ["order"]);
+				assert_eq!(validations, ["check1", "check2"]);
+			}
+			s => panic!("Expected build statement, got {:?}", s),
+		}
+		match parse_build("build out: cc in |@ check").unwrap() {
+			Statement::Build {
+				explicit_deps,
+				implicit_deps,
+				order_deps,
+				validations,
+				..
+			} => {
+				assert_eq!(explicit_deps, ["in"]);
+				assert!(implicit_deps.is_empty());
+				assert!(order_deps.is_empty());
+				assert_eq!(validations, ["check"]);
+			}
+			s => panic!("Expected build statement, got {:?}", s),
+		}
+		assert_eq!(
+			parse_build("build out: cc in |@ check | implicit")
+				.unwrap_err()
+				.error,
+			ParseError::ExpectedPath
+		);
+	}
+}
@@ -1,36 +1,144 @@
+use super::canonicalizepath::canonicalize_path_in_place;
+use super::eat::check_safe_path;
 use super::error::{ExpansionError, ReadError};
 use super::expand::{expand_path, expand_str, expand_var};
 use super::parse::{Parser, Statement, Variable};
 use super::scope::{BuildRuleScope, BuildScope, ExpandedVar, FileScope, Rule, VarScope};
-use super::{BuildCommand, BuildRule, DepStyle, Spec};
+use super::{BuildCommand, BuildRule, Definition, DepStyle, Pool, Spec, SUPPORTED_NINJA_VERSION};
 use crate::error::{AddLocationToError, AddLocationToResult, ErrorWithLocation, Location};
+use log::debug;
 use pile::Pile;
+use raw_string::unix::RawStringExt;
 use raw_string::{RawStr, RawString};
 use std::borrow::ToOwned;
+use std::collections::hash_map::{Entry, HashMap};
 use std::fs::File;
 use std::io::{BufReader, Read};
 use std::mem::replace;
-use std::path::Path;
+use std::num::NonZeroU32;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
 use std::str::from_utf8;
 
 fn read_bytes(file_name: &Path) -> Result<Vec<u8>, ReadError> {
 	let mut bytes = Vec::new();
 	File::open(file_name)
 		.and_then(|f| BufReader::with_capacity(0x10000, f).read_to_end(&mut bytes))
+		.and_then(|_| decompress(bytes))
 		.map_err(|error| ReadError::IoError {
 			file_name: file_name.to_owned(),
 			error,
-		})?;
+		})
+}
+
+/// Decompress the file if it is compressed with gzip.
+///
+/// Only with the `flate2` feature. Without it, the file is used as is.
+#[cfg(feature = "flate2")]
+fn decompress(bytes: Vec<u8>) -> std::io::Result<Vec<u8>> {
+	if !bytes.starts_with(b"\x1f\x8b") {
+		return Ok(bytes);
+	}
+	let mut decompressed = Vec::new();
+	flate2::read::GzDecoder::new(&bytes[..]).read_to_end(&mut decompressed)?;
+	Ok(decompressed)
+}
+
+#[cfg(not(feature = "flate2"))]
+fn decompress(bytes: Vec<u8>) -> std::io::Result<Vec<u8>> {
 	Ok(bytes)
 }
 
+/// Options for [`read_with()`] and [`read_from_with()`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReadOptions {
+	/// Reject paths of `build` and `default` statements that contain shell
+	/// metacharacters or control characters (such as newlines), with a
+	/// [`ParseError::UnsafePath`][super::error::ParseError::UnsafePath].
+	///
+	/// The paths are checked after variable expansion, so this also applies
+	/// to paths produced by variables.
+	///
+	/// This is only meant as an extra layer of defense for files generated
+	/// from untrusted input. It does not make it safe to run untrusted
+	/// `build.ninja` files, which can run any command anyway.
+	pub reject_unsafe_paths: bool,
+}
+
 /// Read, parse, and resolve rules and variables in a `ninja.build` file.
 ///
 /// Parses the file, including any included and subninja'd files, and resolves
 /// all rules and variables, resulting in a `Spec`.
+///
+/// With the `flate2` feature, gzip-compressed files (such as `.ninja.gz`
+/// files) are decompressed before parsing.
 pub fn read(file_name: &Path) -> Result<Spec, ErrorWithLocation<ReadError>> {
+	read_with(file_name, ReadOptions::default())
+}
+
+/// [`read()`], with non-default [`ReadOptions`].
+pub fn read_with(
+	file_name: &Path,
+	options: ReadOptions,
+) -> Result<Spec, ErrorWithLocation<ReadError>> {
 	let source = read_bytes(file_name).err_at(Location::UNKNOWN)?;
-	read_from(file_name, &source)
+	read_from_with(file_name, &source, options)
+}
+
+/// Read multiple independent `build.ninja` files, and merge them into one
+/// [`Spec`].
+///
+/// Each file is [`read()`] on its own, without sharing any rules, variables
+/// or pools. Their build rules, default targets and definitions are then
+/// concatenated in the order of the files. The `builddir` is taken from the
+/// first file that sets one.
+///
+/// Unlike within a single file, it is an error for build rules in different
+/// files to have the same output. A pool may be defined in multiple files, as
+/// long as it has the same depth everywhere.
+pub fn read_all(file_names: &[&Path]) -> Result<Spec, ErrorWithLocation<ReadError>> {
+	let mut spec = Spec::new();
+	let mut output_files = HashMap::<RawString, usize>::new();
+	for (i, &file_name) in file_names.iter().enumerate() {
+		let part = read(file_name)?;
+		let loc = Location {
+			file: Some(file_name),
+			line: None,
+		};
+		for output in part.build_rules.iter().flat_map(|rule| &rule.outputs) {
+			match output_files.entry(output.clone()) {
+				Entry::Occupied(e) if *e.get() != i => {
+					return Err(ReadError::ConflictingOutput {
+						path: e.key().clone(),
+						files: (file_names[*e.get()].to_owned(), file_name.to_owned()),
+					}
+					.at(loc));
+				}
+				Entry::Occupied(_) => {}
+				Entry::Vacant(e) => {
+					e.insert(i);
+				}
+			}
+		}
+		for pool in part.pools {
+			match spec
+				.pools
+				.iter_mut()
+				.find(|p| p.definition.name == pool.definition.name)
+			{
+				Some(p) if p.depth == pool.depth => p.definition.used |= pool.definition.used,
+				Some(_) => return Err(ReadError::DuplicatePool(pool.definition.name).at(loc)),
+				None => spec.pools.push(pool),
+			}
+		}
+		spec.build_rules.extend(part.build_rules);
+		spec.default_targets.extend(part.default_targets);
+		spec.rules.extend(part.rules);
+		if spec.build_dir.is_none() {
+			spec.build_dir = part.build_dir;
+		}
+	}
+	Ok(spec)
 }
 
 /// [`read()`], but with the source given directly instead of read from a file.
@@ -40,17 +148,117 @@ pub fn read(file_name: &Path) -> Result<Spec, ErrorWithLocation<ReadError>> {
 /// `file_name` is used in errors, and to know where to look for `include` and
 /// `subninja` files.
 pub fn read_from(file_name: &Path, source: &[u8]) -> Result<Spec, ErrorWithLocation<ReadError>> {
+	read_from_with(file_name, source, ReadOptions::default())
+}
+
+/// [`read_from()`], with non-default [`ReadOptions`].
+pub fn read_from_with(
+	file_name: &Path,
+	source: &[u8],
+	options: ReadOptions,
+) -> Result<Spec, ErrorWithLocation<ReadError>> {
+	read_from_state(file_name, source, &mut ReadState::new(options, false))
+}
+
+/// Like [`read_with()`], but instead of stopping at the first error, skip
+/// statements with errors and continue, and return all errors at once.
+///
+/// Only problems with reading files (including `include`d and `subninja`d
+/// files) stop reading immediately. That error is the last one in the list.
+pub fn read_collecting_errors(
+	file_name: &Path,
+	options: ReadOptions,
+) -> Result<Spec, Vec<ErrorWithLocation<ReadError>>> {
+	let source = read_bytes(file_name)
+		.err_at(Location::UNKNOWN)
+		.map_err(|e| vec![e])?;
+	let mut state = ReadState::new(options, true);
+	let result = read_from_state(file_name, &source, &mut state);
+	let mut errors = state.errors.unwrap_or_default();
+	match result {
+		Ok(spec) if errors.is_empty() => Ok(spec),
+		Ok(_) => Err(errors),
+		Err(e) => {
+			errors.push(e);
+			Err(errors)
+		}
+	}
+}
+
+/// The state shared between a file and all files it includes.
+struct ReadState {
+	options: ReadOptions,
+	/// The pools defined so far, including the built-in `console` pool.
+	pools: Vec<(String, u16)>,
+	/// The errors so far, if we continue after errors.
+	errors: Option<Vec<ErrorWithLocation<ReadError>>>,
+}
+
+/// An `include` or `subninja` statement through which a file was reached.
+///
+/// They form a linked list on the call stack, which is only copied into an
+/// error when something goes wrong.
+struct IncludedFrom<'a> {
+	file: &'a Path,
+	line: Option<NonZeroU32>,
+	/// The statement through which `file` was reached, if any.
+	parent: Option<&'a IncludedFrom<'a>>,
+}
+
+impl ReadState {
+	fn new(options: ReadOptions, collect_errors: bool) -> Self {
+		ReadState {
+			options,
+			pools: vec![("console".to_string(), 1)],
+			errors: if collect_errors {
+				Some(Vec::new())
+			} else {
+				None
+			},
+		}
+	}
+
+	/// Record the error and return `Ok` if we can continue after it.
+	/// Otherwise, return the error.
+	///
+	/// Adds the include chain to the error, unless it already has one from a
+	/// more deeply included file.
+	fn recover(
+		&mut self,
+		mut error: ErrorWithLocation<ReadError>,
+		included_from: Option<&IncludedFrom>,
+	) -> Result<(), ErrorWithLocation<ReadError>> {
+		if error.included_from.is_empty() {
+			error.included_from = std::iter::successors(included_from, |i| i.parent)
+				.map(|i| (i.file.to_path_buf(), i.line))
+				.collect();
+		}
+		match &mut self.errors {
+			Some(errors) if !matches!(error.error, ReadError::IoError { .. }) => {
+				errors.push(error);
+				Ok(())
+			}
+			_ => Err(error),
+		}
+	}
+}
+
+fn read_from_state(
+	file_name: &Path,
+	source: &[u8],
+	state: &mut ReadState,
+) -> Result<Spec, ErrorWithLocation<ReadError>> {
 	let pile = Pile::new();
 	let mut spec = Spec::new();
 	let mut scope = FileScope::new();
-	let mut pools = vec![("console".to_string(), 1)];
 	read_into(
 		file_name,
 		RawStr::from_bytes(source),
 		&pile,
 		&mut spec,
 		&mut scope,
-		&mut pools,
+		state,
+		None,
 	)?;
 	if let Some(var) = scope
 		.vars
@@ -59,204 +267,363 @@ pub fn read_from(file_name: &Path, source: &[u8]) -> Result<Spec, ErrorWithLocat
 	{
 		spec.build_dir = Some(replace(&mut var.value, RawString::new()));
 	}
+	debug!(
+		target: "ninj::spec",
+		"Read {:?}: {} build rules, {} rules, {} pools.",
+		file_name,
+		spec.build_rules.len(),
+		spec.rules.len(),
+		spec.pools.len()
+	);
 	Ok(spec)
 }
 
+/// Parse a version like `1.10` or `1.10.2` into its major and minor parts.
+///
+/// Like ninja, this is lenient: Anything after the minor version is ignored,
+/// and missing or invalid parts are zero.
+fn parse_version(version: &RawStr) -> (u32, u32) {
+	let mut parts = version.as_bytes().split(|&b| b == b'.').map(|part| {
+		let digits = part.iter().take_while(|b| b.is_ascii_digit()).count();
+		from_utf8(&part[..digits])
+			.ok()
+			.and_then(|s| s.parse().ok())
+			.unwrap_or(0)
+	});
+	(parts.next().unwrap_or(0), parts.next().unwrap_or(0))
+}
+
+/// Check that the `ninja_required_version` is not newer than what we support.
+fn check_version(required: &RawStr) -> Result<(), ReadError> {
+	let supported = SUPPORTED_NINJA_VERSION;
+	if parse_version(required) > parse_version(RawStr::from_str(supported)) {
+		return Err(ReadError::VersionTooOld {
+			required: String::from_utf8_lossy(required.as_bytes()).into_owned(),
+			supported,
+		});
+	}
+	Ok(())
+}
+
 fn read_into<'a: 'p, 'p>(
 	file_name: &Path,
 	source: &'a RawStr,
 	pile: &'a Pile<Vec<u8>>,
 	spec: &mut Spec,
 	scope: &mut FileScope<'a, 'p>,
-	pools: &mut Vec<(String, u16)>,
+	state: &mut ReadState,
+	included_from: Option<&IncludedFrom>,
 ) -> Result<(), ErrorWithLocation<ReadError>> {
 	let mut parser = Parser::new(file_name, source);
 
-	while let Some(statement) = parser.next_statement()? {
-		let loc = parser.location();
-		match statement {
-			Statement::Variable { name, value } => {
-				let value = expand_str(value, scope).err_at(loc)?;
-				scope.vars.push(ExpandedVar { name, value })
+	loop {
+		let result = match parser.next_statement() {
+			Ok(Some(statement)) => read_statement(
+				&mut parser,
+				statement,
+				pile,
+				spec,
+				scope,
+				state,
+				included_from,
+			),
+			Ok(None) => break,
+			Err(e) => Err(e.convert()),
+		};
+		if let Err(e) = result {
+			state.recover(e, included_from)?;
+			parser.skip_statement();
+		}
+	}
+
+	Ok(())
+}
+
+/// Process a single statement, including the variables under it.
+fn read_statement<'a: 'p, 'p>(
+	parser: &mut Parser<'a, '_>,
+	statement: Statement<'a>,
+	pile: &'a Pile<Vec<u8>>,
+	spec: &mut Spec,
+	scope: &mut FileScope<'a, 'p>,
+	state: &mut ReadState,
+	included_from: Option<&IncludedFrom>,
+) -> Result<(), ErrorWithLocation<ReadError>> {
+	let file_name = parser.file_name();
+	let options = state.options;
+	let check_paths = |paths: &[RawString]| -> Result<(), ReadError> {
+		if options.reject_unsafe_paths {
+			for path in paths {
+				check_safe_path(path)?;
 			}
-			Statement::Rule { name } => {
-				if scope.rules.iter().any(|rule| rule.name == name) {
-					return Err(ReadError::DuplicateRule(name.to_string()).at(loc));
-				}
-				let mut vars = Vec::new();
-				while let Some(var) = parser.next_variable()? {
-					if !match var.name {
-						"command" | "description" | "depfile" | "deps" | "msvc_deps_prefix" => true,
-						"rspfile" | "rspfile_content" | "generator" | "restat" | "pool" => true,
-						_ => false,
-					} {
-						return Err(
-							ReadError::UnknownVariable(var.name.to_string()).at(parser.location())
-						);
-					}
-					vars.push(var);
-				}
-				scope.rules.push(Rule { name, vars })
+		}
+		Ok(())
+	};
+
+	let loc = parser.location();
+	match statement {
+		Statement::Variable { name, value } => {
+			let value = expand_str(value, scope).err_at(loc)?;
+			if name == "ninja_required_version" {
+				check_version(&value).err_at(loc)?;
 			}
-			Statement::Pool { name } => {
-				if pools.iter().any(|(n, _)| n == name) {
-					return Err(ReadError::DuplicatePool(name.to_string()).at(loc));
-				}
-				let mut depth = None;
-				while let Some(Variable { name, value }) = parser.next_variable()? {
-					let loc = parser.location();
-					if name != "depth" {
-						return Err(ReadError::UnknownVariable(name.to_string()).at(loc));
-					}
-					// Expand the value.
-					let value = expand_str(value, scope).err_at(loc)?;
-					// Parse the value as an u32.
-					depth = Some(
-						from_utf8(value.as_bytes())
-							.ok()
-							.and_then(|s| s.parse().ok())
-							.ok_or_else(|| ReadError::InvalidPoolDepth.at(loc))?,
-					);
-				}
-				if let Some(depth) = depth {
-					pools.push((name.to_owned(), depth));
-				} else {
-					return Err(ReadError::ExpectedPoolDepth.at(parser.location()));
+			scope.vars.push(ExpandedVar { name, value })
+		}
+		Statement::Rule { name } => {
+			if scope.rules.iter().any(|rule| rule.name == name) {
+				return Err(ReadError::DuplicateRule(name.to_string()).at(loc));
+			}
+			let mut vars = Vec::new();
+			while let Some(var) = parser.next_variable()? {
+				if !matches!(
+					var.name,
+					"command"
+						| "description" | "depfile"
+						| "deps" | "msvc_deps_prefix"
+						| "rspfile" | "rspfile_content"
+						| "generator" | "restat"
+						| "always" | "pool"
+				) {
+					// When collecting errors, skip only this variable, such
+					// that the rule is still defined for the statements using
+					// it.
+					let error = ReadError::UnknownVariable(var.name.to_string());
+					state.recover(error.at(parser.location()), included_from)?;
+					continue;
 				}
+				vars.push(var);
 			}
-			Statement::Build {
-				rule_name,
-				explicit_outputs,
-				implicit_outputs,
-				explicit_deps,
-				implicit_deps,
-				order_deps,
-			} => {
-				let mut vars = Vec::new();
-				while let Some(Variable { name, value }) = parser.next_variable()? {
-					vars.push(ExpandedVar {
-						name,
-						value: expand_str(value, scope).err_at(parser.location())?,
-					});
+			scope.rules.push(Rule {
+				name,
+				vars,
+				definition: spec.rules.len(),
+			});
+			spec.rules.push(Definition::new(name, loc));
+		}
+		Statement::Pool { name } => {
+			if state.pools.iter().any(|(n, _)| n == name) {
+				return Err(ReadError::DuplicatePool(name.to_string()).at(loc));
+			}
+			let mut depth = None;
+			while let Some(Variable { name, value }) = parser.next_variable()? {
+				let loc = parser.location();
+				if name != "depth" {
+					return Err(ReadError::UnknownVariable(name.to_string()).at(loc));
 				}
+				// Expand the value.
+				let value = expand_str(value, scope).err_at(loc)?;
+				// Parse the value as an u32.
+				depth = Some(
+					from_utf8(value.as_bytes())
+						.ok()
+						.and_then(|s| s.parse().ok())
+						.ok_or_else(|| ReadError::InvalidPoolDepth.at(loc))?,
+				);
+			}
+			if let Some(depth) = depth {
+				state.pools.push((name.to_owned(), depth));
+				spec.pools.push(Pool {
+					definition: Definition::new(name, loc),
+					depth,
+				});
+			} else {
+				return Err(ReadError::ExpectedPoolDepth.at(parser.location()));
+			}
+		}
+		Statement::Build {
+			rule_name,
+			explicit_outputs,
+			implicit_outputs,
+			explicit_deps,
+			implicit_deps,
+			order_deps,
+			validations,
+		} => {
+			let mut vars = Vec::new();
+			while let Some(Variable { name, value }) = parser.next_variable()? {
+				vars.push(ExpandedVar {
+					name,
+					value: expand_str(value, scope).err_at(parser.location())?,
+				});
+			}
+
+			// Bring the build variables into scope.
+			let build_scope = BuildScope {
+				file_scope: scope,
+				build_vars: &vars,
+			};
+
+			// And expand the input and output paths with it.
+			let mut outputs = Vec::with_capacity(explicit_outputs.len() + implicit_outputs.len());
+			let mut inputs = Vec::with_capacity(explicit_deps.len() + implicit_deps.len());
+			expand_paths_into(&explicit_outputs, &build_scope, &mut outputs).err_at(loc)?;
+			expand_paths_into(&explicit_deps, &build_scope, &mut inputs).err_at(loc)?;
 
-				// Bring the build variables into scope.
-				let build_scope = BuildScope {
-					file_scope: &scope,
-					build_vars: &vars,
+			let command = if rule_name == "phony" {
+				None
+			} else {
+				// Look up the rule in the current scope.
+				let rule = scope
+					.lookup_rule(rule_name)
+					.ok_or_else(|| ReadError::UndefinedRule(rule_name.to_string()).at(loc))?;
+				spec.rules[rule.definition].used = true;
+
+				// Bring $in, $out, and the rule variables into scope.
+				// (The implicit inputs and outputs are not added until later,
+				// so they don't appear in $in and $out.)
+				let build_rule_scope = BuildRuleScope {
+					build_scope: &build_scope,
+					rule_vars: &rule.vars,
+					inputs: &inputs,
+					outputs: &outputs,
 				};
 
-				// And expand the input and output paths with it.
-				let mut outputs =
-					Vec::with_capacity(explicit_outputs.len() + implicit_outputs.len());
-				let mut inputs = Vec::with_capacity(explicit_deps.len() + implicit_deps.len());
-				expand_paths_into(&explicit_outputs, &build_scope, &mut outputs).err_at(loc)?;
-				expand_paths_into(&explicit_deps, &build_scope, &mut inputs).err_at(loc)?;
+				let expand_var = |name| expand_var(name, &build_rule_scope).err_at(loc);
+
+				// And expand the special variables with it:
 
-				let command = if rule_name == "phony" {
-					None
+				// First the pool, and also look it up:
+				let pool = expand_var("pool")?;
+				let (pool, pool_depth) = if pool.is_empty() {
+					(String::new(), None)
 				} else {
-					// Look up the rule in the current scope.
-					let rule = scope
-						.lookup_rule(rule_name)
-						.ok_or_else(|| ReadError::UndefinedRule(rule_name.to_string()).at(loc))?;
-
-					// Bring $in, $out, and the rule variables into scope.
-					let build_rule_scope = BuildRuleScope {
-						build_scope: &build_scope,
-						rule_vars: &rule.vars,
-						inputs: &inputs,
-						outputs: &outputs,
-					};
-
-					let expand_var = |name| expand_var(name, &build_rule_scope).err_at(loc);
-
-					// And expand the special variables with it:
-
-					// First the pool, and also look it up:
-					let pool = expand_var("pool")?;
-					let (pool, pool_depth) = if pool.is_empty() {
-						(String::new(), None)
-					} else {
-						let (n, d) = pools
-							.iter()
-							.find(|(name, _)| name.as_bytes() == pool.as_bytes())
-							.ok_or_else(|| ReadError::UndefinedPool(pool).at(loc))?;
-						(n.clone(), Some(*d))
-					};
-
-					// And then the rest:
-					Some(BuildCommand {
-						rule_name: rule_name.to_string(),
-						command: expand_var("command")?,
-						description: expand_var("description")?,
-						depfile: expand_var("depfile")?,
-						deps: match expand_var("deps")?.as_bytes() {
-							b"gcc" => Some(DepStyle::Gcc),
-							b"msvc" => Some(DepStyle::Msvc),
-							_ => None,
-						},
-						msvc_deps_prefix: expand_var("msvc_deps_prefix")?,
-						generator: build_rule_scope.lookup_var("generator").is_some(),
-						restat: build_rule_scope.lookup_var("restat").is_some(),
-						rspfile: expand_var("rspfile")?,
-						rspfile_content: expand_var("rspfile")?,
-						pool,
-						pool_depth,
-					})
+					let (n, d) = state
+						.pools
+						.iter()
+						.find(|(name, _)| name.as_bytes() == pool.as_bytes())
+						.ok_or_else(|| ReadError::UndefinedPool(pool).at(loc))?;
+					if let Some(def) = spec.pools.iter_mut().find(|p| p.definition.name == *n) {
+						def.definition.used = true;
+					}
+					(n.clone(), Some(*d))
 				};
 
-				let mut order = Vec::new();
+				// And then the rest:
+				Some(BuildCommand {
+					rule_name: rule_name.to_string(),
+					command: expand_var("command")?,
+					description: expand_var("description")?,
+					depfile: expand_var("depfile")?,
+					deps: match expand_var("deps")?.as_bytes() {
+						b"gcc" => Some(DepStyle::Gcc),
+						b"msvc" => Some(DepStyle::Msvc),
+						_ => None,
+					},
+					msvc_deps_prefix: expand_var("msvc_deps_prefix")?,
+					generator: build_rule_scope.lookup_var("generator").is_some(),
+					restat: build_rule_scope.lookup_var("restat").is_some(),
+					always: build_rule_scope.lookup_var("always").is_some(),
+					rspfile: expand_var("rspfile")?,
+					rspfile_content: expand_var("rspfile")?,
+					pool,
+					pool_depth,
+				})
+			};
 
-				expand_paths_into(&implicit_outputs, &build_scope, &mut outputs).err_at(loc)?;
-				expand_paths_into(&implicit_deps, &build_scope, &mut inputs).err_at(loc)?;
-				expand_paths_into(&order_deps, &build_scope, &mut order).err_at(loc)?;
+			let mut order = Vec::new();
+			let mut validation_paths = Vec::new();
+			let n_explicit_outputs = outputs.len();
+			let n_explicit_inputs = inputs.len();
 
-				spec.build_rules.push(BuildRule {
-					outputs,
-					inputs,
-					order_deps: order,
-					command,
-				});
-			}
-			Statement::Default { paths } => {
-				spec.default_targets.reserve(paths.len());
-				for p in paths {
-					spec.default_targets.push(expand_str(p, scope).err_at(loc)?);
-				}
-			}
-			Statement::Include { path } => {
-				let path = expand_str(path, scope).err_at(loc)?;
-				let path = path.to_path().err_at(loc)?;
-				let source = pile.add(read_bytes(&path).err_at(loc)?);
-				read_into(
-					&file_name.with_file_name(path),
-					RawStr::from_bytes(source),
-					pile,
-					spec,
-					scope,
-					pools,
-				)?;
+			expand_paths_into(&implicit_outputs, &build_scope, &mut outputs).err_at(loc)?;
+			expand_paths_into(&implicit_deps, &build_scope, &mut inputs).err_at(loc)?;
+			expand_paths_into(&order_deps, &build_scope, &mut order).err_at(loc)?;
+			expand_paths_into(&validations, &build_scope, &mut validation_paths).err_at(loc)?;
+
+			for paths in &[&outputs, &inputs, &order, &validation_paths] {
+				check_paths(paths).err_at(loc)?;
 			}
-			Statement::SubNinja { path } => {
-				let path = expand_str(path, scope).err_at(loc)?;
-				let path = path.to_path().err_at(loc)?;
-				let source = read_bytes(&path).err_at(loc)?;
-				read_into(
-					&file_name.with_file_name(path),
-					RawStr::from_bytes(&source),
-					&Pile::new(),
-					spec,
-					&mut scope.new_subscope(),
-					pools,
-				)?;
+
+			spec.build_rules.push(BuildRule {
+				outputs,
+				n_explicit_outputs,
+				inputs,
+				n_explicit_inputs,
+				order_deps: order,
+				validations: validation_paths,
+				command,
+			});
+		}
+		Statement::Default { paths } => {
+			spec.default_targets.reserve(paths.len());
+			for p in paths {
+				let path = expand_str(p, scope).err_at(loc)?;
+				check_paths(std::slice::from_ref(&path)).err_at(loc)?;
+				spec.default_targets.push(path);
 			}
 		}
+		Statement::Include { path } => {
+			let path = expand_str(path, scope).err_at(loc)?;
+			let path = resolve_include(file_name, path.to_path().err_at(loc)?);
+			debug!(target: "ninj::spec", "Including {:?}.", path);
+			let source = pile.add(read_bytes(&path).err_at(loc)?);
+			let from = IncludedFrom {
+				file: file_name,
+				line: loc.line,
+				parent: included_from,
+			};
+			read_into(
+				&path,
+				RawStr::from_bytes(source),
+				pile,
+				spec,
+				scope,
+				state,
+				Some(&from),
+			)?;
+		}
+		Statement::SubNinja { path } => {
+			let path = expand_str(path, scope).err_at(loc)?;
+			let path = resolve_include(file_name, path.to_path().err_at(loc)?);
+			debug!(target: "ninj::spec", "Reading subninja {:?}.", path);
+			let source = read_bytes(&path).err_at(loc)?;
+			let from = IncludedFrom {
+				file: file_name,
+				line: loc.line,
+				parent: included_from,
+			};
+			read_into(
+				&path,
+				RawStr::from_bytes(&source),
+				&Pile::new(),
+				spec,
+				&mut scope.new_subscope(),
+				state,
+				Some(&from),
+			)?;
+		}
 	}
 
 	Ok(())
 }
 
+/// Find the file referred to by an `include` or `subninja` statement.
+///
+/// Absolute paths are used as-is. Relative paths are relative to the directory
+/// of the file containing the statement. The result is canonicalized, such
+/// that e.g. `../common.ninja` included from `sub/build.ninja` gives
+/// `common.ninja`.
+fn resolve_include(file_name: &Path, path: &Path) -> PathBuf {
+	let path = match file_name.parent() {
+		Some(dir) => dir.join(path),
+		None => path.to_owned(),
+	};
+	let mut path = RawString::from_bytes(path.into_os_string().into_vec());
+	canonicalize_path_in_place(&mut path);
+	path.into_pathbuf()
+}
+
+impl Definition {
+	fn new(name: &str, loc: Location) -> Self {
+		Definition {
+			name: name.to_owned(),
+			file: loc.file.map(ToOwned::to_owned),
+			line: loc.line,
+			used: false,
+		}
+	}
+}
+
 fn expand_paths_into<S: VarScope>(
 	sources: &[&RawStr],
 	scope: &S,
@@ -268,3 +635,262 @@ fn expand_paths_into<S: VarScope>(
 	}
 	Ok(())
 }
+
+#[cfg(test)]
+mod test {
+	use super::super::error::ParseError;
+	use super::*;
+	use crate::test_dir::TestDir;
+	use std::fs::{create_dir_all, write};
+
+	#[test]
+	fn required_version() {
+		let read_str = |s: &str| read_from(Path::new("build.ninja"), s.as_bytes());
+		assert!(read_str("ninja_required_version = 1.3\n").is_ok());
+		assert!(read_str("ninja_required_version = 1.11.1\n").is_ok());
+		assert!(read_str("v = 1.5\nninja_required_version = $v.git\n").is_ok());
+		let e = read_str("build a: phony\nninja_required_version = 1.99\n").unwrap_err();
+		assert_eq!(e.line.map(|l| l.get()), Some(2));
+		match e.error {
+			ReadError::VersionTooOld {
+				required,
+				supported,
+			} => {
+				assert_eq!(required, "1.99");
+				assert_eq!(supported, SUPPORTED_NINJA_VERSION);
+			}
+			e => panic!("Unexpected error: {}", e),
+		}
+		assert!(read_str("ninja_required_version = 2\n").is_err());
+	}
+
+	#[test]
+	fn unsafe_paths() {
+		let strict = ReadOptions {
+			reject_unsafe_paths: true,
+		};
+		let read_strict = |s: &str| read_from_with(Path::new("build.ninja"), s.as_bytes(), strict);
+		let is_unsafe = |s: &str| match read_strict(s) {
+			Err(e) => matches!(e.error, ReadError::ParseError(ParseError::UnsafePath)),
+			Ok(_) => false,
+		};
+		assert!(read_strict("build a/b-c.o: phony x_y+z.c\ndefault a/b-c.o\n").is_ok());
+		assert!(is_unsafe("build a;b: phony\n"));
+		assert!(is_unsafe("build a: phony b&c\n"));
+		assert!(is_unsafe("build a: phony || `b`\n"));
+		assert!(is_unsafe("x = $$(rm -rf /)\nbuild a: phony $x\n"));
+		assert!(is_unsafe("x = \tb\nbuild a: phony\ndefault a$x\n"));
+		assert!(is_unsafe("build a: phony |@ 'b'\n"));
+		// Without the option, nothing changes.
+		let read_str = |s: &str| read_from(Path::new("build.ninja"), s.as_bytes());
+		assert!(read_str("x = $$(rm -rf /)\nbuild a: phony $x\n").is_ok());
+	}
+
+	#[test]
+	fn collect_errors() -> Result<(), Box<dyn std::error::Error>> {
+		let dir = TestDir::new("collect-errors");
+		let file = dir.join("build.ninja");
+		let source = "rule cc\n  command = cc $in\n  colour = red\n\
+			build a: cc a.c\n\
+			build b: ld b.o\n\
+			\x20 x = 1\n\
+			rule ld\n  command = ld $in -o $out\n\
+			build c: ld c.o\n\
+			\x20 pool = nope\n\
+			default c\n\
+			\x20 x = 2\n\
+			build d: phony c\n";
+		write(&file, source)?;
+		let errors = read_collecting_errors(&file, ReadOptions::default()).unwrap_err();
+		let lines: Vec<u32> = errors
+			.iter()
+			.filter_map(|e| e.line)
+			.map(|l| l.get())
+			.collect();
+		assert_eq!(lines, [3, 5, 9, 11]);
+		assert!(matches!(errors[0].error, ReadError::UnknownVariable(_)));
+		assert!(matches!(errors[1].error, ReadError::UndefinedRule(_)));
+		assert!(matches!(errors[2].error, ReadError::UndefinedPool(_)));
+		assert!(matches!(
+			errors[3].error,
+			ReadError::ParseError(ParseError::UnexpectedIndent)
+		));
+		// The default is still to stop at the first error.
+		let e = read_from(&file, source.as_bytes()).unwrap_err();
+		assert_eq!(e.line.map(|l| l.get()), Some(3));
+		// An unexpected indent is reported on the line of the statement
+		// before it, in both modes.
+		let e = read_from(&file, b"build c: phony\ndefault c\n  x = 2\n").unwrap_err();
+		assert_eq!(e.line.map(|l| l.get()), Some(2));
+
+		// Errors reading files stop immediately, and come last.
+		write(&file, "build a: ld\ninclude missing.ninja\nbuild b: ld\n")?;
+		let errors = read_collecting_errors(&file, ReadOptions::default()).unwrap_err();
+		assert_eq!(errors.len(), 2);
+		assert!(matches!(errors[1].error, ReadError::IoError { .. }));
+
+		write(&file, "build a: phony\n")?;
+		assert!(read_collecting_errors(&file, ReadOptions::default()).is_ok());
+
+		Ok(())
+	}
+
+	#[test]
+	fn include_chain() -> Result<(), Box<dyn std::error::Error>> {
+		let dir = TestDir::new("include-chain");
+		let file = |name: &str| dir.join(name);
+		write(file("build.ninja"), "build a: phony\ninclude a.ninja\n")?;
+		write(file("a.ninja"), "\nsubninja b.ninja\nbuild c: nope\n")?;
+		write(file("b.ninja"), "build b: phony\nbuild d: nope\n")?;
+		let e = read(&file("build.ninja")).unwrap_err();
+		assert_eq!(
+			e.to_string(),
+			format!(
+				"{}:2: Undefined rule name: nope (included from {}:2, from {}:2)",
+				file("b.ninja").display(),
+				file("a.ninja").display(),
+				file("build.ninja").display(),
+			)
+		);
+
+		// Errors that are collected get the chain of their own file.
+		let errors =
+			read_collecting_errors(&file("build.ninja"), ReadOptions::default()).unwrap_err();
+		assert_eq!(errors.len(), 2);
+		assert_eq!(errors[0].include_chain().count(), 2);
+		assert_eq!(errors[1].file.as_deref(), Some(file("a.ninja").as_path()));
+		assert_eq!(
+			errors[1].include_chain().collect::<Vec<_>>(),
+			[Location {
+				file: Some(&file("build.ninja")),
+				line: NonZeroU32::new(2),
+			}]
+		);
+
+		// Errors in the top-level file have no chain.
+		let e = read_from(&file("x.ninja"), b"build x: nope\n").unwrap_err();
+		assert_eq!(e.include_chain().count(), 0);
+		assert!(!e.to_string().contains("included"));
+
+		Ok(())
+	}
+
+	#[test]
+	fn include_paths() -> Result<(), Box<dyn std::error::Error>> {
+		let dir = TestDir::new("include-paths");
+		create_dir_all(dir.join("sub"))?;
+		create_dir_all(dir.join("abs"))?;
+		let abs = dir.join("abs/c.ninja");
+		write(
+			dir.join("build.ninja"),
+			format!(
+				"build a: phony\ninclude sub/a.ninja\nsubninja {}\n",
+				abs.display()
+			),
+		)?;
+		write(dir.join("sub/a.ninja"), "build b: phony\ninclude b.ninja\n")?;
+		write(dir.join("sub/b.ninja"), "build c: phony\n")?;
+		write(&abs, "build d: phony\n")?;
+		let spec = read(&dir.join("build.ninja"))?;
+		let outputs: Vec<&RawString> = spec.build_rules.iter().map(|r| &r.outputs[0]).collect();
+		assert_eq!(outputs, ["a", "b", "c", "d"]);
+
+		Ok(())
+	}
+
+	#[test]
+	fn read_all_files() -> Result<(), Box<dyn std::error::Error>> {
+		let dir = TestDir::new("read-all");
+		let a = dir.join("a.ninja");
+		let b = dir.join("b.ninja");
+		let c = dir.join("c.ninja");
+		write(
+			&a,
+			"pool p\n  depth = 2\nrule r\n  command = r\n  pool = p\n\
+			 build a1: r\nbuild a2: phony a1\ndefault a2\n",
+		)?;
+		write(
+			&b,
+			"builddir = out\npool p\n  depth = 2\nbuild b1 | a3: phony\ndefault b1\n",
+		)?;
+		write(&c, "build c1 a1: phony\n")?;
+
+		let spec = read_all(&[&a, &b])?;
+		let outputs: Vec<&RawString> = spec.build_rules.iter().map(|r| &r.outputs[0]).collect();
+		assert_eq!(outputs, ["a1", "a2", "b1"]);
+		assert_eq!(spec.default_targets, ["a2", "b1"]);
+		assert_eq!(spec.pools.len(), 1);
+		assert!(spec.pools[0].definition.used);
+		assert_eq!(spec.build_dir(), Path::new("out"));
+
+		let e = read_all(&[&a, &b, &c]).unwrap_err();
+		assert_eq!(e.file.as_deref(), Some(c.as_path()));
+		match e.error {
+			ReadError::ConflictingOutput { path, files } => {
+				assert_eq!(path, "a1");
+				assert_eq!(files, (a.clone(), c.clone()));
+			}
+			e => panic!("Unexpected error: {}", e),
+		}
+
+		write(&c, "pool p\n  depth = 3\n")?;
+		assert!(read_all(&[&a, &c]).is_err());
+
+		Ok(())
+	}
+
+	#[test]
+	#[cfg(feature = "flate2")]
+	fn include_gzip() -> Result<(), Box<dyn std::error::Error>> {
+		use flate2::write::GzEncoder;
+		use std::io::Write;
+		let dir = TestDir::new("include-gzip");
+		write(
+			dir.join("build.ninja"),
+			"include a.ninja.gz\nsubninja b.ninja.gz\n",
+		)?;
+		for (name, source) in &[("a", "build a: phony\n"), ("b", "build b: phony\n")] {
+			let mut encoder = GzEncoder::new(Vec::new(), Default::default());
+			encoder.write_all(source.as_bytes())?;
+			write(dir.join(format!("{}.ninja.gz", name)), encoder.finish()?)?;
+		}
+		let spec = read(&dir.join("build.ninja"))?;
+		let outputs: Vec<&RawString> = spec.build_rules.iter().map(|r| &r.outputs[0]).collect();
+		assert_eq!(outputs, ["a", "b"]);
+
+		Ok(())
+	}
+
+	#[test]
+	fn include_parent_dir() -> Result<(), Box<dyn std::error::Error>> {
+		let dir = TestDir::new("include-parent-dir");
+		create_dir_all(dir.join("sub/deeper"))?;
+		create_dir_all(dir.join("common"))?;
+		write(
+			dir.join("sub/build.ninja"),
+			"include ./deeper/a.ninja\ninclude ../common/rules.ninja\n",
+		)?;
+		write(
+			dir.join("sub/deeper/a.ninja"),
+			"build a: phony\ninclude ../../common/b.ninja\n",
+		)?;
+		write(dir.join("common/b.ninja"), "build b: phony\n")?;
+		write(dir.join("common/rules.ninja"), "build c: phony\n")?;
+		let spec = read(&dir.join("sub/build.ninja"))?;
+		let outputs: Vec<&RawString> = spec.build_rules.iter().map(|r| &r.outputs[0]).collect();
+		assert_eq!(outputs, ["a", "b", "c"]);
+		assert_eq!(
+			resolve_include(
+				Path::new("sub/build.ninja"),
+				Path::new("../common/rules.ninja")
+			),
+			Path::new("common/rules.ninja")
+		);
+		assert_eq!(
+			resolve_include(Path::new("build.ninja"), Path::new("./sub/a.ninja")),
+			Path::new("sub/a.ninja")
+		);
+
+		Ok(())
+	}
+}
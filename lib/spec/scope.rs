@@ -16,10 +16,13 @@ pub struct ExpandedVar<'a> {
 pub struct Rule<'a> {
 	pub name: &'a str,
 	pub vars: Vec<Var<'a>>,
+	/// The index of the corresponding [`Definition`][super::Definition] in
+	/// [`Spec::rules`][super::Spec::rules].
+	pub definition: usize,
 }
 
 /// A file-level scope, containing variables and rules.
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct FileScope<'a: 'p, 'p> {
 	/// The scope of the file that subninja'd this file, if any.
 	pub parent_scope: Option<&'p FileScope<'a, 'p>>,
@@ -89,11 +92,11 @@ pub enum FoundVar<'a> {
 /// A scope containing variable definitions.
 pub trait VarScope {
 	/// Look up a variable definition.
-	fn lookup_var(&self, var_name: &str) -> Option<FoundVar>;
+	fn lookup_var(&self, var_name: &str) -> Option<FoundVar<'_>>;
 }
 
 impl<'a> VarScope for [Var<'a>] {
-	fn lookup_var(&self, var_name: &str) -> Option<FoundVar> {
+	fn lookup_var(&self, var_name: &str) -> Option<FoundVar<'_>> {
 		self.iter()
 			.rfind(|Var { name, .. }| *name == var_name)
 			.map(|var| FoundVar::Unexpanded(var.value))
@@ -101,15 +104,15 @@ impl<'a> VarScope for [Var<'a>] {
 }
 
 impl<'a> VarScope for [ExpandedVar<'a>] {
-	fn lookup_var(&self, var_name: &str) -> Option<FoundVar> {
+	fn lookup_var(&self, var_name: &str) -> Option<FoundVar<'_>> {
 		self.iter()
 			.rfind(|ExpandedVar { name, .. }| *name == var_name)
-			.map(|var| FoundVar::Expanded(&*var.value))
+			.map(|var| FoundVar::Expanded(&var.value))
 	}
 }
 
 impl<'a, 'p> VarScope for FileScope<'a, 'p> {
-	fn lookup_var(&self, var_name: &str) -> Option<FoundVar> {
+	fn lookup_var(&self, var_name: &str) -> Option<FoundVar<'_>> {
 		self.vars.lookup_var(var_name).or_else(|| {
 			self.parent_scope
 				.and_then(|parent| parent.lookup_var(var_name))
@@ -118,7 +121,7 @@ impl<'a, 'p> VarScope for FileScope<'a, 'p> {
 }
 
 impl<'a> VarScope for BuildScope<'a> {
-	fn lookup_var(&self, var_name: &str) -> Option<FoundVar> {
+	fn lookup_var(&self, var_name: &str) -> Option<FoundVar<'_>> {
 		self.build_vars
 			.lookup_var(var_name)
 			.or_else(|| self.file_scope.lookup_var(var_name))
@@ -126,7 +129,7 @@ impl<'a> VarScope for BuildScope<'a> {
 }
 
 impl<'a> VarScope for BuildRuleScope<'a> {
-	fn lookup_var(&self, var_name: &str) -> Option<FoundVar> {
+	fn lookup_var(&self, var_name: &str) -> Option<FoundVar<'_>> {
 		match var_name {
 			"in" => Some(FoundVar::Paths {
 				paths: self.inputs,
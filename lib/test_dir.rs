@@ -0,0 +1,37 @@
+//! Temporary directories for tests.
+
+use std::fs::{create_dir_all, remove_dir_all};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// An empty directory for a test, which is removed again when dropped (also
+/// when the test fails).
+///
+/// The name includes the process ID, such that concurrent test runs don't
+/// collide.
+pub(crate) struct TestDir {
+	path: PathBuf,
+}
+
+impl TestDir {
+	/// Create the directory. `name` should be unique for each test.
+	pub(crate) fn new(name: &str) -> TestDir {
+		let path = std::env::temp_dir().join(format!("ninj-test-{}-{}", name, std::process::id()));
+		remove_dir_all(&path).ok();
+		create_dir_all(&path).unwrap();
+		TestDir { path }
+	}
+}
+
+impl Deref for TestDir {
+	type Target = Path;
+	fn deref(&self) -> &Path {
+		&self.path
+	}
+}
+
+impl Drop for TestDir {
+	fn drop(&mut self) {
+		remove_dir_all(&self.path).ok();
+	}
+}
@@ -1,30 +1,40 @@
 mod logger;
+mod plan;
 mod status;
 mod subtools;
 mod timeformat;
 mod worker;
 
+#[cfg(test)]
+#[path = "../lib/test_dir.rs"]
+mod test_dir;
+
 use self::logger::Logger;
-use self::status::{show_build_status, BuildStatus, ProgressFormat};
+use self::plan::{read_plan, write_plan};
+use self::status::{show_build_status, BuildStatus, DisplayOptions, ProgressFormat};
 use self::worker::status::WorkerStatusUpdater;
-use self::worker::Worker;
-use log::{debug, error};
+use self::worker::{SleepTime, Worker};
+use log::{debug, error, warn};
 use ninj::buildlog::BuildLog;
+use ninj::cache::Cache;
 use ninj::deplog::DepLogMut;
 use ninj::mtime::StatCache;
 use ninj::outdated::is_outdated;
-use ninj::queue::{BuildQueue, DepInfo, TaskInfo};
-use ninj::spec::read;
+use ninj::queue::{BuildQueue, DepInfo, PoolInfo, TaskInfo, TaskStatus};
+use ninj::spec::{glob, read_collecting_errors, ReadOptions, Spec};
 use raw_string::{RawStr, RawString};
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::path::{Path, PathBuf};
 use std::process::exit;
-use std::sync::Mutex;
-use std::time::Instant;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
 struct Options {
-	/// The targets to build. Empty to build the default targets.
+	/// The targets to build. Empty to build the default targets, or all root
+	/// targets if there are no defaults.
 	#[structopt(parse(from_str))]
 	targets: Vec<RawString>,
 
@@ -37,6 +47,43 @@ struct Options {
 	#[structopt(short = "n")]
 	dry_run: bool,
 
+	/// Dry run that checks the build rules of the targets and everything they
+	/// depend on for problems, such as empty commands or missing inputs,
+	/// without running anything.
+	#[structopt(long = "check")]
+	check: bool,
+
+	/// Only build the targets affected by the files listed (one per line) in
+	/// this file, such as the output of `git diff --name-only`. Use `-` to
+	/// read the list from standard input. The paths must be as they appear in
+	/// the build specification. Can't be combined with explicit targets.
+	#[structopt(long = "changed-from", parse(from_os_str))]
+	changed_from: Option<PathBuf>,
+
+	/// Don't run any commands, but write the commands that would be run, in
+	/// order, to this file. Use --run-plan to run them later.
+	#[structopt(long = "write-plan", parse(from_os_str))]
+	write_plan: Option<PathBuf>,
+
+	/// Run the commands in a plan written by --write-plan, without checking
+	/// which targets are outdated. Fails if the build specification changed
+	/// the command of any of them. Can't be combined with explicit targets or
+	/// --changed-from.
+	#[structopt(long = "run-plan", parse(from_os_str))]
+	run_plan: Option<PathBuf>,
+
+	/// Treat the targets, and the targets of `default` statements, as glob
+	/// patterns over the declared outputs: `*` and `?` match within a path
+	/// component, `**` matches across directories, and `[...]` matches a set
+	/// of characters. Patterns that match nothing are an error.
+	#[structopt(long = "glob")]
+	glob: bool,
+
+	/// Don't build all root targets when no targets are given and there are
+	/// no default targets, but list them and stop.
+	#[structopt(long = "require-targets")]
+	require_targets: bool,
+
 	/// Show command lines instead of descriptions. (Currently only in
 	/// combination with -n.)
 	#[structopt(short = "v")]
@@ -46,18 +93,88 @@ struct Options {
 	#[structopt(long = "sleep")]
 	sleep_run: bool,
 
+	/// How long to sleep for every task in a sleep run: A number of
+	/// milliseconds, `seeded` for a duration between 2.5 and 4.5 seconds
+	/// that only depends on the task, or `log` for the duration the task took
+	/// according to the build log. Implies --sleep.
+	#[structopt(long = "sleep-time")]
+	sleep_time: Option<SleepTime>,
+
+	/// Capture the standard output and standard error of commands as a single
+	/// stream, like `2>&1`, to keep them in order.
+	#[structopt(long = "merge-output")]
+	merge_output: bool,
+
+	/// A program to prepend to every command, such as `ccache`. Not used for
+	/// generator rules.
+	#[structopt(long = "launcher")]
+	launcher: Option<String>,
+
+	/// Keep the outputs of commands in this directory, and restore them from
+	/// there instead of running a command again with the same inputs. This
+	/// requires commands to be deterministic, and to declare all their inputs
+	/// and outputs. Generator rules, rules with `always` set, and rules with a
+	/// `depfile` but no `deps`, are never cached.
+	#[structopt(long = "cache-dir", parse(from_os_str))]
+	cache_dir: Option<PathBuf>,
+
+	/// Stop starting new commands after this many seconds, and let the
+	/// commands that are already running finish. The build then fails,
+	/// listing how many commands were not started. Running again continues
+	/// where it stopped.
+	#[structopt(long = "max-time")]
+	max_time: Option<u64>,
+
+	/// Write a checksum at the end of .ninja_deps, and check it the next time
+	/// to detect corruption. A corrupt log is recovered by dropping everything
+	/// from the first invalid record, with a warning. (Ninja ignores the
+	/// checksum.)
+	#[structopt(long = "checksum-deps")]
+	checksum_deps: bool,
+
+	/// Reject paths in the build specification that contain shell
+	/// metacharacters or control characters. Meant for build specifications
+	/// generated from untrusted input. (Also applies to subtools.)
+	#[structopt(long = "strict-paths")]
+	strict_paths: bool,
+
 	/// Run a subtool. Use -t list to list subtools.
 	#[structopt(short = "t")]
 	tool: Option<String>,
 
+	/// Also clean the outputs of generator rules. (Only for -t clean.)
+	#[structopt(short = "g")]
+	clean_generator: bool,
+
+	/// The port to serve on. (Only for -t browse.)
+	#[cfg(feature = "browse")]
+	#[structopt(long = "port", default_value = "8000")]
+	port: u16,
+
+	/// Only clean or list the outputs of this rule. (Only for -t clean and -t
+	/// targets.)
+	#[structopt(short = "r", long = "rule")]
+	rule: Option<String>,
+
 	/// The build specification.
 	#[structopt(short = "f", default_value = "build.ninja", parse(from_os_str))]
 	file: PathBuf,
 
+	/// Where to keep .ninja_log and .ninja_deps, instead of the `builddir`
+	/// from the build specification. This does not change where the outputs
+	/// are built.
+	#[structopt(long = "builddir", parse(from_os_str))]
+	build_dir: Option<PathBuf>,
+
 	/// Number of concurrent jobs.
 	#[structopt(short = "j", default_value = "8")]
 	n_threads: usize,
 
+	/// Keep going until this many jobs fail. Zero means keep going until all
+	/// possible work is done.
+	#[structopt(short = "k", default_value = "1")]
+	keep_going: usize,
+
 	/// Enable debug messages.
 	#[structopt(long)]
 	debug: bool,
@@ -65,6 +182,39 @@ struct Options {
 	/// Set format of progress indication (none/text/ascii/highres).
 	#[structopt(short = "P", long = "progress", default_value = "highres")]
 	progress: ProgressFormat,
+
+	/// Also show the description of the most recently started task in the
+	/// progress bar. (Only for the .split progress formats.)
+	#[structopt(long = "progress-task")]
+	progress_task: bool,
+
+	/// Prefix every line of output of failed commands with
+	/// `FAILED[description]: `, for easy extraction from logs. The output of
+	/// commands is then only shown once they finish.
+	#[structopt(long = "failure-prefix")]
+	failure_prefix: bool,
+
+	/// Also show the number of tasks finished per second, averaged over the
+	/// last few seconds, in the progress indication.
+	#[structopt(long = "progress-rate")]
+	progress_rate: bool,
+}
+
+impl Options {
+	/// The directory for .ninja_log and .ninja_deps: Either the one given with
+	/// --builddir, or the `builddir` of the spec.
+	fn build_dir<'a>(&'a self, spec: &'a Spec) -> &'a Path {
+		self.build_dir
+			.as_deref()
+			.unwrap_or_else(|| spec.build_dir())
+	}
+
+	/// The options for reading the build specification.
+	fn read_options(&self) -> ReadOptions {
+		ReadOptions {
+			reject_unsafe_paths: self.strict_paths,
+		}
+	}
 }
 
 fn main() {
@@ -93,63 +243,180 @@ fn main() {
 		exit(0);
 	}
 
-	let spec = read(&opt.file).unwrap_or_else(|e| {
-		error!("{}", e);
+	let spec = read_collecting_errors(&opt.file, opt.read_options()).unwrap_or_else(|errors| {
+		for e in errors {
+			error!("{}", e);
+		}
 		exit(1);
 	});
 
-	let targets: &[RawString] = if opt.targets.is_empty() {
-		&spec.default_targets
-	} else {
-		&opt.targets
-	};
+	for e in spec.validate() {
+		warn!("{}", e);
+	}
 
-	let build_log = Mutex::new(
-		BuildLog::read(spec.build_dir().join(".ninja_log")).unwrap_or_else(|e| {
-			error!("Error while reading .ninja_log: {}", e);
-			error!("Not using .ninja_log.");
-			BuildLog::new()
-		}),
-	);
+	if opt.changed_from.is_some() && !opt.targets.is_empty() {
+		error!("--changed-from can't be combined with explicit targets.");
+		exit(1);
+	}
 
-	let dep_log = DepLogMut::open(spec.build_dir().join(".ninja_deps")).unwrap_or_else(|e| {
-		error!("Error while reading .ninja_deps: {}", e);
-		// TODO: Delete and start a new file.
+	if opt.run_plan.is_some() && (opt.changed_from.is_some() || !opt.targets.is_empty()) {
+		error!("--run-plan can't be combined with explicit targets or --changed-from.");
 		exit(1);
-	});
+	}
+
+	let targets: Vec<&RawStr> = if !opt.targets.is_empty() {
+		opt.targets.iter().map(|t| &t[..]).collect()
+	} else if !spec.default_targets.is_empty() {
+		spec.default_targets.iter().map(|t| &t[..]).collect()
+	} else if opt.require_targets {
+		error!("No targets given, and no default targets. Root targets are:");
+		for target in spec.root_targets() {
+			error!("  {}", target);
+		}
+		exit(1);
+	} else {
+		spec.root_targets()
+	};
 
 	let target_to_rule = spec.make_index();
 
+	let targets = if opt.glob {
+		expand_globs(targets, &target_to_rule)
+	} else {
+		targets
+	};
+
 	let targets = targets.iter().map(|target| {
-		*target_to_rule.get(&target[..]).unwrap_or_else(|| {
+		*target_to_rule.get(target).unwrap_or_else(|| {
 			error!("Unknown target {:?}", target);
 			exit(1);
 		})
 	});
 
+	if opt.check {
+		let errors = spec.check_rules(&target_to_rule, targets);
+		for e in &errors {
+			error!("{}", e);
+		}
+		if !errors.is_empty() {
+			exit(1);
+		}
+		eprintln!("ninj: no problems found.");
+		exit(0);
+	}
+
+	let build_log_path = opt.build_dir(&spec).join(".ninja_log");
+
+	let build_log = BuildLog::read(&build_log_path).unwrap_or_else(|e| {
+		error!("Error while reading .ninja_log: {}", e);
+		error!("Not using .ninja_log.");
+		BuildLog::new()
+	});
+
+	let planned_tasks = opt.run_plan.as_ref().map(|file| {
+		read_plan(file, &spec, &target_to_rule).unwrap_or_else(|e| {
+			error!("Unable to read plan {:?}: {}", file, e);
+			exit(1);
+		})
+	});
+	let plan: Option<HashSet<usize>> = planned_tasks.as_ref().map(|t| t.iter().cloned().collect());
+
+	let dep_log_path = opt.build_dir(&spec).join(".ninja_deps");
+	let dep_log = if opt.checksum_deps {
+		DepLogMut::open_checked(dep_log_path)
+	} else {
+		DepLogMut::open(dep_log_path)
+	};
+	let mut dep_log = dep_log.unwrap_or_else(|e| {
+		error!("Error while reading .ninja_deps: {}", e);
+		// TODO: Delete and start a new file.
+		exit(1);
+	});
+	// The order of dependencies doesn't matter for deciding what is outdated.
+	dep_log.set_ignore_order(true);
+
+	let targets: Vec<usize> = match (planned_tasks, &opt.changed_from) {
+		(Some(tasks), _) => tasks,
+		(None, None) => targets.collect(),
+		(None, Some(file)) => {
+			let changed = read_path_list(file);
+			let any_known = {
+				let mut known: HashSet<&RawStr> = target_to_rule.keys().copied().collect();
+				known.extend(
+					spec.build_rules
+						.iter()
+						.flat_map(|r| r.inputs.iter().map(|i| &i[..])),
+				);
+				known.extend(dep_log.iter().flat_map(|(_, deps)| deps.deps()));
+				changed.is_empty() || changed.iter().any(|path| known.contains(&path[..]))
+			};
+			if !any_known {
+				error!(
+					"None of the paths in {:?} appear in the build files or the dependency log.",
+					file
+				);
+				close_dep_log(dep_log);
+				exit(1);
+			}
+			let logged_deps = dep_log
+				.iter()
+				.flat_map(|(output, deps)| deps.deps().map(move |dep| (output, dep)));
+			let affected =
+				spec.affected_rules(&target_to_rule, logged_deps, changed.iter().map(|p| &p[..]));
+			debug!(
+				"{} changed files affect {} build rules.",
+				changed.len(),
+				affected.len()
+			);
+			affected
+		}
+	};
+
 	let mut stat_cache = StatCache::new();
 	let mut dep_stat_cache = StatCache::new();
+	let mut pool_ids = HashMap::<&str, usize>::new();
 
 	let mut queue = BuildQueue::new(spec.build_rules.len(), targets, |task: usize| {
 		let rule = &spec.build_rules[task];
 		let mut dependencies = Vec::new();
-		let outdated = is_outdated(
-			rule,
-			&dep_log,
-			&mut stat_cache,
-			&mut dep_stat_cache,
-			|input: &RawStr| {
-				let task = target_to_rule.get(input);
-				if let Some(&task) = task {
-					dependencies.push(DepInfo {
-						task,
-						order_only: false,
-					});
+		let mut check_dep = |input: &RawStr| {
+			let task = target_to_rule.get(input);
+			if let Some(&task) = task {
+				dependencies.push(DepInfo {
+					task,
+					order_only: false,
+				});
+			}
+			task.is_some()
+		};
+		let outdated = if let Some(plan) = &plan {
+			// Don't check anything, just run what the plan says. Dependencies
+			// from the dependency log still order the tasks, such that a
+			// generated header is built before the tasks that include it.
+			for input in &rule.inputs {
+				check_dep(input);
+			}
+			if rule.command.as_ref().is_some_and(|c| c.deps.is_some()) {
+				for output in &rule.outputs {
+					if let Some(deps) = dep_log.get(output) {
+						for dep in deps.deps() {
+							check_dep(dep);
+						}
+					}
 				}
-				task.is_some()
-			},
-		)
-		.unwrap();
+			}
+			plan.contains(&task)
+		} else {
+			is_outdated(
+				rule,
+				&dep_log,
+				&build_log,
+				&mut stat_cache,
+				&mut dep_stat_cache,
+				check_dep,
+			)
+			.unwrap()
+		};
 		for order_dep in &rule.order_deps {
 			let order_dep: &RawStr = order_dep;
 			if let Some(&task) = target_to_rule.get(order_dep) {
@@ -159,21 +426,55 @@ fn main() {
 				});
 			}
 		}
+		let validations = rule
+			.validations
+			.iter()
+			.filter_map(|validation| target_to_rule.get(&validation[..]).cloned())
+			.collect::<Vec<_>>();
+		// A depth of zero means no limit, like ninja.
+		let pool = rule.command.as_ref().and_then(|c| {
+			let depth = c.pool_depth.filter(|&d| d > 0)?;
+			let n_pools = pool_ids.len();
+			let id = *pool_ids.entry(&c.pool).or_insert(n_pools);
+			Some(PoolInfo {
+				id,
+				depth: depth.into(),
+			})
+		});
 		TaskInfo {
 			dependencies,
+			validations,
 			phony: rule.is_phony(),
 			outdated,
+			pool,
 		}
 	});
 
 	drop(dep_stat_cache);
 
 	if queue.n_left() == 0 {
+		close_dep_log(dep_log);
 		eprintln!("ninj: no work to do.");
 		exit(0);
 	}
 
+	if let Some(file) = &opt.write_plan {
+		close_dep_log(dep_log);
+		let mut tasks = Vec::with_capacity(queue.n_left());
+		while let Some(task) = queue.next() {
+			tasks.push(task);
+			queue.complete_task(task, None);
+		}
+		write_plan(file, &spec, &tasks).unwrap_or_else(|e| {
+			error!("Unable to write plan {:?}: {}", file, e);
+			exit(1);
+		});
+		eprintln!("ninj: wrote a plan of {} tasks.", tasks.len());
+		exit(0);
+	}
+
 	if opt.dry_run {
+		close_dep_log(dep_log);
 		let n_tasks = queue.n_left();
 		while let Some(task) = queue.next() {
 			let c = spec.build_rules[task]
@@ -192,10 +493,20 @@ fn main() {
 	}
 
 	let n_threads = opt.n_threads;
+	let start_time = Instant::now();
+	let max_time = opt.max_time;
+	if let Some(max_time) = max_time {
+		queue.set_deadline(start_time + Duration::from_secs(max_time));
+	}
 	let queue = queue.make_async();
-	let dep_log = Mutex::new(dep_log);
+	let dep_log = RwLock::new(dep_log);
+	let build_log = Mutex::new(build_log);
 	let status = BuildStatus::new(n_threads);
-	let start_time = Instant::now();
+	let launcher = opt.launcher.clone();
+	let sleep_time = opt
+		.sleep_time
+		.or_else(|| opt.sleep_run.then_some(SleepTime::Seeded));
+	let cache = opt.cache_dir.as_ref().map(Cache::new);
 
 	crossbeam::thread::scope(|scope| {
 		for i in 0..n_threads {
@@ -206,17 +517,33 @@ fn main() {
 					status_listener: &status,
 					worker_id: i,
 				},
-				sleep: opt.sleep_run,
+				sleep: sleep_time,
+				merge_output: opt.merge_output,
+				launcher: launcher.as_deref().map(RawStr::from_str),
+				max_failures: opt.keep_going,
 				dep_log: &dep_log,
 				build_log: &build_log,
 				start_time,
+				cache: cache.as_ref(),
 			};
 			scope.spawn(move |_| worker.run());
 		}
 		if opt.debug {
 			debug!("Regular output disabled because debug messages are enabled.");
 		} else {
-			show_build_status(start_time, &status, &queue, &spec, &build_log, opt.progress);
+			show_build_status(
+				start_time,
+				&status,
+				&queue,
+				&spec,
+				&build_log,
+				DisplayOptions {
+					progress_format: opt.progress,
+					show_task: opt.progress_task,
+					failure_prefix: opt.failure_prefix,
+					show_rate: opt.progress_rate,
+				},
+			);
 		}
 	})
 	.unwrap();
@@ -224,9 +551,109 @@ fn main() {
 	build_log
 		.into_inner()
 		.unwrap()
-		.write(spec.build_dir().join(".ninja_log"))
+		.write(&build_log_path)
 		.unwrap_or_else(|e| {
 			eprintln!("Unable to store logfile: {}", e);
 			exit(1);
 		});
+
+	close_dep_log(dep_log.into_inner().unwrap());
+
+	let queue = queue.into_inner();
+	if queue.n_failed() > 0 {
+		report_failures(&spec, &queue);
+		exit(1);
+	}
+	if queue.deadline_reached() {
+		eprintln!(
+			"ninj: build stopped after {} seconds: {} succeeded, {} not started.",
+			max_time.unwrap_or_default(),
+			queue.n_finished(),
+			queue.n_left()
+		);
+		exit(1);
+	}
+}
+
+/// Close the dependency log, to flush it (and write its checksum), or exit
+/// with an error.
+fn close_dep_log(dep_log: DepLogMut) {
+	dep_log.close().unwrap_or_else(|e| {
+		error!("Unable to write .ninja_deps: {}", e);
+		exit(1);
+	});
+}
+
+/// Read a list of paths, one per line, from a file, or from standard input if
+/// the file name is `-`. Lines may also end in `\r\n`.
+fn read_path_list(file: &Path) -> Vec<RawString> {
+	let data = if file == Path::new("-") {
+		let mut data = Vec::new();
+		std::io::stdin().read_to_end(&mut data).map(|_| data)
+	} else {
+		std::fs::read(file)
+	};
+	let data = data.unwrap_or_else(|e| {
+		error!("Unable to read {:?}: {}", file, e);
+		exit(1);
+	});
+	data.split(|&b| b == b'\n')
+		.map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+		.filter(|line| !line.is_empty())
+		.map(|line| RawString::from_bytes(line.to_vec()))
+		.collect()
+}
+
+/// Replace glob patterns by the outputs they match, for --glob.
+fn expand_globs<'a>(
+	targets: Vec<&'a RawStr>,
+	index: &HashMap<&'a RawStr, usize>,
+) -> Vec<&'a RawStr> {
+	let mut expanded = Vec::with_capacity(targets.len());
+	for target in targets {
+		if !glob::is_glob(target) {
+			expanded.push(target);
+			continue;
+		}
+		let outputs = glob::match_outputs(target, index);
+		if outputs.is_empty() {
+			error!("No targets match {:?}", target);
+			exit(1);
+		}
+		debug!("{:?} matches {} targets.", target, outputs.len());
+		expanded.extend(outputs);
+	}
+	expanded
+}
+
+/// Print the sorted lists of failed targets, and targets that were skipped
+/// because a dependency failed.
+fn report_failures(spec: &Spec, queue: &BuildQueue) {
+	let mut failed = Vec::new();
+	let mut skipped = Vec::new();
+	for (task, rule) in spec.build_rules.iter().enumerate() {
+		let list = match queue.get_task_status(task) {
+			TaskStatus::Failed { .. } => &mut failed,
+			TaskStatus::Skipped => &mut skipped,
+			_ => continue,
+		};
+		list.extend(rule.explicit_outputs().iter().map(|t| &t[..]));
+	}
+	failed.sort();
+	skipped.sort();
+	eprintln!(
+		"ninj: build failed: {} succeeded, {} failed, {} skipped. Failed targets:",
+		queue.n_finished(),
+		queue.n_failed(),
+		queue.n_skipped()
+	);
+	for target in failed {
+		eprintln!("  {}", target);
+	}
+	if !skipped.is_empty() {
+		eprintln!("ninj: Skipped targets, because a dependency failed:");
+		for target in skipped {
+			eprintln!("  {}", target);
+		}
+	}
 }
@@ -0,0 +1,115 @@
+//! Build plans: The list of tasks to run, in order, as written by
+//! `--write-plan` and run by `--run-plan`.
+//!
+//! A plan starts with the line `# ninj plan v1`, followed by one line per
+//! task, containing the first output of its build rule and its command,
+//! separated by a tab.
+
+use ninj::spec::Spec;
+use raw_string::RawStr;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Write};
+use std::path::Path;
+
+const HEADER: &str = "# ninj plan v1";
+
+/// Write the tasks to a plan file.
+pub fn write_plan(file: &Path, spec: &Spec, tasks: &[usize]) -> Result<(), Error> {
+	let mut out = BufWriter::new(File::create(file)?);
+	writeln!(out, "{}", HEADER)?;
+	for &task in tasks {
+		let rule = &spec.build_rules[task];
+		let output = &rule.outputs[0];
+		let command = &rule.command.as_ref().expect("Got phony task").command;
+		if output.contains_byte(b'\t')
+			|| output.contains_byte(b'\n')
+			|| command.contains_byte(b'\n')
+		{
+			return Err(Error::new(
+				ErrorKind::InvalidInput,
+				format!("Unable to write {:?} to a plan", output),
+			));
+		}
+		out.write_all(output.as_bytes())?;
+		out.write_all(b"\t")?;
+		out.write_all(command.as_bytes())?;
+		out.write_all(b"\n")?;
+	}
+	out.flush()
+}
+
+/// Read a plan file, and look up its tasks in the spec.
+///
+/// Fails if a target is unknown, or if its command is different from the
+/// one in the plan.
+pub fn read_plan(
+	file: &Path,
+	spec: &Spec,
+	index: &HashMap<&RawStr, usize>,
+) -> Result<Vec<usize>, Error> {
+	let data = std::fs::read(file)?;
+	let mut lines = data.split(|&b| b == b'\n').filter(|line| !line.is_empty());
+	if lines.next() != Some(HEADER.as_bytes()) {
+		return Err(Error::new(ErrorKind::InvalidData, "Not a plan file"));
+	}
+	lines
+		.map(|line| {
+			let tab = memchr::memchr(b'\t', line)
+				.ok_or_else(|| Error::new(ErrorKind::InvalidData, "Missing command"))?;
+			let output = RawStr::from_bytes(&line[..tab]);
+			let command = RawStr::from_bytes(&line[tab + 1..]);
+			let task = *index.get(output).ok_or_else(|| {
+				Error::new(
+					ErrorKind::InvalidData,
+					format!("Unknown target {:?}", output),
+				)
+			})?;
+			let rule = &spec.build_rules[task];
+			if rule.command.as_ref().map(|c| &c.command[..]) != Some(command) {
+				return Err(Error::new(
+					ErrorKind::InvalidData,
+					format!("Command of {:?} changed since the plan was made", output),
+				));
+			}
+			Ok(task)
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::test_dir::TestDir;
+	use ninj::spec::read_from;
+
+	#[test]
+	fn write_and_read() -> Result<(), Error> {
+		let spec = read_from(
+			Path::new("build.ninja"),
+			b"rule cc\n  command = cc $in -o $out\n\
+			  build a.o: cc a.c\n\
+			  build b.o: cc b.c\n\
+			  build all: phony a.o b.o\n",
+		)
+		.unwrap();
+		let index = spec.make_index();
+		let dir = TestDir::new("plan");
+		let file = dir.join("plan");
+		write_plan(&file, &spec, &[1, 0])?;
+		assert_eq!(
+			std::fs::read_to_string(&file)?,
+			"# ninj plan v1\nb.o\tcc b.c -o b.o\na.o\tcc a.c -o a.o\n"
+		);
+		assert_eq!(read_plan(&file, &spec, &index)?, [1, 0]);
+
+		std::fs::write(&file, "# ninj plan v1\na.o\tcc -O2 a.c -o a.o\n")?;
+		assert!(read_plan(&file, &spec, &index).is_err());
+		std::fs::write(&file, "# ninj plan v1\nc.o\tcc c.c -o c.o\n")?;
+		assert!(read_plan(&file, &spec, &index).is_err());
+		std::fs::write(&file, "a.o\tcc a.c -o a.o\n")?;
+		assert!(read_plan(&file, &spec, &index).is_err());
+
+		Ok(())
+	}
+}
@@ -7,15 +7,61 @@ use ninj::buildlog::BuildLog;
 use ninj::queue::{AsyncBuildQueue, TaskStatus};
 use ninj::spec::Spec;
 use progressbar::ProgressBar;
-use raw_string::RawString;
+use raw_string::{RawStr, RawString};
+use std::collections::{HashMap, VecDeque};
 use std::error::Error;
-use std::fmt;
-use std::mem::replace;
+use std::fmt::{self, Write as _};
+use std::io::{ErrorKind, Stdout, Write as _};
+use std::mem::{replace, take};
 use std::process::ExitStatus;
 use std::sync::{Condvar, Mutex};
 use std::time::{Duration, Instant};
 use time::Duration as TimeDuration;
 
+/// Like `print!`, but through an [`Output`].
+macro_rules! out {
+	($out:expr, $($arg:tt)*) => {
+		$out.print(format_args!($($arg)*))
+	};
+}
+
+/// Like `println!`, but through an [`Output`].
+macro_rules! outln {
+	($out:expr, $($arg:tt)*) => {
+		$out.print(format_args!("{}\n", format_args!($($arg)*)))
+	};
+}
+
+/// Standard output for the status display.
+///
+/// Instead of panicking when standard output is a closed pipe (e.g. with
+/// `ninj | head`), it silently stops printing, and the build continues.
+struct Output {
+	stdout: Stdout,
+	broken: bool,
+}
+
+impl Output {
+	fn new() -> Self {
+		Output {
+			stdout: std::io::stdout(),
+			broken: false,
+		}
+	}
+
+	fn print(&mut self, args: fmt::Arguments) {
+		if self.broken {
+			return;
+		}
+		if let Err(e) = self.stdout.write_fmt(args) {
+			if e.kind() != ErrorKind::BrokenPipe {
+				panic!("failed printing to stdout: {}", e);
+			}
+			self.broken = true;
+		}
+	}
+}
+
 #[derive(Debug, Clone, PartialEq)]
 enum WorkerStatus {
 	Starting,
@@ -123,7 +169,8 @@ impl BuildStatusInner {
 	}
 }
 
-fn estimated_total_task_time(
+/// Estimate how long a task takes from the build log.
+pub fn estimated_total_task_time(
 	spec: &Spec,
 	task: usize,
 	build_log: &std::sync::MutexGuard<BuildLog>,
@@ -134,7 +181,7 @@ fn estimated_total_task_time(
 			.get(*output)
 			.and_then(|entry| entry.end_time_ms.checked_sub(entry.start_time_ms))
 		{
-			return Some(Duration::from_millis(estimate_ms.into()));
+			return Some(Duration::from_millis(estimate_ms));
 		}
 	}
 
@@ -145,8 +192,8 @@ fn estimated_total_task_time(
 	} else {
 		let sum_ms: u64 = build_log
 			.entries
-			.iter()
-			.map(|(_, entry)| u64::from(entry.end_time_ms.saturating_sub(entry.start_time_ms)))
+			.values()
+			.map(|entry| entry.end_time_ms.saturating_sub(entry.start_time_ms))
 			.sum();
 		Some(Duration::from_millis(
 			sum_ms / build_log.entries.len() as u64,
@@ -194,15 +241,80 @@ impl fmt::Display for ParseProgressFormatError {
 
 impl Error for ParseProgressFormatError {}
 
+/// How [`show_build_status`] displays things.
+pub struct DisplayOptions {
+	pub progress_format: ProgressFormat,
+	/// Show the description of the most recently started task in the split
+	/// progress bars.
+	pub show_task: bool,
+	/// Hold back the output of tasks until they finish, and prefix every line
+	/// of output of failed tasks with `FAILED[description]: `, without any
+	/// escape codes.
+	pub failure_prefix: bool,
+	/// Show the number of tasks finished per second.
+	pub show_rate: bool,
+}
+
+/// Measures the number of tasks finished per second, as a moving average.
+#[derive(Default)]
+struct RateMeter {
+	/// The times at which the number of done tasks was sampled, and that
+	/// number.
+	samples: VecDeque<(Instant, usize)>,
+}
+
+impl RateMeter {
+	/// The period over which the average is taken.
+	const WINDOW: Duration = Duration::from_secs(5);
+
+	fn sample(&mut self, now: Instant, n_done: usize) {
+		self.samples.push_back((now, n_done));
+		while self.samples.len() > 2 && now - self.samples[1].0 >= Self::WINDOW {
+			self.samples.pop_front();
+		}
+	}
+
+	/// Tasks per second, or `None` if there aren't enough samples yet.
+	fn rate(&self) -> Option<f64> {
+		let (first_time, first_n) = *self.samples.front()?;
+		let (last_time, last_n) = *self.samples.back()?;
+		let period = (last_time - first_time).as_secs_f64();
+		if period < 1. {
+			return None;
+		}
+		Some((last_n - first_n) as f64 / period)
+	}
+}
+
+/// Print the output of a task, line by line.
+fn print_lines(out: &mut Output, data: &RawStr) {
+	let mut n_written = 0;
+	for newline in memchr_iter(b'\n', data.as_bytes()) {
+		outln!(out, "{}\x1b[K", &data[n_written..newline]);
+		n_written = newline + 1;
+	}
+	outln!(out, "{}\x1b[K\x1b[m", &data[n_written..]);
+}
+
 pub fn show_build_status(
 	start_time: Instant,
 	status: &BuildStatus,
 	queue: &AsyncBuildQueue,
 	spec: &Spec,
 	build_log: &Mutex<BuildLog>,
-	progress_format: ProgressFormat,
+	options: DisplayOptions,
 ) {
-	let mut last_output_task = usize::max_value();
+	let DisplayOptions {
+		progress_format,
+		show_task,
+		failure_prefix,
+		show_rate,
+	} = options;
+	let mut rate_meter = RateMeter::default();
+	let mut out = Output::new();
+	let mut held_output = HashMap::<usize, RawString>::new();
+	let mut last_output_task = usize::MAX;
+	let mut last_started_task = None;
 	let mut lock = status.inner.lock().unwrap();
 	loop {
 		let mut now = Instant::now();
@@ -214,9 +326,14 @@ pub fn show_build_status(
 		let queuelock = queue.lock();
 		let mut queuestate = queuelock.clone_queue();
 		drop(queuelock);
+		if show_rate {
+			// Sample now, before the simulation below completes tasks.
+			let n_done = queuestate.n_finished() + queuestate.n_failed();
+			rate_meter.sample(Instant::now(), n_done);
+		}
 		let mut buildstate = BuildStatusInner {
 			workers: lock.workers.clone(),
-			output: replace(&mut lock.output, Vec::new()),
+			output: take(&mut lock.output),
 			dirty: replace(&mut lock.dirty, false),
 		};
 		drop(lock);
@@ -226,6 +343,36 @@ pub fn show_build_status(
 				.command
 				.as_ref()
 				.expect("Got output for phony task");
+			// With failure_prefix, print the output only once we know whether
+			// the task failed.
+			let held = if failure_prefix {
+				if let Message::Output(data) = output {
+					held_output.entry(*task).or_default().push_str(data);
+					continue;
+				}
+				held_output.remove(task)
+			} else {
+				None
+			};
+			match (output, held) {
+				(Message::Failed(_), Some(held)) => {
+					for line in held.as_bytes().split(|&b| b == b'\n') {
+						if !line.is_empty() {
+							let line = RawStr::from_bytes(line);
+							outln!(out, "FAILED[{}]: {}", command.description, line);
+						}
+					}
+				}
+				(Message::Success, Some(held)) => {
+					outln!(
+						out,
+						"\x1b[30;43m  \x1b[m\x1b[33m {}:\x1b[K\x1b[m",
+						command.description
+					);
+					print_lines(&mut out, &held);
+				}
+				_ => {}
+			}
 			match output {
 				Message::Output(data) => {
 					if *task != last_output_task {
@@ -239,9 +386,10 @@ pub fn show_build_status(
 								i.next();
 								failed = true;
 								if let Some(status) = status {
-									println!("\x1b[30;41m  \x1b[m\x1b[31;1m Failed with {}: {}:\x1b[K\x1b[m", status, command.description);
+									outln!(out, "\x1b[30;41m  \x1b[m\x1b[31;1m Failed with {}: {}:\x1b[K\x1b[m", status, command.description);
 								} else {
-									println!(
+									outln!(
+										out,
 										"\x1b[30;41m  \x1b[m\x1b[31;1m Failed: {}:\x1b[K\x1b[m",
 										command.description
 									);
@@ -249,37 +397,37 @@ pub fn show_build_status(
 							}
 						}
 						if !failed {
-							println!(
+							outln!(
+								out,
 								"\x1b[30;43m  \x1b[m\x1b[33m {}:\x1b[K\x1b[m",
 								command.description
 							);
 						}
 					}
-					let mut n_written = 0;
-					for newline in memchr_iter(b'\n', data.as_bytes()) {
-						println!("{}\x1b[K", &data[n_written..newline]);
-						n_written = newline + 1;
-					}
-					println!("{}\x1b[K\x1b[m", &data[n_written..]);
+					print_lines(&mut out, data);
 					last_output_task = *task;
 				}
-				Message::Started => {}
+				Message::Started => last_started_task = Some(*task),
 				Message::Success => {
-					println!(
+					outln!(
+						out,
 						"\x1b[30;42m  \x1b[m\x1b[32m Finished {}\x1b[K\x1b[m",
 						command.description
 					);
 					last_output_task = *task;
 				}
 				Message::Failed(Some(status)) => {
-					println!(
+					outln!(
+						out,
 						"\x1b[30;41m  \x1b[m\x1b[31;1m Failed with {}: {}\x1b[K\x1b[m",
-						status, command.description
+						status,
+						command.description
 					);
 					last_output_task = *task;
 				}
 				Message::Failed(None) => {
-					println!(
+					outln!(
+						out,
 						"\x1b[30;41m  \x1b[m\x1b[31;1m Failed: {}\x1b[K\x1b[m",
 						command.description
 					);
@@ -301,9 +449,11 @@ pub fn show_build_status(
 					}
 					_ => String::new(),
 				};
-				println!(
+				outln!(
+					out,
 					"\x1b[30;44m  \x1b[m\x1b[34m {}{} ...\x1b[K\x1b[m",
-					statustext, command.description,
+					statustext,
+					command.description,
 				);
 				worker_status_lines += 1;
 			}
@@ -356,18 +506,20 @@ pub fn show_build_status(
 							} else {
 								Duration::from_millis(0)
 							};
-							match estimated_total_task_time(spec, *task, &build_log.lock().unwrap())
-							{
-								Some(time) => Some((
-									i,
-									task,
-									time.checked_sub(runtime)
-										.unwrap_or(Duration::from_millis(0)),
-								)),
-								None => None,
-							}
+							estimated_total_task_time(spec, *task, &build_log.lock().unwrap()).map(
+								|time| {
+									(
+										i,
+										task,
+										time.checked_sub(runtime)
+											.unwrap_or(Duration::from_millis(0)),
+									)
+								},
+							)
+						}
+						TaskStatus::Finished { .. } | TaskStatus::Failed { .. } => {
+							Some((i, task, Duration::from_millis(0)))
 						}
-						TaskStatus::Finished { .. } => Some((i, task, Duration::from_millis(0))),
 						_ => unreachable!(),
 					},
 					_ => None,
@@ -391,7 +543,7 @@ pub fn show_build_status(
 				TaskStatus::Running { .. } => {
 					queuestate.complete_task_at(*task, None, simulated_time);
 				}
-				TaskStatus::Finished { .. } => {}
+				TaskStatus::Finished { .. } | TaskStatus::Failed { .. } => {}
 				_ => unreachable!(),
 			};
 
@@ -441,26 +593,34 @@ pub fn show_build_status(
 			}
 		};
 
+		let ratetext = match rate_meter.rate() {
+			_ if !show_rate => String::new(),
+			Some(rate) => format!(", {:.1} jobs/s", rate),
+			None => ", ?? jobs/s".to_owned(),
+		};
+
 		let progress = match progress_format {
 			ProgressFormat::None => "".to_owned(),
 			ProgressFormat::Text => format!(
-				"[Building for {}, {}, {} remaining, ETA {}]\x1b[K\x1b[m\n",
+				"[Building for {}, {}, {} remaining, ETA {}{}]\x1b[K\x1b[m\n",
 				MinSec::since(start_time),
 				percentagetext,
 				remainingtext,
-				etatext
+				etatext,
+				ratetext
 			),
 			ProgressFormat::ASCIIBar | ProgressFormat::HighResBar => {
 				// Every 5 seconds, switch between showing ETA and remaining duration
 				let show_eta = (current_duration.as_secs() % 10) > 5;
 				let text = format!(
-					"{} ({})",
+					"{} ({}){}",
 					percentagetext,
 					if show_eta {
 						format!("ETA {}", etatext)
 					} else {
 						format!("{} remaining", remainingtext)
-					}
+					},
+					ratetext
 				);
 
 				format!(
@@ -474,7 +634,12 @@ pub fn show_build_status(
 				)
 			}
 			ProgressFormat::ASCIISplitBar | ProgressFormat::HighResSplitBar => {
-				let text = format!("{} remaining", remainingtext);
+				let mut text = format!("{} remaining{}", remainingtext, ratetext);
+				if let Some(task) = last_started_task.filter(|_| show_task) {
+					if let Some(command) = &spec.build_rules[task].command {
+						write!(text, ": {}", command.description).unwrap();
+					}
+				}
 				format!(
 					"{} [{}] ETA {}\x1b[K\x1b[m\r",
 					percentagetext,
@@ -490,16 +655,20 @@ pub fn show_build_status(
 			}
 		};
 
-		print!("{}", progress);
+		out!(out, "{}", progress);
 
 		if build_is_done {
 			break;
 		}
 
-		println!("\x1b[{}A", progress.lines().count() + worker_status_lines);
+		outln!(
+			out,
+			"\x1b[{}A",
+			progress.lines().count() + worker_status_lines
+		);
 		lock = status.inner.lock().unwrap();
 	}
-	println!("Finished.\x1b[K");
+	outln!(out, "Finished.\x1b[K");
 }
 
 fn terminal_width() -> usize {
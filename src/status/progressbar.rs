@@ -11,17 +11,21 @@ impl<'a> std::fmt::Display for ProgressBar<'a> {
 	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
 		let blocks = [' ', '▏', '▎', '▍', '▌', '▋', '▊', '▉'];
 		let ticks = (self.progress * self.width as f64 * blocks.len() as f64) as usize;
-		let label_pos = self.width.saturating_sub(self.label.len()) / 2;
+		// Only the printable characters of the label, and only as many as fit.
+		// Working with chars instead of bytes makes sure we never cut a UTF-8
+		// sequence in half.
+		let label: Vec<char> = printable_chars(self.label).take(self.width).collect();
+		let label_pos = (self.width - label.len()) / 2;
 		f.write_str("\x1b[32m")?;
 		let mut i = 0;
 		while i < self.width {
-			if i >= label_pos && i < label_pos + self.label.len() {
+			if i >= label_pos && i < label_pos + label.len() {
 				f.write_str(if i < ticks / blocks.len() {
 					"\x1b[32;7m"
 				} else {
 					"\x1b[m"
 				})?;
-				f.write_str(&self.label[i - label_pos..][..1])?;
+				f.write_char(label[i - label_pos])?;
 				f.write_str("\x1b[27;32m")?;
 			} else {
 				f.write_char(if i < ticks / blocks.len() {
@@ -46,3 +50,48 @@ impl<'a> std::fmt::Display for ProgressBar<'a> {
 		Ok(())
 	}
 }
+
+/// The characters of `s`, without control characters and without ANSI escape
+/// sequences (such as colours).
+fn printable_chars(s: &str) -> impl Iterator<Item = char> + '_ {
+	let mut chars = s.chars();
+	std::iter::from_fn(move || loop {
+		match chars.next()? {
+			'\x1b' => {
+				if chars.clone().next() == Some('[') {
+					// Skip a CSI sequence, up to and including its final byte.
+					chars.next();
+					for c in &mut chars {
+						if ('\x40'..='\x7e').contains(&c) {
+							break;
+						}
+					}
+				}
+			}
+			c if c.is_control() => {}
+			c => return Some(c),
+		}
+	})
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn coloured_label() {
+		let label = "\x1b[1;31mérrör\x1b[0m: ünïcödé";
+		assert_eq!(printable_chars(label).collect::<String>(), "érrör: ünïcödé");
+		let bar = ProgressBar {
+			progress: 0.0,
+			width: 4,
+			ascii: true,
+			label,
+		}
+		.to_string();
+		assert!(!bar.contains("[1;31m"));
+		// Without the escape sequences of the bar itself, only the first four
+		// characters of the label remain.
+		assert_eq!(printable_chars(&bar).collect::<String>(), "érrö");
+	}
+}
@@ -0,0 +1,198 @@
+use super::Options;
+use ninj::spec::{read_with, BuildRule, Spec};
+use raw_string::{RawStr, RawString};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Error, Write};
+use std::net::{TcpListener, TcpStream};
+
+/// Serve a browsable view of the build graph over HTTP.
+///
+/// Every target gets a page showing the rule that builds it with its inputs,
+/// and the targets that depend on it, all as links to their own pages.
+pub(super) fn main(opt: &Options) -> Result<(), Error> {
+	let spec = read_with(&opt.file, opt.read_options())?;
+	let graph = Graph::new(&spec);
+	let listener = TcpListener::bind(("127.0.0.1", opt.port))?;
+	let start = opt
+		.targets
+		.first()
+		.or_else(|| spec.default_targets.first())
+		.map_or(String::new(), |t| encode(t));
+	println!("Serving on http://{}/?{}", listener.local_addr()?, start);
+	for stream in listener.incoming() {
+		if let Err(e) = stream.and_then(|stream| graph.serve(stream)) {
+			log::warn!("{}", e);
+		}
+	}
+	Ok(())
+}
+
+/// The build rules, indexed by output and by input.
+struct Graph<'a> {
+	spec: &'a Spec,
+	outputs: HashMap<&'a RawStr, usize>,
+	dependents: HashMap<&'a RawStr, Vec<&'a RawStr>>,
+}
+
+impl<'a> Graph<'a> {
+	fn new(spec: &'a Spec) -> Self {
+		let mut dependents = HashMap::<&RawStr, Vec<&RawStr>>::new();
+		for rule in &spec.build_rules {
+			for input in rule.inputs.iter().chain(&rule.order_deps) {
+				let list = dependents.entry(input).or_default();
+				list.extend(rule.outputs.iter().map(|o| &o[..]));
+			}
+		}
+		Graph {
+			spec,
+			outputs: spec.make_index(),
+			dependents,
+		}
+	}
+
+	/// Handle a single HTTP request.
+	fn serve(&self, mut stream: TcpStream) -> Result<(), Error> {
+		let mut request = String::new();
+		BufReader::new(&stream).read_line(&mut request)?;
+		let target = request
+			.split(' ')
+			.nth(1)
+			.and_then(|path| path.split_once('?'))
+			.map(|(_, query)| query)
+			.map_or(RawString::new(), decode);
+		let body = self.page(&target);
+		write!(
+			stream,
+			"HTTP/1.0 200 OK\r\n\
+			 Content-Type: text/html; charset=utf-8\r\n\
+			 Content-Length: {}\r\n\r\n{}",
+			body.len(),
+			body
+		)
+	}
+
+	/// Generate the HTML page for a target.
+	fn page(&self, target: &RawStr) -> String {
+		let mut html = String::new();
+		write!(
+			html,
+			"<!DOCTYPE html>\n<title>{0}</title>\n\
+			 <style>body {{ font-family: sans-serif }} ul {{ font-family: monospace }}</style>\n\
+			 <h1>{0}</h1>\n",
+			escape(target)
+		)
+		.unwrap();
+		match self.outputs.get(target) {
+			Some(&rule) => self.write_rule(&mut html, &self.spec.build_rules[rule]),
+			None => html.push_str("<p>No rule builds this target.</p>\n"),
+		}
+		if let Some(dependents) = self.dependents.get(target) {
+			html.push_str("<h2>Dependents</h2>\n");
+			write_links(&mut html, dependents.iter().cloned());
+		}
+		html
+	}
+
+	fn write_rule(&self, html: &mut String, rule: &BuildRule) {
+		let name = rule.command.as_ref().map_or("phony", |c| &c.rule_name);
+		writeln!(html, "<p>Built by rule <b>{}</b>.</p>", escape(name)).unwrap();
+		if let Some(command) = &rule.command {
+			writeln!(html, "<pre>{}</pre>", escape(&command.command)).unwrap();
+		}
+		let sections = [
+			("Inputs", rule.explicit_inputs()),
+			("Implicit inputs", rule.implicit_inputs()),
+			("Order-only dependencies", &rule.order_deps[..]),
+			("Outputs", &rule.outputs[..]),
+		];
+		for (title, paths) in &sections {
+			if !paths.is_empty() {
+				writeln!(html, "<h2>{}</h2>", title).unwrap();
+				write_links(html, paths.iter().map(|p| &p[..]));
+			}
+		}
+	}
+}
+
+fn write_links<'a>(html: &mut String, targets: impl Iterator<Item = &'a RawStr>) {
+	html.push_str("<ul>\n");
+	for target in targets {
+		writeln!(
+			html,
+			"<li><a href=\"?{}\">{}</a></li>",
+			encode(target),
+			escape(target)
+		)
+		.unwrap();
+	}
+	html.push_str("</ul>\n");
+}
+
+/// Escape a string for use in HTML.
+fn escape(s: impl AsRef<RawStr>) -> String {
+	let mut escaped = String::new();
+	for c in String::from_utf8_lossy(s.as_ref().as_bytes()).chars() {
+		match c {
+			'<' => escaped.push_str("&lt;"),
+			'>' => escaped.push_str("&gt;"),
+			'&' => escaped.push_str("&amp;"),
+			'"' => escaped.push_str("&quot;"),
+			c => escaped.push(c),
+		}
+	}
+	escaped
+}
+
+/// Percent-encode a target name for use in a URL.
+fn encode(s: &RawStr) -> String {
+	let mut encoded = String::new();
+	for &b in s.as_bytes() {
+		if b.is_ascii_alphanumeric() || b"-_./".contains(&b) {
+			encoded.push(b as char);
+		} else {
+			write!(encoded, "%{:02X}", b).unwrap();
+		}
+	}
+	encoded
+}
+
+/// Decode a percent-encoded target name.
+fn decode(s: &str) -> RawString {
+	let mut decoded = RawString::new();
+	let mut bytes = s.bytes();
+	while let Some(b) = bytes.next() {
+		if b == b'%' {
+			let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+			let value = std::str::from_utf8(&hex)
+				.ok()
+				.filter(|h| h.len() == 2)
+				.and_then(|h| u8::from_str_radix(h, 16).ok());
+			match value {
+				Some(value) => decoded.push(value),
+				None => {
+					decoded.push(b'%');
+					decoded.push_str(RawStr::from_bytes(&hex));
+				}
+			}
+		} else if b == b'+' {
+			decoded.push(b' ');
+		} else {
+			decoded.push(b);
+		}
+	}
+	decoded
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn url_encoding() {
+		let target = RawStr::from_bytes(b"dir/a b+c%\xff.o");
+		assert_eq!(encode(target), "dir/a%20b%2Bc%25%FF.o");
+		assert_eq!(decode(&encode(target)), target);
+		assert_eq!(decode("a+b%2"), "a b%2");
+	}
+}
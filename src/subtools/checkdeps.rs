@@ -0,0 +1,57 @@
+use super::Options;
+use ninj::deplog::DepLogMut;
+use ninj::mtime::StatCache;
+use raw_string::unix::RawStrExt;
+use std::io::Error;
+
+/// Check the dependency log against the file system.
+///
+/// Like `deps`, but also checks every recorded dependency, and reports the
+/// ones which no longer exist or are newer than the recorded `mtime`.
+pub(super) fn main(opt: &Options) -> Result<(), Error> {
+	use ninj::spec::read_with;
+	let spec = read_with(&opt.file, opt.read_options())?;
+	let targets = spec.make_index();
+	let dep_log = DepLogMut::open(opt.build_dir(&spec).join(".ninja_deps"))?;
+	let mut stat_cache = StatCache::new();
+	for (path, deps) in dep_log.iter() {
+		if !targets.contains_key(path) {
+			continue;
+		}
+		let mtime = stat_cache.mtime(path.as_path())?;
+		let mut dep_states = Vec::with_capacity(deps.deps().len());
+		for dep in deps.deps() {
+			dep_states.push(match stat_cache.mtime(dep.as_path())? {
+				None => Some("MISSING"),
+				Some(dep_mtime) if Some(dep_mtime) > deps.mtime() => Some("NEWER"),
+				Some(_) => None,
+			});
+		}
+		let state = if mtime.is_none() || mtime > deps.mtime() {
+			"STALE"
+		} else if dep_states.contains(&Some("MISSING")) {
+			"MISSING-DEP"
+		} else if dep_states.contains(&Some("NEWER")) {
+			"STALE"
+		} else {
+			"VALID"
+		};
+		println!(
+			"{}: #deps {}, deps mtime {} ({})",
+			path,
+			deps.deps().len(),
+			deps.mtime()
+				.map_or_else(|| "none".to_string(), |t| t.to_string()),
+			state,
+		);
+		for (dep, dep_state) in deps.deps().zip(dep_states) {
+			if let Some(dep_state) = dep_state {
+				println!("    {} ({})", dep, dep_state);
+			} else {
+				println!("    {}", dep);
+			}
+		}
+		println!();
+	}
+	Ok(())
+}
@@ -0,0 +1,101 @@
+use super::{rule_filter, Options};
+use ninj::spec::{read_with, Spec};
+use raw_string::unix::RawStrExt;
+use raw_string::RawStr;
+use std::io::{Error, ErrorKind};
+
+/// Remove all files produced by the build rules.
+///
+/// Removes the outputs, depfiles and rspfiles of all non-phony rules.
+/// Outputs of generator rules (e.g. `build.ninja` itself) are kept, unless
+/// `-g` is given.
+///
+/// With `--rule`, only the files of build rules using that rule are removed.
+///
+/// With `-n`, only lists the files that would be removed.
+pub(super) fn main(opt: &Options) -> Result<(), Error> {
+	if !opt.targets.is_empty() {
+		return Err(Error::other("Cleaning specific targets is not supported"));
+	}
+	let spec = read_with(&opt.file, opt.read_options())?;
+	let filter = rule_filter(opt, &spec)?;
+	let mut n_removed = 0;
+	for file in files_to_clean(&spec, opt.clean_generator, filter) {
+		if opt.dry_run {
+			if file.as_path().exists() {
+				println!("Remove {}", file);
+				n_removed += 1;
+			}
+			continue;
+		}
+		match std::fs::remove_file(file.as_path()) {
+			Ok(()) => {
+				if opt.verbose {
+					println!("Remove {}", file);
+				}
+				n_removed += 1;
+			}
+			Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+			Err(e) => {
+				return Err(Error::new(
+					e.kind(),
+					format!("Unable to remove {:?}: {}", file, e),
+				))
+			}
+		}
+	}
+	println!("Cleaned {} files.", n_removed);
+	Ok(())
+}
+
+/// The files produced by the build rules, which `clean` removes.
+fn files_to_clean<'a>(spec: &'a Spec, generator: bool, only_rule: Option<&str>) -> Vec<&'a RawStr> {
+	let mut files = Vec::new();
+	for rule in &spec.build_rules {
+		let command = match &rule.command {
+			Some(command) => command,
+			None => continue,
+		};
+		if rule.is_generator() && !generator {
+			continue;
+		}
+		if only_rule.is_some_and(|name| name != command.rule_name) {
+			continue;
+		}
+		files.extend(rule.outputs.iter().map(|o| &o[..]));
+		files.extend(command.transient_files());
+	}
+	files
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use ninj::spec::read_from;
+	use std::path::Path;
+
+	#[test]
+	fn generator() {
+		let spec = read_from(
+			Path::new("build.ninja"),
+			b"rule gen\n  command = gen\n  generator = 1\n\
+			  rule cc\n  command = cc\n  depfile = $out.d\n  rspfile = $out.rsp\n\
+			  build build.ninja: gen\n\
+			  build a.o: cc a.c\n\
+			  build all: phony a.o\n",
+		)
+		.unwrap();
+		assert!(spec.build_rules[0].is_generator());
+		assert!(!spec.build_rules[1].is_generator());
+		assert_eq!(
+			files_to_clean(&spec, false, None),
+			["a.o", "a.o.d", "a.o.rsp"]
+		);
+		assert_eq!(
+			files_to_clean(&spec, true, None),
+			["build.ninja", "a.o", "a.o.d", "a.o.rsp"]
+		);
+		assert_eq!(files_to_clean(&spec, true, Some("gen")), ["build.ninja"]);
+		assert!(files_to_clean(&spec, false, Some("gen")).is_empty());
+	}
+}
@@ -5,12 +5,12 @@ use raw_string::unix::RawStrExt;
 use std::io::Error;
 
 pub(super) fn main(opt: &Options) -> Result<(), Error> {
-	use ninj::spec::read;
-	let spec = read(&opt.file)?;
+	use ninj::spec::read_with;
+	let spec = read_with(&opt.file, opt.read_options())?;
 	let targets = spec.make_index();
-	let dep_log = DepLogMut::open(spec.build_dir().join(".ninja_deps"))?;
+	let dep_log = DepLogMut::open(opt.build_dir(&spec).join(".ninja_deps"))?;
 	for (path, deps) in dep_log.iter() {
-		if targets.contains_key(&path[..]) {
+		if targets.contains_key(path) {
 			let mtime = std::fs::metadata(path.as_path())
 				.and_then(|m| m.modified())
 				.ok()
@@ -19,7 +19,8 @@ pub(super) fn main(opt: &Options) -> Result<(), Error> {
 				"{}: #deps {}, deps mtime {} ({})",
 				path,
 				deps.deps().len(),
-				deps.mtime().map_or(0, Timestamp::to_nanos),
+				deps.mtime()
+					.map_or_else(|| "none".to_string(), |t| t.to_string()),
 				if mtime.is_none() || mtime > deps.mtime() {
 					"STALE"
 				} else {
@@ -0,0 +1,134 @@
+use super::Options;
+use ninj::spec::{read_with, BuildRule, Spec};
+use raw_string::unix::RawStrExt;
+use raw_string::RawStr;
+use std::collections::BTreeSet;
+use std::io::Error;
+
+/// Compare two build specifications, listing the outputs that were added,
+/// removed, or are now built differently.
+///
+/// `-t diff OLD` compares the file `OLD` to the build specification given by
+/// `-f`. `-t diff OLD NEW` compares two files.
+///
+/// Outputs that are built by both are compared by their command, and by their
+/// set of inputs (including order-only dependencies).
+pub(super) fn main(opt: &Options) -> Result<(), Error> {
+	let (old, new) = match &opt.targets[..] {
+		[old] => (old.as_path(), opt.file.as_path()),
+		[old, new] => (old.as_path(), new.as_path()),
+		_ => return Err(Error::other("Expected one or two build files to compare")),
+	};
+	let old = read_with(old, opt.read_options())?;
+	let new = read_with(new, opt.read_options())?;
+	let diff = diff(&old, &new);
+	let groups = [
+		("Added", &diff.added),
+		("Removed", &diff.removed),
+		("Changed command", &diff.changed_command),
+		("Changed inputs", &diff.changed_inputs),
+	];
+	if groups.iter().all(|(_, outputs)| outputs.is_empty()) {
+		println!("No differences.");
+	}
+	for (title, outputs) in &groups {
+		if outputs.is_empty() {
+			continue;
+		}
+		println!("{} ({}):", title, outputs.len());
+		for output in outputs.iter() {
+			println!("\t{}", output);
+		}
+	}
+	Ok(())
+}
+
+/// The outputs that differ between two specs, each sorted by path.
+#[derive(Debug, Default)]
+struct Diff<'a> {
+	added: Vec<&'a RawStr>,
+	removed: Vec<&'a RawStr>,
+	changed_command: Vec<&'a RawStr>,
+	changed_inputs: Vec<&'a RawStr>,
+}
+
+fn diff<'a>(old: &'a Spec, new: &'a Spec) -> Diff<'a> {
+	let old_index = old.make_index();
+	let new_index = new.make_index();
+	let mut diff = Diff::default();
+	for (&output, &i) in &new_index {
+		let new_rule = &new.build_rules[i];
+		match old_index.get(output) {
+			None => diff.added.push(output),
+			Some(&j) => {
+				let old_rule = &old.build_rules[j];
+				if command(old_rule) != command(new_rule) {
+					diff.changed_command.push(output);
+				}
+				if input_set(old_rule) != input_set(new_rule) {
+					diff.changed_inputs.push(output);
+				}
+			}
+		}
+	}
+	for &output in old_index.keys() {
+		if !new_index.contains_key(output) {
+			diff.removed.push(output);
+		}
+	}
+	diff.added.sort_unstable();
+	diff.removed.sort_unstable();
+	diff.changed_command.sort_unstable();
+	diff.changed_inputs.sort_unstable();
+	diff
+}
+
+/// The command of a rule, or `None` for phony rules.
+fn command(rule: &BuildRule) -> Option<&RawStr> {
+	rule.command.as_ref().map(|c| &c.command[..])
+}
+
+fn input_set(rule: &BuildRule) -> BTreeSet<&RawStr> {
+	rule.inputs
+		.iter()
+		.chain(&rule.order_deps)
+		.map(|p| &p[..])
+		.collect()
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use ninj::spec::read_from;
+	use std::path::Path;
+
+	#[test]
+	fn diff_specs() {
+		let old = read_from(
+			Path::new("old.ninja"),
+			b"rule cc\n  command = cc $in -o $out\n\
+			  build a.o: cc a.c\n\
+			  build b.o: cc b.c | b.h\n\
+			  build c.o: cc c.c\n\
+			  build d.o: cc d.c\n\
+			  build all: phony a.o b.o c.o d.o\n",
+		)
+		.unwrap();
+		let new = read_from(
+			Path::new("new.ninja"),
+			b"rule cc\n  command = cc -O2 $in -o $out\n\
+			  rule cc0\n  command = cc $in -o $out\n\
+			  build a.o: cc0 a.c\n\
+			  build b.o: cc0 b.c\n\
+			  build c.o: cc c.c\n\
+			  build e.o: cc0 e.c\n\
+			  build all: phony a.o b.o c.o e.o\n",
+		)
+		.unwrap();
+		let diff = diff(&old, &new);
+		assert_eq!(diff.added, ["e.o"]);
+		assert_eq!(diff.removed, ["d.o"]);
+		assert_eq!(diff.changed_command, ["c.o"]);
+		assert_eq!(diff.changed_inputs, ["all", "b.o"]);
+	}
+}
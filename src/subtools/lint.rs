@@ -0,0 +1,20 @@
+use super::Options;
+use ninj::spec::read_with;
+use std::io::Error;
+
+/// Report possible mistakes in the build specification.
+///
+/// Lists the problems found by `Spec::validate`, and any rules and pools that
+/// are defined but never used.
+pub(super) fn main(opt: &Options) -> Result<(), Error> {
+	let spec = read_with(&opt.file, opt.read_options())?;
+	for e in spec.validate() {
+		println!("{}", e);
+	}
+	let rules = spec.rules.iter().map(|def| ("rule", def));
+	let pools = spec.pools.iter().map(|pool| ("pool", &pool.definition));
+	for (kind, def) in rules.chain(pools).filter(|(_, def)| !def.used) {
+		println!("{}: Unused {}: {}", def.location(), kind, def.name);
+	}
+	Ok(())
+}
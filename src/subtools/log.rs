@@ -1,11 +1,11 @@
 use super::Options;
 use ninj::buildlog::BuildLog;
-use ninj::spec::read;
+use ninj::spec::read_with;
 use std::io::Error;
 
 pub(super) fn main(opt: &Options) -> Result<(), Error> {
-	let spec = read(&opt.file)?;
-	let build_log = BuildLog::read(spec.build_dir().join(".ninja_log"))?;
+	let spec = read_with(&opt.file, opt.read_options())?;
+	let build_log = BuildLog::read(opt.build_dir(&spec).join(".ninja_log"))?;
 	println!("{:#?}", build_log);
 	Ok(())
 }
@@ -1,19 +1,37 @@
+#[cfg(feature = "browse")]
+mod browse;
+mod checkdeps;
+mod clean;
 mod deps;
+mod diff;
 mod graph;
+mod lint;
 mod log;
 mod rules;
 mod spec;
+mod stats;
 mod targets;
 
 use super::Options;
-use std::io::{Error, ErrorKind};
+use ninj::spec::{BuildRule, Spec};
+use std::collections::BTreeSet;
+use std::io::Error;
 
-static SUBTOOLS: &'static [(&'static str, fn(&Options) -> Result<(), Error>)] = &[
+type Subtool = fn(&Options) -> Result<(), Error>;
+
+static SUBTOOLS: &[(&str, Subtool)] = &[
+	#[cfg(feature = "browse")]
+	("browse", browse::main),
+	("checkdeps", checkdeps::main),
+	("clean", clean::main),
 	("deps", deps::main),
+	("diff", diff::main),
 	("graph", self::graph::main),
+	("lint", lint::main),
 	("log", log::main),
 	("rules", rules::main),
 	("spec", spec::main),
+	("stats", stats::main),
 	("targets", targets::main),
 	("list", list),
 ];
@@ -22,11 +40,33 @@ pub(super) fn run_subtool(tool: &str, options: &Options) -> Result<(), Error> {
 	if let Some((_, main)) = SUBTOOLS.iter().find(|(name, _)| *name == tool) {
 		main(options)
 	} else {
-		Err(Error::new(
-			ErrorKind::Other,
-			format!("Unknown subtool {:?}", tool),
-		))
+		Err(Error::other(format!("Unknown subtool {:?}", tool)))
+	}
+}
+
+/// The name of the rule used by a build rule, or `"phony"`.
+fn rule_name(rule: &BuildRule) -> &str {
+	rule.command.as_ref().map_or("phony", |c| &c.rule_name)
+}
+
+/// Check that the rule given with `--rule` (if any) is used in the spec.
+///
+/// Returns the rule name to filter on.
+fn rule_filter<'a>(opt: &'a Options, spec: &Spec) -> Result<Option<&'a str>, Error> {
+	let name = match &opt.rule {
+		Some(name) => name,
+		None => return Ok(None),
+	};
+	let names: BTreeSet<&str> = spec.build_rules.iter().map(rule_name).collect();
+	if !names.contains(&name[..]) {
+		let names: Vec<&str> = names.into_iter().collect();
+		return Err(Error::other(format!(
+			"Unknown rule {:?}. Known rules: {}",
+			name,
+			names.join(", ")
+		)));
 	}
+	Ok(Some(name))
 }
 
 fn list(_: &Options) -> Result<(), Error> {
@@ -1,5 +1,5 @@
 use super::Options;
-use ninj::spec::read;
+use ninj::spec::read_with;
 use std::collections::BTreeSet;
 use std::io::Error;
 
@@ -8,7 +8,7 @@ use std::io::Error;
 /// Unlike the original ninja, this only outputs the names of the rules that
 /// are actually used.
 pub(super) fn main(opt: &Options) -> Result<(), Error> {
-	let spec = read(&opt.file)?;
+	let spec = read_with(&opt.file, opt.read_options())?;
 
 	let mut rule_names = BTreeSet::new();
 	let mut phony = false;
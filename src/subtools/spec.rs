@@ -1,9 +1,9 @@
 use super::Options;
-use ninj::spec::read;
+use ninj::spec::read_with;
 use std::io::Error;
 
 pub(super) fn main(opt: &Options) -> Result<(), Error> {
-	let spec = read(&opt.file)?;
+	let spec = read_with(&opt.file, opt.read_options())?;
 	println!("{:#?}", spec);
 	Ok(())
 }
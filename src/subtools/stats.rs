@@ -0,0 +1,15 @@
+use super::Options;
+use ninj::spec::read_with;
+use std::io::Error;
+
+/// Show a summary of the size of the build graph.
+pub(super) fn main(opt: &Options) -> Result<(), Error> {
+	let spec = read_with(&opt.file, opt.read_options())?;
+	let (real, phony) = spec.count_rules();
+	println!("build rules: {} ({} phony)", real + phony, phony);
+	println!("outputs:     {}", spec.count_outputs());
+	println!("inputs:      {}", spec.count_inputs());
+	println!("rules:       {}", spec.rules.len());
+	println!("pools:       {}", spec.pools.len());
+	Ok(())
+}
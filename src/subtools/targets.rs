@@ -1,16 +1,20 @@
-use super::Options;
-use ninj::spec::read;
+use super::{rule_filter, rule_name, Options};
+use ninj::spec::read_with;
 use std::io::Error;
 
+/// List all outputs, together with the name of the rule that produces them.
+///
+/// With `--rule`, only the outputs of that rule are listed.
 pub(super) fn main(opt: &Options) -> Result<(), Error> {
-	let spec = read(&opt.file)?;
+	let spec = read_with(&opt.file, opt.read_options())?;
+	let filter = rule_filter(opt, &spec)?;
 	for target in &spec.build_rules {
+		let name = rule_name(target);
+		if filter.is_some_and(|filter| filter != name) {
+			continue;
+		}
 		for output in &target.outputs {
-			println!(
-				"{}: {}",
-				output,
-				target.command.as_ref().map_or("phony", |c| &c.rule_name)
-			);
+			println!("{}: {}", output, name);
 		}
 	}
 	Ok(())
@@ -1,34 +1,91 @@
 pub mod status;
-mod subprocess;
 
 use self::status::{TaskStatusUpdater, WorkerStatusUpdater};
-use self::subprocess::listen_to_child;
-use log::{debug, error};
-use ninj::buildlog::BuildLog;
+use crate::status::estimated_total_task_time;
+use log::{debug, error, warn};
+use ninj::buildlog::{murmur_hash_64a, BuildLog};
+use ninj::cache::{cache_key, cacheable_inputs, Cache};
 use ninj::depfile::read_deps_file;
 use ninj::deplog::DepLogMut;
 use ninj::mtime;
-use ninj::mtime::Timestamp;
+use ninj::mtime::{StatCache, Timestamp};
+use ninj::outdated::{is_outdated, restat_mtime};
+use ninj::process::{listen_to_child, spawn_merged};
 use ninj::queue::AsyncBuildQueue;
 use ninj::spec::{BuildCommand, BuildRule, DepStyle, Spec};
 use raw_string::unix::RawStrExt;
-use raw_string::RawStr;
+use raw_string::{RawStr, RawString};
 use std::fs::create_dir_all;
 use std::os::unix::process::ExitStatusExt;
 use std::process::exit;
 use std::process::ExitStatus;
-use std::sync::Mutex;
-use std::time::Instant;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// How long to sleep for every task in a sleep run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SleepTime {
+	/// The same duration for every task.
+	Fixed(Duration),
+	/// A duration between 2.5 and 4.5 seconds, derived from the task index,
+	/// such that it's the same every run.
+	Seeded,
+	/// The duration the task took the last time, according to the build log.
+	/// Tasks not in the log take the average, or [`Seeded`][Self::Seeded] if
+	/// the log is empty.
+	FromLog,
+}
+
+#[derive(Debug)]
+pub struct ParseSleepTimeError {
+	value: String,
+}
+
+impl std::str::FromStr for SleepTime {
+	type Err = ParseSleepTimeError;
+	fn from_str(s: &str) -> Result<Self, ParseSleepTimeError> {
+		match s {
+			"seeded" => Ok(SleepTime::Seeded),
+			"log" => Ok(SleepTime::FromLog),
+			ms => ms
+				.parse()
+				.map(|ms| SleepTime::Fixed(Duration::from_millis(ms)))
+				.map_err(|_| ParseSleepTimeError {
+					value: s.to_string(),
+				}),
+		}
+	}
+}
+
+impl std::fmt::Display for ParseSleepTimeError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "{}", self.value)
+	}
+}
 
 /// A worker that executes tasks of a [`Spec`] according to a [`BuildQueue`].
 pub struct Worker<'a> {
 	pub spec: &'a Spec,
 	pub queue: &'a AsyncBuildQueue,
 	pub status_updater: WorkerStatusUpdater<'a>,
-	pub sleep: bool,
-	pub dep_log: &'a Mutex<DepLogMut>,
+	/// Instead of running commands, sleep. (See `--sleep`.)
+	pub sleep: Option<SleepTime>,
+	pub merge_output: bool,
+	/// Prepended (with a space) to the command of every non-generator rule.
+	pub launcher: Option<&'a RawStr>,
+	/// Stop starting new tasks after this many tasks failed. Zero means no
+	/// limit.
+	pub max_failures: usize,
+	/// Read by every worker (for fingerprints and restat checks), but only
+	/// changed after a task finished. The write lock is only held to add
+	/// dependencies in memory. They are written to the file afterwards, with
+	/// [`DepLogMut::flush`], which only needs the read lock.
+	pub dep_log: &'a RwLock<DepLogMut>,
 	pub build_log: &'a Mutex<BuildLog>,
 	pub start_time: Instant,
+	/// Restore outputs from this cache instead of running the command, if
+	/// possible, and store the outputs of commands that did run.
+	pub cache: Option<&'a Cache>,
 }
 
 impl<'a> Worker<'a> {
@@ -65,12 +122,18 @@ impl<'a> Worker<'a> {
 
 			// Run the task.
 			debug!(target: &log, "Running: {:?}", command.command);
-			self.run_task(rule, task_status_updater);
+			if !self.run_task(task, rule, task_status_updater) {
+				queue = self.queue.lock();
+				queue.fail_task(task);
+				if queue.n_failed() == self.max_failures {
+					queue.stop();
+				}
+				continue;
+			}
 
 			// Check if we need to re-stat anything.
 			let mut restat_fn;
-			let restat = if !self.sleep && command.restat {
-				self.restat(task);
+			let restat = if self.sleep.is_none() && command.restat {
 				restat_fn = |task: usize| self.recheck_outdated(task);
 				Some::<&mut dyn FnMut(usize) -> bool>(&mut restat_fn)
 			} else {
@@ -83,44 +146,71 @@ impl<'a> Worker<'a> {
 		}
 	}
 
-	fn restat(&self, task: usize) {
-		// TODO
-		debug!(
-			"I should now re-stat {:?}",
-			self.spec.build_rules[task].outputs
+	/// Check if a task is outdated, after a restat rule it depends on ran.
+	///
+	/// Called with the queue locked, so this only takes the read lock of the
+	/// dependency log.
+	fn recheck_outdated(&self, task: usize) -> bool {
+		let rule = &self.spec.build_rules[task];
+		let outdated = is_outdated(
+			rule,
+			&self.dep_log.read().unwrap(),
+			&self.build_log.lock().unwrap(),
+			&mut StatCache::new(),
+			&mut StatCache::new(),
+			|_| true,
 		);
+		debug!("Rechecked {:?}: {:?}", rule.outputs, outdated);
+		outdated.unwrap_or_else(|e| {
+			warn!("Unable to check if {:?} is outdated: {}", rule.outputs, e);
+			true
+		})
 	}
 
-	fn recheck_outdated(&self, task: usize) -> bool {
-		// TODO
-		debug!(
-			"I should check if {:?} is now outdated.",
-			self.spec.build_rules[task].outputs
-		);
-		false
+	/// Write the dependencies added by [`refresh_deps`][Self::refresh_deps]
+	/// or [`check_gcc_deps`][Self::check_gcc_deps] to the file.
+	fn flush_deps(&self) {
+		self.dep_log.read().unwrap().flush().unwrap_or_else(|e| {
+			error!("Unable to update dependency log: {}", e);
+			exit(1);
+		});
 	}
 
-	fn run_task(&self, rule: &BuildRule, status_updater: TaskStatusUpdater) {
+	/// Run the command of a task, and returns whether it was successful.
+	fn run_task(&self, task: usize, rule: &BuildRule, status_updater: TaskStatusUpdater) -> bool {
 		let command = rule.command.as_ref().expect("Got phony rule");
 
-		if self.sleep {
+		if let Some(sleep) = self.sleep {
 			// Just sleep. Zzz.
-			std::thread::sleep(std::time::Duration::from_millis(
-				2500 + command.command.len() as u64 * 5123 % 2000,
-			));
+			std::thread::sleep(self.sleep_duration(sleep, task));
 
 			// Pretend success.
 			status_updater.finished(ExitStatus::from_raw(0));
 
-			return;
+			return true;
 		}
 
+		// Remember the mtimes of the outputs, to see if a restat rule changed
+		// them.
+		let old_mtimes = if command.restat {
+			rule.output_paths()
+				.map(|output| {
+					mtime::mtime(output).unwrap_or_else(|e| {
+						error!("Unable to get mtime of {:?}: {}", output, e);
+						exit(1);
+					})
+				})
+				.collect()
+		} else {
+			Vec::new()
+		};
+
 		// Start the clock!
 		let start_time = Instant::now();
 
 		// Create directories for the output.
-		for output in &rule.outputs {
-			if let Some(dir) = output.as_path().parent() {
+		for output in rule.output_paths() {
+			if let Some(dir) = output.parent() {
 				create_dir_all(dir).unwrap_or_else(|e| {
 					error!("Unable to create directory {:?}: {}", dir, e);
 					exit(1);
@@ -128,27 +218,15 @@ impl<'a> Worker<'a> {
 			}
 		}
 
-		// Run the command, capturing its output.
-		let child = std::process::Command::new("sh")
-			.arg("-c")
-			.arg(command.command.as_osstr())
-			.stdin(std::process::Stdio::null())
-			.stdout(std::process::Stdio::piped())
-			.stderr(std::process::Stdio::piped())
-			.spawn()
-			.unwrap_or_else(|e| {
-				error!("Unable to spawn sh process: {}", e);
-				exit(1);
-			});
+		// Restore the outputs from the cache, if possible.
+		let cached = self.fetch_from_cache(rule);
 
-		// Listen for output.
-		let status = listen_to_child(child, 100, &|output| {
-			status_updater.output(RawStr::from(output));
-		})
-		.unwrap_or_else(|e| {
-			error!("Unable to read from subprocess: {}", e);
-			exit(1);
-		});
+		// Otherwise, run the command.
+		let status = if cached {
+			ExitStatus::from_raw(0)
+		} else {
+			self.run_command(rule, &status_updater)
+		};
 
 		// Report the status.
 		status_updater.finished(status);
@@ -156,28 +234,28 @@ impl<'a> Worker<'a> {
 		// Handle a failed task.
 		if !status.success() {
 			error!("Command exited with {}: {}", status, command.command);
-			exit(1);
+			return false;
 		}
 
 		// Check for any extra dependencies.
-		match command.deps {
-			Some(DepStyle::Gcc) => self.check_gcc_deps(command),
-			Some(DepStyle::Msvc) => unimplemented!("MSVC-style dependencies"),
-			None => {}
+		if cached {
+			self.refresh_deps(rule);
+		} else {
+			match command.deps {
+				Some(DepStyle::Gcc) => self.check_gcc_deps(command),
+				Some(DepStyle::Msvc) => unimplemented!("MSVC-style dependencies"),
+				None => {}
+			}
 		}
 
 		// Stop the clock!
 		let end_time = Instant::now();
 
-		let mut mtime = None;
-		// TODO: If this is a restat rule and any output's mtime is unchanged,
-		// use the mtime of the newest input instead.
-		for output in &rule.outputs {
-			mtime = mtime.max(mtime::mtime(output.as_path()).unwrap_or_else(|e| {
-				error!("Unable to get mtime of {:?}: {}", output, e);
+		let mtime =
+			restat_mtime(rule, &self.dep_log.read().unwrap(), &old_mtimes).unwrap_or_else(|e| {
+				error!("Unable to get mtime of {:?}: {}", rule.outputs, e);
 				exit(1);
-			}));
-		}
+			});
 
 		// Record the success to the build log.
 		self.build_log.lock().unwrap().add_entry(
@@ -187,6 +265,155 @@ impl<'a> Worker<'a> {
 			end_time,
 			mtime,
 		);
+
+		if !cached {
+			self.store_in_cache(rule);
+		}
+
+		true
+	}
+
+	/// How long to sleep instead of running a task.
+	fn sleep_duration(&self, sleep: SleepTime, task: usize) -> Duration {
+		let seeded = || {
+			let hash = murmur_hash_64a(&(task as u64).to_le_bytes());
+			Duration::from_millis(2500 + hash % 2000)
+		};
+		match sleep {
+			SleepTime::Fixed(duration) => duration,
+			SleepTime::Seeded => seeded(),
+			SleepTime::FromLog => {
+				estimated_total_task_time(self.spec, task, &self.build_log.lock().unwrap())
+					.unwrap_or_else(seeded)
+			}
+		}
+	}
+
+	/// Run the command of a rule, and return its exit status.
+	fn run_command(&self, rule: &BuildRule, status_updater: &TaskStatusUpdater) -> ExitStatus {
+		let command = rule.command.as_ref().expect("Got phony rule");
+
+		// Prepend the launcher, if any.
+		let mut launched_command;
+		let command_line = match self.launcher {
+			Some(launcher) if !rule.is_generator() => {
+				launched_command = RawString::from(launcher);
+				launched_command.push(b' ');
+				launched_command.push_str(&command.command);
+				&launched_command[..]
+			}
+			_ => &command.command[..],
+		};
+
+		// Run the command, capturing its output.
+		let mut process = std::process::Command::new("sh");
+		process
+			.arg("-c")
+			.arg(command_line.as_osstr())
+			.stdin(std::process::Stdio::null());
+		let child = if self.merge_output {
+			spawn_merged(process)
+		} else {
+			process
+				.stdout(std::process::Stdio::piped())
+				.stderr(std::process::Stdio::piped())
+				.spawn()
+		}
+		.unwrap_or_else(|e| {
+			error!("Unable to spawn sh process: {}", e);
+			exit(1);
+		});
+
+		// Listen for output.
+		listen_to_child(child, 100, |_, output| {
+			status_updater.output(RawStr::from_bytes(output));
+		})
+		.unwrap_or_else(|e| {
+			error!("Unable to read from subprocess: {}", e);
+			exit(1);
+		})
+	}
+
+	/// Compute the fingerprint of a rule from the contents of its inputs.
+	///
+	/// Returns `None` if the rule can't be cached, or if an input can't be
+	/// read.
+	fn fingerprint(&self, rule: &BuildRule) -> Option<u64> {
+		let inputs = cacheable_inputs(rule, &self.dep_log.read().unwrap())?;
+		cache_key(rule, &inputs)
+	}
+
+	/// Try to restore the outputs of a rule from the cache, and return whether
+	/// that succeeded.
+	fn fetch_from_cache(&self, rule: &BuildRule) -> bool {
+		let cache = if let Some(cache) = self.cache {
+			cache
+		} else {
+			return false;
+		};
+		let fingerprint = if let Some(f) = self.fingerprint(rule) {
+			f
+		} else {
+			return false;
+		};
+		match cache.fetch(fingerprint, rule.output_paths()) {
+			Ok(hit) => {
+				debug!(
+					"Cache {} for {:?} ({:016x}).",
+					if hit { "hit" } else { "miss" },
+					rule.outputs,
+					fingerprint
+				);
+				hit
+			}
+			Err(e) => {
+				warn!("Unable to restore {:?} from cache: {}", rule.outputs, e);
+				false
+			}
+		}
+	}
+
+	/// Store the outputs of a rule that just ran in the cache.
+	fn store_in_cache(&self, rule: &BuildRule) {
+		let cache = if let Some(cache) = self.cache {
+			cache
+		} else {
+			return;
+		};
+		if let Some(fingerprint) = self.fingerprint(rule) {
+			if let Err(e) = cache.store(fingerprint, rule.output_paths()) {
+				warn!("Unable to store {:?} in cache: {}", rule.outputs, e);
+			}
+		}
+	}
+
+	/// Record the same dependencies again for outputs restored from the cache,
+	/// such that they are not older than the outputs.
+	fn refresh_deps(&self, rule: &BuildRule) {
+		if rule.command.as_ref().is_none_or(|c| c.deps.is_none()) {
+			return;
+		}
+		let mtime = Timestamp::from_system_time(std::time::SystemTime::now());
+		let dep_log = self.dep_log.read().unwrap();
+		let found = rule.outputs.iter().find_map(|output| {
+			let deps = dep_log.get(output)?;
+			Some((output.clone(), deps.deps().map(RawStr::to_owned).collect()))
+		});
+		drop(dep_log);
+		let (target, deps) = if let Some(x) = found {
+			x
+		} else {
+			return;
+		};
+		self.dep_log
+			.write()
+			.unwrap()
+			.insert_deps(target, Some(mtime), deps)
+			.unwrap_or_else(|e| {
+				error!("Unable to update dependency log: {}", e);
+				exit(1);
+			});
+		self.flush_deps();
 	}
 
 	fn check_gcc_deps(&self, command: &BuildCommand) {
@@ -194,7 +421,7 @@ impl<'a> Worker<'a> {
 		let mtime = Timestamp::from_system_time(std::time::SystemTime::now());
 		read_deps_file(command.depfile.as_path(), |target, deps| {
 			self.dep_log
-				.lock()
+				.write()
 				.unwrap()
 				.insert_deps(target, Some(mtime), deps)
 				.unwrap_or_else(|e| {
@@ -210,6 +437,7 @@ impl<'a> Worker<'a> {
 			);
 			exit(1);
 		});
+		self.flush_deps();
 		std::fs::remove_file(command.depfile.as_path()).unwrap_or_else(|e| {
 			error!(
 				"Unable to remove dependency file {:?}: {}",
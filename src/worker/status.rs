@@ -57,7 +57,7 @@ impl<'a> WorkerStatusUpdater<'a> {
 	/// Dropping the returned object without calling
 	/// [`finished`][TaskStatusUpdater::finished] will mark the task as
 	/// failed.
-	pub fn start_task(&self, task_id: usize) -> TaskStatusUpdater {
+	pub fn start_task(&self, task_id: usize) -> TaskStatusUpdater<'_> {
 		let updater = TaskStatusUpdater {
 			worker_status_updater: self,
 			task_id,